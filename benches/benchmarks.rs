@@ -22,28 +22,34 @@ fn build_playback_benchmark(c: &mut Criterion) {
                         path: "sounds/sample-3.wav".to_string(),
                         start_time_ms: 0,
                         duration_ms: 10,
+                        ..Default::default()
                     },
                     ClipModel {
                         path: "sounds/sample-3.wav".to_string(),
                         start_time_ms: 0,
                         duration_ms: 10,
+                        ..Default::default()
                     },
                     ClipModel {
                         path: "sounds/sample-3.wav".to_string(),
                         start_time_ms: 0,
                         duration_ms: 10,
+                        ..Default::default()
                     },
                     ClipModel {
                         path: "sounds/sample-3.wav".to_string(),
                         start_time_ms: 0,
                         duration_ms: 10,
+                        ..Default::default()
                     },
                     ClipModel {
                         path: "sounds/sample-3.wav".to_string(),
                         start_time_ms: 0,
                         duration_ms: 10,
+                        ..Default::default()
                     },
                 ],
+                ..Default::default()
             },
             ChannelModel {
                 id: "chan-2".to_string(),
@@ -51,7 +57,9 @@ fn build_playback_benchmark(c: &mut Criterion) {
                     path: "sounds/sample-5.wav".to_string(),
                     start_time_ms: 0,
                     duration_ms: 10,
+                    ..Default::default()
                 }],
+                ..Default::default()
             },
             ChannelModel {
                 id: "chan-3".to_string(),
@@ -59,7 +67,9 @@ fn build_playback_benchmark(c: &mut Criterion) {
                     path: "sounds/sample-1.wav".to_string(),
                     start_time_ms: 0,
                     duration_ms: 10,
+                    ..Default::default()
                 }],
+                ..Default::default()
             },
             ChannelModel {
                 id: "chan-4".to_string(),
@@ -67,14 +77,16 @@ fn build_playback_benchmark(c: &mut Criterion) {
                     path: "sounds/sample-1.wav".to_string(),
                     start_time_ms: 0,
                     duration_ms: 10,
+                    ..Default::default()
                 }],
+                ..Default::default()
             },
         ],
-        // });
+        ..Default::default() // });
     };
 
     c.bench_function("PlaybackBuilder", |b| {
-        b.iter(|| PlaybackBuilder::new(&mixer, stream_config.clone()))
+        b.iter(|| PlaybackBuilder::new(&mixer, stream_config.config()))
     });
 }
 
@@ -21,24 +21,30 @@ fn main() {
             clips: vec![ClipModel {
                 path: "sounds/sample-1.wav".to_string(),
                 start_time_ms: 0,
-                duration_ms: 10,
+                duration_ms: 3196,
+                ..Default::default()
             }],
+            ..Default::default()
         });
         channels.push(ChannelModel {
             id: "chan-2".to_string(),
             clips: vec![ClipModel {
                 path: "sounds/sample-2.wav".to_string(),
                 start_time_ms: 0,
-                duration_ms: 10,
+                duration_ms: 2137,
+                ..Default::default()
             }],
+            ..Default::default()
         });
         channels.push(ChannelModel {
             id: "chan-3".to_string(),
             clips: vec![ClipModel {
                 path: "sounds/sample-3.wav".to_string(),
                 start_time_ms: 0,
-                duration_ms: 10,
+                duration_ms: 19174,
+                ..Default::default()
             }],
+            ..Default::default()
         });
         channels.push(ChannelModel {
             id: "chan-4".to_string(),
@@ -46,7 +52,9 @@ fn main() {
                 path: "sounds/sample-4.wav".to_string(),
                 start_time_ms: 0,
                 duration_ms: 10,
+                ..Default::default()
             }],
+            ..Default::default()
         });
         channels.push(ChannelModel {
             id: "chan-4".to_string(),
@@ -54,7 +62,9 @@ fn main() {
                 path: "sounds/sample-5.wav".to_string(),
                 start_time_ms: 0,
                 duration_ms: 10,
+                ..Default::default()
             }],
+            ..Default::default()
         });
     }
 
@@ -80,7 +90,10 @@ fn main() {
     //     })
     // }
 
-    let mixer = MixerModel { channels };
+    let mixer = MixerModel {
+        channels,
+        ..Default::default()
+    };
 
     println!("Creating thing");
     let playback = PlaybackBuilder::new(&mixer, stream_config.config()).unwrap();
@@ -0,0 +1,204 @@
+/// How a lane gets from one breakpoint to the next.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Curve {
+    /// In a straight line
+    #[default]
+    Linear,
+    /// By the same ratio every frame, which sounds even for frequencies and
+    /// gains. Falls back to linear when the values aren't both positive or
+    /// both negative.
+    Exponential,
+    /// Stays put, then jumps at the next breakpoint
+    Step,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Breakpoint {
+    /// Where on the timeline the lane reaches `value`
+    pub time_ms: f64,
+    pub value: f32,
+    /// How the lane goes on from here to the next breakpoint
+    pub curve: Curve,
+}
+
+/// A value that follows the timeline. Before the first breakpoint it holds the
+/// first value and after the last it holds the last.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AutomationLane {
+    pub breakpoints: Vec<Breakpoint>,
+}
+
+/// Automation of one parameter of one of a channel's inserts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParamAutomation {
+    /// Index into the channel's inserts
+    pub insert: usize,
+    /// Name of the parameter, as in its `ParamInfo`
+    pub param: String,
+    pub lane: AutomationLane,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Point {
+    frame: u64,
+    value: f32,
+    curve: Curve,
+}
+
+/// A lane with its breakpoints in frames, ready to be read on the audio
+/// thread. Reading only depends on the frame asked for, so it is right
+/// straight after a seek.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Automation {
+    points: Vec<Point>,
+}
+
+impl Automation {
+    /// `None` when the lane has no breakpoints.
+    pub fn new(lane: &AutomationLane, sample_rate: u32) -> Option<Self> {
+        let mut points: Vec<Point> = lane
+            .breakpoints
+            .iter()
+            .map(|point| Point {
+                frame: (point.time_ms.max(0f64) / 1000f64 * sample_rate as f64).round() as u64,
+                value: point.value,
+                curve: point.curve,
+            })
+            .collect();
+
+        if points.is_empty() {
+            return None;
+        }
+
+        // Stable, so breakpoints at the same time keep their order and the
+        // lane jumps between them
+        points.sort_by_key(|point| point.frame);
+
+        Some(Automation { points })
+    }
+
+    /// The lane's value at timeline frame `frame`.
+    pub fn value_at(&self, frame: u64) -> f32 {
+        self.segment_value(self.segment(frame), frame)
+    }
+
+    /// Where the lane is heading as it gets to `frame`, which differs from
+    /// `value_at` when it steps at `frame`.
+    pub fn value_before(&self, frame: u64) -> f32 {
+        self.segment_value(self.segment(frame.saturating_sub(1)), frame)
+    }
+
+    /// Fills `out` with the lane's value for each frame from `start` on.
+    pub fn fill(&self, start: u64, out: &mut [f32]) {
+        let mut segment = self.segment(start);
+
+        for (frame, value) in (start..).zip(out.iter_mut()) {
+            while segment < self.points.len() && self.points[segment].frame <= frame {
+                segment += 1;
+            }
+            *value = self.segment_value(segment, frame);
+        }
+    }
+
+    /// The first breakpoint after `frame`, which is where a step can land.
+    pub fn next_breakpoint(&self, frame: u64) -> Option<u64> {
+        self.points
+            .get(self.segment(frame))
+            .map(|point| point.frame)
+    }
+
+    /// Index of the first point after `frame`, so `frame` falls between that
+    /// point and the one before it.
+    fn segment(&self, frame: u64) -> usize {
+        self.points.partition_point(|point| point.frame <= frame)
+    }
+
+    fn segment_value(&self, segment: usize, frame: u64) -> f32 {
+        if segment == 0 {
+            return self.points[0].value;
+        }
+
+        let from = self.points[segment - 1];
+        let to = match self.points.get(segment) {
+            Some(to) => to,
+            None => return from.value,
+        };
+
+        let t = (frame - from.frame) as f64 / (to.frame - from.frame) as f64;
+        let (a, b) = (from.value as f64, to.value as f64);
+
+        let value = match from.curve {
+            Curve::Step => a,
+            Curve::Exponential if a * b > 0f64 => a * (b / a).powf(t),
+            _ => a + (b - a) * t,
+        };
+
+        value as f32
+    }
+}
+
+#[cfg(test)]
+mod automation_tests {
+    use crate::automation::*;
+
+    fn lane(points: &[(f64, f32, Curve)]) -> Automation {
+        let lane = AutomationLane {
+            breakpoints: points
+                .iter()
+                .map(|(time_ms, value, curve)| Breakpoint {
+                    time_ms: *time_ms,
+                    value: *value,
+                    curve: *curve,
+                })
+                .collect(),
+        };
+
+        // 1 frame per ms
+        Automation::new(&lane, 1000).unwrap()
+    }
+
+    #[test]
+    fn curves_between_breakpoints() {
+        let linear = lane(&[(10f64, 0f32, Curve::Linear), (20f64, 1f32, Curve::Linear)]);
+        assert_eq!(linear.value_at(0), 0f32);
+        assert_eq!(linear.value_at(15), 0.5);
+        assert_eq!(linear.value_at(20), 1f32);
+        assert_eq!(linear.value_at(1000), 1f32);
+
+        let exponential = lane(&[
+            (0f64, 100f32, Curve::Exponential),
+            (10f64, 10000f32, Curve::Exponential),
+        ]);
+        assert!((exponential.value_at(5) - 1000f32).abs() < 1e-2);
+
+        let step = lane(&[(0f64, 1f32, Curve::Step), (10f64, 2f32, Curve::Step)]);
+        assert_eq!(step.value_at(9), 1f32);
+        assert_eq!(step.value_at(10), 2f32);
+        assert_eq!(step.value_before(10), 1f32);
+        assert_eq!(linear.value_before(20), 1f32);
+    }
+
+    #[test]
+    fn fill_matches_value_at() {
+        let lane = lane(&[
+            (5f64, -1f32, Curve::Linear),
+            (10f64, 1f32, Curve::Step),
+            (12f64, 0.5, Curve::Exponential),
+            (20f64, 0.25, Curve::Linear),
+        ]);
+
+        let mut values = vec![0f32; 30];
+        lane.fill(3, &mut values);
+
+        for (frame, value) in (3..).zip(values) {
+            assert_eq!(value, lane.value_at(frame));
+        }
+        assert_eq!(lane.next_breakpoint(10), Some(12));
+        assert_eq!(lane.next_breakpoint(20), None);
+    }
+
+    #[test]
+    fn empty_lane_is_no_automation() {
+        assert!(Automation::new(&AutomationLane::default(), 44100).is_none());
+    }
+}
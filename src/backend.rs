@@ -0,0 +1,534 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{Device, Host, SampleFormat, SampleRate, SupportedBufferSize, SupportedStreamConfig};
+use parking_lot::Mutex;
+
+use crate::{
+    device::{open_output, Output, OutputConfig},
+    error::Result,
+    playhead::PlayheadTracker,
+    ring_buffer::Consumer,
+    stats::StatsCollector,
+};
+
+/// Somewhere for the engine's output to go: a sound card, or something that
+/// stands in for one.
+pub trait Backend: Send {
+    /// The config the output runs at
+    fn config(&self) -> &SupportedStreamConfig;
+
+    /// Frames per callback
+    fn buffer_size(&self) -> u32;
+
+    /// Starts calling `output` for audio. It keeps going until the returned
+    /// stream is dropped.
+    fn start(&mut self, output: OutputCallback) -> Result<Box<dyn OutputStream>>;
+}
+
+/// A running output. Dropping it stops the output.
+pub trait OutputStream {}
+
+impl OutputStream for cpal::Stream {}
+
+/// Everything a backend's callback needs to pull audio from the engine.
+pub struct OutputCallback {
+    consumer: Consumer,
+    playhead: PlayheadTracker,
+    stats: Arc<StatsCollector>,
+    // The ring buffer holds f32s, so other sample formats are popped into
+    // here first. A multiple of the channel count so frames stay whole.
+    scratch: Vec<f32>,
+    channel_count: usize,
+    sample_rate: u32,
+}
+
+impl OutputCallback {
+    pub(crate) fn new(
+        consumer: Consumer,
+        playhead: PlayheadTracker,
+        stats: Arc<StatsCollector>,
+        config: &SupportedStreamConfig,
+        buffer_size: u32,
+    ) -> Self {
+        let channel_count = config.channels() as usize;
+
+        OutputCallback {
+            consumer,
+            playhead,
+            stats,
+            scratch: vec![0f32; buffer_size.max(1) as usize * channel_count * 4],
+            channel_count,
+            sample_rate: config.sample_rate().0,
+        }
+    }
+
+    /// Samples the engine has ready
+    pub fn ready(&self) -> usize {
+        self.consumer.len()
+    }
+
+    /// Fills `data` with the engine's output, which will be heard
+    /// `latency_frames` from now. Never blocks: if the engine has fallen
+    /// behind the rest is silence and an underrun is counted.
+    #[inline]
+    pub fn write<T: cpal::Sample>(&mut self, data: &mut [T], latency_frames: u64) {
+        let started = Instant::now();
+        let ring_buffer_fill = self.consumer.len();
+
+        let popped = self.fill(data);
+
+        self.playhead
+            .advance((popped / self.channel_count) as u64, latency_frames);
+
+        // The callback has to finish within the time the audio it delivers
+        // takes to play
+        let frames = (data.len() / self.channel_count) as u64;
+        let budget = Duration::from_nanos(frames * 1_000_000_000 / self.sample_rate as u64);
+
+        self.stats.record_callback(
+            ring_buffer_fill,
+            popped < data.len(),
+            started.elapsed(),
+            budget,
+        );
+    }
+
+    /// Copies whatever the engine has ready into `data` and returns how many
+    /// samples that was, padding the rest with silence.
+    #[inline]
+    fn fill<T: cpal::Sample>(&mut self, data: &mut [T]) -> usize {
+        let mut popped = 0;
+
+        for chunk in data.chunks_mut(self.scratch.len()) {
+            let scratch = &mut self.scratch[..chunk.len()];
+            let read = self.consumer.pop(scratch);
+            scratch[read..].fill(0f32);
+
+            for (out, sample) in chunk.iter_mut().zip(scratch.iter()) {
+                *out = cpal::Sample::from::<f32>(sample);
+            }
+
+            popped += read;
+        }
+
+        popped
+    }
+}
+
+fn err_fn(err: cpal::StreamError) {
+    eprintln!("an error occurred on stream: {}", err);
+}
+
+/// Plays through a cpal output device.
+pub struct CpalBackend {
+    host: Host,
+    device: Device,
+    config: SupportedStreamConfig,
+    buffer_size: u32,
+}
+
+impl CpalBackend {
+    pub fn open(output: &OutputConfig) -> Result<Self> {
+        let Output {
+            host,
+            device,
+            config,
+            buffer_size,
+        } = open_output(output)?;
+
+        Ok(CpalBackend {
+            host,
+            device,
+            config,
+            buffer_size,
+        })
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+}
+
+impl Backend for CpalBackend {
+    fn config(&self) -> &SupportedStreamConfig {
+        &self.config
+    }
+
+    fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
+    fn start(&mut self, mut output: OutputCallback) -> Result<Box<dyn OutputStream>> {
+        let mut config = self.config.config();
+        config.buffer_size = cpal::BufferSize::Fixed(self.buffer_size);
+        let sample_rate = config.sample_rate.0;
+
+        let stream_result = match self.config.sample_format() {
+            SampleFormat::I16 => self.device.build_output_stream(
+                &config,
+                move |data: &mut [i16], info: &cpal::OutputCallbackInfo| {
+                    output.write(data, latency_frames(info, sample_rate))
+                },
+                err_fn,
+            ),
+            SampleFormat::U16 => self.device.build_output_stream(
+                &config,
+                move |data: &mut [u16], info: &cpal::OutputCallbackInfo| {
+                    output.write(data, latency_frames(info, sample_rate))
+                },
+                err_fn,
+            ),
+            SampleFormat::F32 => self.device.build_output_stream(
+                &config,
+                move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                    output.write(data, latency_frames(info, sample_rate))
+                },
+                err_fn,
+            ),
+        };
+
+        let stream = stream_result?;
+        stream.play()?;
+
+        Ok(Box::new(stream))
+    }
+}
+
+/// Frames between the callback and the audio coming out of the device.
+fn latency_frames(info: &cpal::OutputCallbackInfo, sample_rate: u32) -> u64 {
+    let timestamp = info.timestamp();
+    let latency = timestamp
+        .playback
+        .duration_since(&timestamp.callback)
+        .unwrap_or(Duration::ZERO);
+
+    (latency.as_nanos() * sample_rate as u128 / 1_000_000_000) as u64
+}
+
+/// How a virtual device takes audio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pacing {
+    /// One buffer each buffer's worth of real time, like a sound card
+    #[default]
+    Realtime,
+    /// Each buffer as soon as the engine has one ready
+    Fast,
+}
+
+/// A device that throws its audio away. For running without audio hardware.
+pub struct NullBackend {
+    config: SupportedStreamConfig,
+    buffer_size: u32,
+    pacing: Pacing,
+}
+
+impl NullBackend {
+    pub fn new(sample_rate: u32, channels: u16, buffer_size: u32, pacing: Pacing) -> Self {
+        NullBackend {
+            config: virtual_config(sample_rate, channels),
+            buffer_size,
+            pacing,
+        }
+    }
+}
+
+impl Backend for NullBackend {
+    fn config(&self) -> &SupportedStreamConfig {
+        &self.config
+    }
+
+    fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
+    fn start(&mut self, output: OutputCallback) -> Result<Box<dyn OutputStream>> {
+        let stream =
+            VirtualStream::start(output, &self.config, self.buffer_size, self.pacing, |_| {});
+
+        Ok(Box::new(stream))
+    }
+}
+
+/// A device that keeps everything it is sent, so tests can check what the
+/// engine played.
+pub struct CaptureBackend {
+    config: SupportedStreamConfig,
+    buffer_size: u32,
+    pacing: Pacing,
+    capture: Capture,
+}
+
+impl CaptureBackend {
+    /// Returns the backend, for the engine, and a handle to what it records.
+    pub fn new(
+        sample_rate: u32,
+        channels: u16,
+        buffer_size: u32,
+        pacing: Pacing,
+    ) -> (Self, Capture) {
+        let capture = Capture::default();
+
+        let backend = CaptureBackend {
+            config: virtual_config(sample_rate, channels),
+            buffer_size,
+            pacing,
+            capture: capture.clone(),
+        };
+
+        (backend, capture)
+    }
+}
+
+impl Backend for CaptureBackend {
+    fn config(&self) -> &SupportedStreamConfig {
+        &self.config
+    }
+
+    fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
+    fn start(&mut self, output: OutputCallback) -> Result<Box<dyn OutputStream>> {
+        let capture = self.capture.clone();
+        let stream = VirtualStream::start(
+            output,
+            &self.config,
+            self.buffer_size,
+            self.pacing,
+            move |data| capture.samples.lock().extend_from_slice(data),
+        );
+
+        Ok(Box::new(stream))
+    }
+}
+
+/// What a `CaptureBackend` has been sent, as interleaved samples.
+#[derive(Clone, Default)]
+pub struct Capture {
+    samples: Arc<Mutex<Vec<f32>>>,
+}
+
+impl Capture {
+    pub fn samples(&self) -> Vec<f32> {
+        self.samples.lock().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.samples.lock().clear();
+    }
+
+    /// Waits until at least `len` samples have been captured. Returns false
+    /// if that hasn't happened within `timeout`.
+    pub fn wait_for(&self, len: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        while self.len() < len {
+            if Instant::now() > deadline {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        true
+    }
+}
+
+fn virtual_config(sample_rate: u32, channels: u16) -> SupportedStreamConfig {
+    SupportedStreamConfig::new(
+        channels,
+        SampleRate(sample_rate),
+        SupportedBufferSize::Unknown,
+        SampleFormat::F32,
+    )
+}
+
+/// Runs a virtual device's callbacks on its own thread.
+struct VirtualStream {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VirtualStream {
+    fn start<F>(
+        mut output: OutputCallback,
+        config: &SupportedStreamConfig,
+        buffer_size: u32,
+        pacing: Pacing,
+        mut sink: F,
+    ) -> Self
+    where
+        F: FnMut(&[f32]) + Send + 'static,
+    {
+        let running = Arc::new(AtomicBool::new(true));
+        let buffer_len = buffer_size.max(1) as usize * config.channels() as usize;
+        let period = Duration::from_nanos(
+            buffer_size as u64 * 1_000_000_000 / config.sample_rate().0 as u64,
+        );
+
+        let thread = {
+            let running = running.clone();
+
+            thread::spawn(move || {
+                let mut data = vec![0f32; buffer_len];
+                let mut next_callback = Instant::now();
+
+                while running.load(Ordering::Relaxed) {
+                    match pacing {
+                        Pacing::Realtime => {
+                            next_callback += period;
+                            thread::sleep(next_callback.saturating_duration_since(Instant::now()));
+                        }
+                        Pacing::Fast => {
+                            // Waiting for the engine isn't an underrun here
+                            if output.ready() < buffer_len {
+                                thread::sleep(Duration::from_micros(100));
+                                continue;
+                            }
+                        }
+                    }
+
+                    output.write(&mut data, 0);
+                    sink(&data);
+                }
+            })
+        };
+
+        VirtualStream {
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl OutputStream for VirtualStream {}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod backend_tests {
+    use crate::backend::*;
+    use crate::playhead::Playhead;
+    use crate::ring_buffer::{ring_buffer, Producer};
+    use crossbeam::channel::bounded;
+
+    fn output_callback(capacity: usize) -> (Producer, OutputCallback, Arc<StatsCollector>) {
+        let (producer, consumer) = ring_buffer(capacity);
+        let (_, markers) = bounded(1);
+        let stats = Arc::new(StatsCollector::new(capacity));
+
+        let output = OutputCallback::new(
+            consumer,
+            PlayheadTracker::new(Arc::new(Playhead::new(44100)), markers),
+            stats.clone(),
+            &virtual_config(44100, 2),
+            2,
+        );
+
+        (producer, output, stats)
+    }
+
+    #[test]
+    fn output_copies_from_the_ring_buffer() {
+        let (mut producer, mut output, stats) = output_callback(16);
+        let mut data = [0i16; 6];
+
+        producer.push(&[0.5f32, -0.5f32, 1f32, -1f32, 0f32, 0.25f32]);
+        output.write(&mut data, 0);
+
+        assert_eq!(data, [16383, -16384, 32767, -32768, 0, 8191]);
+        assert_eq!(stats.snapshot().underruns, 0);
+    }
+
+    #[test]
+    fn output_underrun_plays_silence() {
+        let (mut producer, mut output, stats) = output_callback(16);
+        let mut data = [1f32; 6];
+
+        producer.push(&[0.5f32, -0.5f32]);
+        output.write(&mut data, 0);
+
+        assert_eq!(data, [0.5f32, -0.5f32, 0f32, 0f32, 0f32, 0f32]);
+        assert_eq!(stats.snapshot().underruns, 1);
+
+        output.write(&mut data, 0);
+        assert_eq!(data, [0f32; 6]);
+        assert_eq!(stats.snapshot().underruns, 2);
+    }
+
+    #[test]
+    fn capture_records_in_order() {
+        let (mut producer, output, _) = output_callback(1024);
+        let (mut backend, capture) = CaptureBackend::new(44100, 2, 4, Pacing::Fast);
+        let samples: Vec<f32> = (0..1000).map(|i| i as f32).collect();
+
+        let stream = backend.start(output).unwrap();
+        producer.push(&samples);
+
+        assert!(capture.wait_for(1000, Duration::from_secs(5)));
+        drop(stream);
+
+        assert_eq!(capture.samples(), samples);
+    }
+
+    #[test]
+    fn realtime_pacing_takes_real_time() {
+        let (mut producer, output, stats) = output_callback(8192);
+        let (mut backend, capture) = CaptureBackend::new(1000, 1, 10, Pacing::Realtime);
+
+        producer.push(&[0f32; 8192]);
+        let started = Instant::now();
+        let stream = backend.start(output).unwrap();
+
+        // 50 frames at 1kHz
+        assert!(capture.wait_for(50, Duration::from_secs(5)));
+        drop(stream);
+
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(stats.snapshot().underruns, 0);
+    }
+
+    #[test]
+    fn null_backend_stops_when_dropped() {
+        let (_, output, stats) = output_callback(16);
+        let mut backend = NullBackend::new(1000, 1, 1, Pacing::Realtime);
+
+        let stream = backend.start(output).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        drop(stream);
+
+        // Nothing was ever pushed, so every callback underran
+        let underruns = stats.snapshot().underruns;
+        assert!(underruns > 0);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(stats.snapshot().underruns, underruns);
+    }
+}
@@ -1,171 +1,1702 @@
-use crossbeam::channel::{self, Receiver, Sender};
 use parking_lot::Mutex;
 use std::{
-    sync::{mpsc::channel, Arc},
-    thread::{self, JoinHandle},
-    time::Duration,
+    collections::VecDeque,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
-use cpal::{StreamConfig, SupportedStreamConfig};
+use cpal::StreamConfig;
 
-use crate::{engine::EngineController, source_reader::SourceReader, symph::Symphonia};
+use crate::{
+    automation::{Automation, AutomationLane, ParamAutomation},
+    convolution::{ConvolutionSettings, Convolver, ImpulseResponse},
+    delay::{Delay, DelaySettings},
+    dynamics::{Compressor, CompressorSettings, Gate, GateSettings},
+    engine::EngineController,
+    eq::{EqBand, ParametricEq},
+    error::{Error, Result},
+    loudness::LoudnessMeter,
+    master::{MasterBus, MasterModel},
+    meter::{Meter, MeterSettings, Meters},
+    mixer::{Gain, InsertChain, Mixer, PanLaw, Processor},
+    param::{InsertParams, ParamHandle},
+    reverb::{Reverb, ReverbSettings},
+    routing::Routing,
+    sample_rate::SampleRate,
+    source_reader::SourceReader,
+    symph::Symphonia,
+};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct ClipModel {
     pub path: String,
     pub start_time_ms: i32,
     pub duration_ms: u32,
+    /// Where in the file the clip starts playing from (trim-in)
+    pub source_offset_ms: u32,
+    /// When both are set, the clip loops this region of the file (times in the
+    /// file, not the timeline) until its duration is up.
+    pub loop_start_ms: Option<u32>,
+    pub loop_end_ms: Option<u32>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct ChannelModel {
     pub id: String,
     // channel_count: usize, // Probalby don't need yet
     pub clips: Vec<ClipModel>,
+    /// Fader level in dB, 0 is unity
+    pub volume_db: f32,
+    /// From -1 (hard left) to 1 (hard right)
+    pub pan: f32,
+    pub mute: bool,
+    /// When any channel is soloed only soloed channels are heard
+    pub solo: bool,
+    /// Id of the bus the channel plays into, or the master when `None`
+    pub output: Option<String>,
+    pub sends: Vec<SendModel>,
+    /// Effects the channel runs through, in order, before its sends and fader
+    pub inserts: Vec<InsertModel>,
+    /// Moves the fader over the timeline, in dB. Empty for none.
+    pub volume_automation: AutomationLane,
+    /// Moves the pan over the timeline. Empty for none.
+    pub pan_automation: AutomationLane,
+    pub param_automation: Vec<ParamAutomation>,
+}
+
+/// An effect in a channel's or bus's insert chain. Each playback built from
+/// the model gets its own fresh instance.
+#[derive(Clone, Debug)]
+pub enum InsertModel {
+    /// A fixed gain in dB
+    Gain(f32),
+    /// A parametric EQ with these bands
+    Eq(Vec<EqBand>),
+    Compressor(CompressorSettings),
+    /// A gate or expander
+    Gate(GateSettings),
+    /// A delay, which can be synced to the mixer's tempo
+    Delay(DelaySettings),
+    Reverb(ReverbSettings),
+    /// A convolution reverb, loading its impulse response when the playback
+    /// is built
+    Convolution(ConvolutionSettings),
+    /// Any other processor
+    Custom(ProcessorFactory),
+}
+
+impl InsertModel {
+    fn instantiate(&self, tempo_bpm: f32, sample_rate: u32) -> Result<Box<dyn Processor>> {
+        Ok(match self {
+            InsertModel::Gain(gain_db) => Box::new(Gain::new(*gain_db)),
+            InsertModel::Eq(bands) => Box::new(ParametricEq::new(bands)),
+            InsertModel::Compressor(settings) => Box::new(Compressor::new(settings)),
+            InsertModel::Gate(settings) => Box::new(Gate::new(settings)),
+            InsertModel::Delay(settings) => Box::new(Delay::new(settings, tempo_bpm)),
+            InsertModel::Reverb(settings) => Box::new(Reverb::new(settings)),
+            InsertModel::Convolution(settings) => {
+                let ir = ImpulseResponse::load(&settings.path, sample_rate)?;
+                Box::new(Convolver::new(&ir, settings))
+            }
+            InsertModel::Custom(factory) => (factory.0)(),
+        })
+    }
+
+    /// Id of the channel the insert follows the level of, if it does.
+    fn sidechain(&self) -> Option<&str> {
+        match self {
+            InsertModel::Compressor(settings) => settings.sidechain.as_deref(),
+            InsertModel::Gate(settings) => settings.sidechain.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Makes a new instance of a processor each time a playback is built.
+#[derive(Clone)]
+pub struct ProcessorFactory(Arc<dyn Fn() -> Box<dyn Processor> + Send + Sync>);
+
+impl ProcessorFactory {
+    pub fn new<F>(factory: F) -> Self
+    where
+        F: Fn() -> Box<dyn Processor> + Send + Sync + 'static,
+    {
+        ProcessorFactory(Arc::new(factory))
+    }
+}
+
+impl std::fmt::Debug for ProcessorFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProcessorFactory")
+    }
+}
+
+/// A copy of a channel's (or bus's) audio sent on to a bus as well as its
+/// output, e.g. to share one reverb between many channels.
+#[derive(Clone, Debug, Default)]
+pub struct SendModel {
+    /// Id of the bus to send to
+    pub bus: String,
+    pub level_db: f32,
+    /// Taken before the fader and pan rather than after
+    pub pre_fader: bool,
+}
+
+/// A bus that channels and other buses can output or send to: a group when
+/// channels output to it, an aux when they send to it.
+#[derive(Clone, Debug, Default)]
+pub struct BusModel {
+    pub id: String,
+    pub volume_db: f32,
+    pub pan: f32,
+    pub mute: bool,
+    /// Id of the bus this one plays into, or the master when `None`
+    pub output: Option<String>,
+    pub sends: Vec<SendModel>,
+    /// Effects the bus runs through, in order, before its sends and fader,
+    /// e.g. the reverb on an aux
+    pub inserts: Vec<InsertModel>,
 }
 
 #[derive(Clone, Debug)]
 pub struct MixerModel {
     pub channels: Vec<ChannelModel>,
+    pub buses: Vec<BusModel>,
+    pub pan_law: PanLaw,
+    pub master: MasterModel,
+    pub metering: MeterSettings,
+    /// Beats per minute, for inserts synced to the tempo
+    pub tempo_bpm: f32,
+}
+
+impl Default for MixerModel {
+    fn default() -> Self {
+        MixerModel {
+            channels: Vec::new(),
+            buses: Vec::new(),
+            pan_law: PanLaw::default(),
+            master: MasterModel::default(),
+            metering: MeterSettings::default(),
+            tempo_bpm: 120f32,
+        }
+    }
 }
 
 pub struct PlaybackBuilder {}
 
 pub struct Playback {
     channels: Vec<Channel>,
+    mixer: Mixer,
+    routing: Routing,
+    master: MasterBus,
+    // One per channel, after its fader
+    meters: Vec<Meter>,
+    master_meter: Meter,
+    loudness: LoudnessMeter,
     config: StreamConfig,
+    scratch: Vec<f32>,
+    // Each channel's clips for the block, before its inserts
+    clip_audio: Vec<Vec<f32>>,
+    // The channels are summed here before the master bus
+    mix: Vec<f64>,
+    // Timeline frame after the last one any channel had audio in
+    audio_end: u64,
+    // Timeline position of the next frame to be rendered
+    position: u64,
+    // How long each channel took to render the last block
+    render_times: Vec<Duration>,
 }
 
 pub struct PlayableClip {
     reader: SourceReader,
     clip_model: ClipModel,
+    // Where the clip sits on the timeline, in frames. Can be negative, in
+    // which case the head of the clip is cut off.
+    start_frame: i64,
+    length: u64,
+    // How many frames into the clip the reader is
+    frames_read: u64,
+    // Trim-in and loop region of the clip in the source's frames
+    source_offset: u64,
+    loop_region: Option<(u64, u64)>,
+}
+
+impl PlayableClip {
+    fn new(mut reader: SourceReader, clip_model: ClipModel, sample_rate: &SampleRate) -> Self {
+        let source_rate = SampleRate(reader.source_sample_rate());
+        let source_frame = |ms: u32| source_rate.ms_to_frame(ms as i64) as u64;
+
+        let source_offset = source_frame(clip_model.source_offset_ms);
+        let loop_region = match (clip_model.loop_start_ms, clip_model.loop_end_ms) {
+            (Some(start), Some(end)) if start < end => {
+                Some((source_frame(start), source_frame(end)))
+            }
+            _ => None,
+        };
+
+        if let Some((start, end)) = loop_region {
+            reader.set_loop(start, end);
+        }
+
+        let mut clip = PlayableClip {
+            reader,
+            start_frame: sample_rate.ms_to_frame(clip_model.start_time_ms as i64),
+            length: sample_rate.ms_to_frame(clip_model.duration_ms as i64) as u64,
+            clip_model,
+            frames_read: 0,
+            source_offset,
+            loop_region,
+        };
+
+        if source_offset > 0 {
+            clip.seek(0);
+        }
+
+        clip
+    }
+
+    /// Where in the source file (in source frames) the clip is `clip_frame`
+    /// frames after it starts, taking the trim-in and loop into account.
+    fn source_frame(&self, clip_frame: u64) -> u64 {
+        let ratio =
+            self.reader.source_sample_rate() as f64 / self.reader.target_sample_rate() as f64;
+        let position = self.source_offset + (clip_frame as f64 * ratio) as u64;
+
+        match self.loop_region {
+            Some((start, end)) if self.source_offset < end && position >= end => {
+                start + (position - end) % (end - start)
+            }
+            _ => position,
+        }
+    }
+
+    /// Moves the reader to `clip_frame` frames into the clip.
+    fn seek(&mut self, clip_frame: u64) {
+        // Seeking past the end of the source leaves the reader finished, which
+        // plays as silence
+        let _ = self.reader.seek(self.source_frame(clip_frame));
+        self.frames_read = clip_frame;
+    }
+
+    fn end_frame(&self) -> i64 {
+        self.start_frame + self.length as i64
+    }
+
+    /// Adds the part of the clip that falls within the block of frames starting
+    /// at `position` on the timeline into `out`.
+    fn render(&mut self, out: &mut [f32], position: u64, channel_count: usize) {
+        let block_start = position as i64;
+        let block_end = block_start + (out.len() / channel_count) as i64;
+
+        let from = block_start.max(self.start_frame);
+        let to = block_end.min(self.end_frame());
+
+        if from >= to {
+            return;
+        }
+
+        // Move the reader to where this block starts in the clip. This is
+        // what cuts the head off clips that start before the timeline does.
+        let offset = (from - self.start_frame) as u64;
+        if self.frames_read != offset {
+            self.seek(offset);
+        }
+
+        let out_from = (from - block_start) as usize * channel_count;
+        let out_to = (to - block_start) as usize * channel_count;
+
+        // A source shorter than the clip's duration leaves silence
+        for sample in out[out_from..out_to].iter_mut() {
+            match self.reader.next() {
+                Some(s) => *sample += s,
+                None => break,
+            }
+        }
+
+        self.frames_read += (to - from) as u64;
+    }
 }
 
 struct Channel {
+    id: String,
     // clips: Vec<SourceReader>,
     clips: Vec<PlayableClip>,
+    inserts: InsertChain,
+    volume: Option<Automation>,
+    pan: Option<Automation>,
+    params: Vec<(ParamHandle, Automation)>,
+    // Whether automated parameters jump to the lane rather than ramp there
+    // next block, e.g. after a seek
+    jump_params: bool,
+    // This block's automated fader and pan, one value per frame
+    volumes: Vec<f32>,
+    pans: Vec<f32>,
+    // Holds the inserts' output back so every channel comes out as late as
+    // the one with the most latency
+    compensation: VecDeque<f32>,
+    compensation_frames: usize,
+    channel_count: usize,
+    // Timeline position of the next frame to be rendered
+    position: u64,
+    // The frame after the last clip ends
+    end_frame: u64,
 }
 
-impl Channel {}
+impl Channel {
+    fn new(
+        id: String,
+        clips: Vec<PlayableClip>,
+        inserts: InsertChain,
+        params: Vec<(ParamHandle, Automation)>,
+        channel_count: usize,
+    ) -> Self {
+        let end_frame = clips
+            .iter()
+            .map(|clip| clip.end_frame().max(0) as u64)
+            .max()
+            .unwrap_or(0);
+
+        Channel {
+            id,
+            clips,
+            inserts,
+            volume: None,
+            pan: None,
+            params,
+            jump_params: true,
+            volumes: Vec::new(),
+            pans: Vec::new(),
+            compensation: VecDeque::new(),
+            compensation_frames: 0,
+            channel_count,
+            position: 0,
+            end_frame,
+        }
+    }
+
+    /// Fills `out` with the next interleaved frames of the timeline, before
+    /// the inserts. Each clip plays from its start time for its duration, with
+    /// silence wherever no clip is playing. Overlapping clips are summed.
+    ///
+    /// Returns how many samples of `out` come before the end of the last clip.
+    fn render_clips(&mut self, out: &mut [f32]) -> usize {
+        let frames = out.len() / self.channel_count;
+
+        out.fill(0f32);
+
+        for clip in self.clips.iter_mut() {
+            clip.render(out, self.position, self.channel_count);
+        }
+
+        let remaining = self.end_frame.saturating_sub(self.position);
+        remaining.min(frames as u64) as usize * self.channel_count
+    }
+
+    /// Delays the channel by `frames` after its inserts, on top of their own
+    /// latency.
+    fn set_compensation(&mut self, frames: usize) {
+        let samples = frames * self.channel_count;
+        self.compensation_frames = frames;
+        self.compensation = VecDeque::with_capacity(samples + 1);
+        self.compensation.resize(samples, 0f32);
+    }
+
+    /// Runs the block `render_clips` rendered through the inserts and moves
+    /// on to the next block. `channels` is every channel's block from
+    /// `render_clips`, for inserts with a sidechain.
+    fn run_inserts(&mut self, out: &mut [f32], channels: &[Vec<f32>]) {
+        if self.params.is_empty() {
+            self.inserts.process_with_sidechains(out, channels, 0);
+        } else {
+            self.process_automated(out, channels);
+        }
+
+        if self.compensation_frames > 0 {
+            for sample in out.iter_mut() {
+                self.compensation.push_back(*sample);
+                *sample = self.compensation.pop_front().unwrap_or(0f32);
+            }
+        }
+
+        self.position += (out.len() / self.channel_count) as u64;
+    }
+
+    /// Moves to `frame` on the timeline. Clips catch their readers up the next
+    /// time they render.
+    fn seek(&mut self, frame: u64) {
+        self.position = frame;
+
+        for (param, lane) in self.params.iter() {
+            param.set_immediate(lane.value_at(frame));
+        }
+        self.jump_params = true;
+        self.inserts.reset();
+        self.compensation.iter_mut().for_each(|s| *s = 0f32);
+    }
+
+    /// Runs `out` through the inserts a little at a time, moving automated
+    /// parameters on between each part. Parts end on breakpoints, and each
+    /// parameter ramps in a straight line to where its lane is at the end of
+    /// the part. Where a lane jumps, e.g. on a step, the part is a single frame
+    /// that the parameter jumps for, so the step lands on the right frame.
+    fn process_automated(&mut self, out: &mut [f32], channels: &[Vec<f32>]) {
+        let frames = out.len() / self.channel_count;
+        let clamp = |param: &ParamHandle, value: f32| {
+            let info = param.info();
+            value.clamp(info.min, info.max)
+        };
+        let mut done = 0;
+
+        while done < frames {
+            let frame = self.position + done as u64;
+            let mut length = (frames - done).min(AUTOMATION_BLOCK_FRAMES);
+
+            for (_, lane) in self.params.iter() {
+                if let Some(next) = lane.next_breakpoint(frame) {
+                    length = length.min((next - frame) as usize);
+                }
+            }
+
+            // The last part left each parameter where its lane was, unless
+            // the lane jumps here
+            let jump = std::mem::take(&mut self.jump_params)
+                || self
+                    .params
+                    .iter()
+                    .any(|(param, lane)| param.get() != clamp(param, lane.value_at(frame)));
+            if jump {
+                length = 1;
+            }
+
+            for (param, lane) in self.params.iter() {
+                if jump {
+                    param.set_immediate(lane.value_at(frame));
+                } else {
+                    let end = lane.value_before(frame + length as u64);
+                    param.ramp_to(end, length as u32);
+                }
+            }
+
+            let from = done * self.channel_count;
+            let to = (done + length) * self.channel_count;
+            self.inserts
+                .process_with_sidechains(&mut out[from..to], channels, from);
+            done += length;
+        }
+    }
+
+    /// Works out the automated fader and pan for the `frames` frames the
+    /// inserts put out for the block from `start`. Those are delayed by the
+    /// inserts' latency and the compensation, so the lanes are read from that
+    /// much earlier.
+    fn automate_strip(&mut self, start: u64, frames: usize) -> (Option<&[f32]>, Option<&[f32]>) {
+        let lag = (self.inserts.latency() + self.compensation_frames) as u64;
+        // Frames from before the timeline starts, which hold the first value
+        let early = (lag.saturating_sub(start) as usize).min(frames);
+        let start = start.saturating_sub(lag);
+
+        let fill = |lane: &Option<Automation>, values: &mut Vec<f32>| {
+            lane.as_ref().map(|lane| {
+                values.resize(frames, 0f32);
+                values[..early].fill(lane.value_at(0));
+                lane.fill(start, &mut values[early..]);
+            })
+        };
+
+        let volume = fill(&self.volume, &mut self.volumes);
+        let pan = fill(&self.pan, &mut self.pans);
+
+        (
+            volume.map(|_| &self.volumes[..]),
+            pan.map(|_| &self.pans[..]),
+        )
+    }
+
+    /// Frames the channel can still sound for after its last clip ends
+    fn tail(&self) -> u64 {
+        (self.inserts.latency() + self.compensation_frames + self.inserts.tail()) as u64
+    }
+}
+
+impl Playback {
+    pub fn config(&self) -> &StreamConfig {
+        &self.config
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.config.channels as usize
+    }
+
+    /// Ids of the channels in the order they are mixed (and tapped by
+    /// `render_with_stems`).
+    pub fn channel_ids(&self) -> Vec<&str> {
+        self.channels.iter().map(|c| c.id.as_str()).collect()
+    }
+
+    /// How long each channel, in `channel_ids` order, took to read, decode and
+    /// resample its clips for the last block.
+    pub fn channel_render_times(&self) -> &[Duration] {
+        &self.render_times
+    }
+
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+
+    /// For changing channel strips between blocks.
+    pub fn mixer_mut(&mut self) -> &mut Mixer {
+        &mut self.mixer
+    }
+
+    pub fn master(&self) -> &MasterBus {
+        &self.master
+    }
+
+    pub fn master_mut(&mut self) -> &mut MasterBus {
+        &mut self.master
+    }
+
+    /// Frames the channels are held back by on the way to the master so that
+    /// going through buses with latency keeps them in time.
+    pub fn bus_latency(&self) -> usize {
+        self.routing.latency()
+    }
+
+    /// Handles for reading the channel and master meters from another thread.
+    pub fn meters(&self) -> Meters {
+        Meters {
+            channels: self.meters.iter().map(|meter| meter.handle()).collect(),
+            master: self.master_meter.handle(),
+            loudness: self.loudness.handle(),
+            gain_reduction: self
+                .channels
+                .iter()
+                .map(|channel| channel.inserts.gain_reduction())
+                .collect(),
+            bus_gain_reduction: self
+                .routing
+                .bus_inserts()
+                .map(|inserts| inserts.gain_reduction())
+                .collect(),
+        }
+    }
+
+    /// Handles for changing the inserts' parameters from another thread.
+    pub fn params(&self) -> InsertParams {
+        InsertParams::new(
+            self.channels
+                .iter()
+                .map(|channel| channel.inserts.params())
+                .collect(),
+            self.routing
+                .bus_inserts()
+                .map(|inserts| inserts.params())
+                .collect(),
+        )
+    }
+
+    /// Timeline frame the next call to `render` starts at.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Moves every channel to `frame` on the timeline, so the next call to
+    /// `render` starts there.
+    pub fn seek(&mut self, frame: u64) {
+        for channel in self.channels.iter_mut() {
+            channel.seek(frame);
+        }
+
+        self.routing.reset();
+        self.master.reset();
+        self.position = frame;
+        self.audio_end = frame;
+    }
+
+    /// Mixes the next `out.len() / channel_count()` frames of every channel
+    /// into `out` as interleaved samples, through the mixer's channel strips.
+    ///
+    /// Returns the number of frames that still had audio in at least one
+    /// channel, counting any the inserts or master bus held back or are still
+    /// ringing out with. Once every channel has run out and the inserts and
+    /// master bus are empty this returns 0 and `out` is filled with silence.
+    pub fn render(&mut self, out: &mut [f32]) -> usize {
+        self.render_channels(out, |_, _| {})
+    }
+
+    /// Same as `render`, but also copies each channel's own output, after its
+    /// fader and pan, into the matching buffer in `stems`. Stems are in
+    /// `channel_ids` order and each one is replaced with exactly the frames
+    /// mixed into `out`. They are taken before the buses and master bus, so
+    /// `out` lags them by `bus_latency() + master().latency()` frames.
+    pub fn render_with_stems(&mut self, out: &mut [f32], stems: &mut [Vec<f32>]) -> usize {
+        assert_eq!(
+            stems.len(),
+            self.channels.len(),
+            "Need one stem per channel"
+        );
+
+        self.render_channels(out, |index, samples| {
+            let stem = &mut stems[index];
+            stem.clear();
+            stem.extend_from_slice(samples);
+        })
+    }
+
+    /// Renders every channel in turn, hands its block to `tap` and routes it
+    /// to its output and sends. Then runs the buses and sends the sum through
+    /// the master bus into `out`.
+    fn render_channels<T>(&mut self, out: &mut [f32], mut tap: T) -> usize
+    where
+        T: FnMut(usize, &[f32]),
+    {
+        let channel_count = self.channel_count();
+        let frames = out.len() / channel_count;
+        let samples = frames * channel_count;
+
+        self.mix.clear();
+        self.mix.resize(samples, 0f64);
+        self.routing.start_block(samples);
+        self.render_times
+            .resize(self.channels.len(), Duration::ZERO);
+
+        let start = self.position;
+
+        // Every channel's clips are rendered first, so any of them can be a
+        // sidechain for the others' inserts
+        self.clip_audio
+            .resize_with(self.channels.len(), Default::default);
+
+        for (index, channel) in self.channels.iter_mut().enumerate() {
+            let audio = &mut self.clip_audio[index];
+            audio.resize(samples, 0f32);
+
+            let started = Instant::now();
+            let read = channel.render_clips(audio);
+            self.render_times[index] = started.elapsed();
+
+            // Round partially read frames up so a trailing frame isn't
+            // dropped, and let the inserts and buses ring out after the clips
+            // end
+            if read > 0 {
+                let tail = channel.tail() + self.routing.channel_tail(index) as u64;
+                let end = start + read.div_ceil(channel_count) as u64 + tail;
+                self.audio_end = self.audio_end.max(end);
+            }
+        }
+
+        for (index, channel) in self.channels.iter_mut().enumerate() {
+            self.scratch.clear();
+            self.scratch.extend_from_slice(&self.clip_audio[index]);
+            channel.run_inserts(&mut self.scratch, &self.clip_audio);
+
+            let audible = self.mixer.is_audible(index);
+            if audible {
+                self.routing.send(index, &self.scratch, true);
+            }
+
+            let (volume_db, pan) = channel.automate_strip(start, frames);
+            self.mixer
+                .apply_automated(index, &mut self.scratch, channel_count, volume_db, pan);
+            self.meters[index].process(&self.scratch);
+            tap(index, &self.scratch);
+
+            if audible {
+                self.routing.send(index, &self.scratch, false);
+                self.routing.output(index, &self.scratch, &mut self.mix);
+            }
+        }
+
+        self.routing.run_buses(
+            self.mixer.pan_law(),
+            channel_count,
+            &self.clip_audio,
+            &mut self.mix,
+        );
+
+        self.master.process(&mut self.mix, &mut out[..samples]);
+        self.master_meter.process(&out[..samples]);
+        self.loudness.process(&out[..samples]);
+        out[samples..].fill(0f32);
+
+        self.position += frames as u64;
+
+        // Whatever the master bus is holding back still has to come out
+        let audible_end = self.audio_end + self.master.latency() as u64;
+        (audible_end.saturating_sub(start) as usize).min(frames)
+    }
+}
 
 impl PlaybackBuilder {
     // TODO: Make config a member of playback builder or something.
-    pub fn new<'a>(mixer: &'a MixerModel, config: StreamConfig) -> Result<Playback, ()> {
+    pub fn new<'a>(mixer: &'a MixerModel, config: StreamConfig) -> Result<Playback> {
+        let mut routing = Routing::new(mixer)?;
+
         // Maybe use with_capacity
         let mut channels = Vec::<Channel>::with_capacity(mixer.channels.len());
 
-        // Start a new thread for each channel
+        let sample_rate = SampleRate(config.sample_rate.0);
+        let channel_count = config.channels as usize;
+
         for chan in mixer.channels.iter() {
             let mut clips = Vec::<PlayableClip>::with_capacity(chan.clips.len());
 
             for clip in chan.clips.iter() {
-                let symp = Symphonia::new(clip.path.clone()).expect("Clip should have opened file");
-                let reader = SourceReader::new(symp, config.clone());
+                let symp = Symphonia::new(clip.path.clone())?;
+                let reader = SourceReader::new(symp, config.clone())?;
 
-                let playable_clip = PlayableClip {
-                    reader,
-                    clip_model: clip.clone(),
-                };
+                clips.push(PlayableClip::new(reader, clip.clone(), &sample_rate));
+            }
+
+            let mut inserts = insert_chain(&chan.inserts, mixer, config.sample_rate.0)?;
+
+            let insert_params = inserts.params();
+            let mut params = Vec::new();
+            for automation in chan.param_automation.iter() {
+                let param = insert_params
+                    .get(automation.insert)
+                    .and_then(|params| {
+                        params
+                            .iter()
+                            .find(|param| param.info().name == automation.param)
+                    })
+                    .ok_or_else(|| Error::UnknownParam(automation.param.clone()))?;
 
-                clips.push(playable_clip);
+                if let Some(lane) = Automation::new(&automation.lane, config.sample_rate.0) {
+                    // Start where the lane does rather than smoothing over
+                    param.set_immediate(lane.value_at(0));
+                    params.push((param.clone(), lane));
+                }
             }
 
-            channels.push(Channel { clips });
+            inserts.prepare(config.sample_rate.0, channel_count, block_size(&config));
+
+            let mut channel = Channel::new(chan.id.clone(), clips, inserts, params, channel_count);
+            channel.volume = Automation::new(&chan.volume_automation, config.sample_rate.0);
+            channel.pan = Automation::new(&chan.pan_automation, config.sample_rate.0);
+            channels.push(channel);
         }
 
+        // Channels with less latency wait for the rest, so they stay in time
+        let latency = channels
+            .iter()
+            .map(|channel| channel.inserts.latency())
+            .max()
+            .unwrap_or(0);
+        for channel in channels.iter_mut() {
+            let frames = latency - channel.inserts.latency();
+            channel.set_compensation(frames);
+        }
+
+        for (index, bus) in mixer.buses.iter().enumerate() {
+            let mut inserts = insert_chain(&bus.inserts, mixer, config.sample_rate.0)?;
+            inserts.prepare(config.sample_rate.0, channel_count, block_size(&config));
+            routing.set_bus_inserts(index, inserts);
+        }
+        // And the ways through the buses with less latency wait for the rest
+        routing.compensate(channel_count);
+
         // Ok(Playback { channels })
-        Ok(Playback { channels, config })
+        Ok(Playback {
+            channels,
+            mixer: Mixer::new(mixer),
+            routing,
+            master: MasterBus::new(&mixer.master, config.sample_rate.0, channel_count),
+            meters: (0..mixer.channels.len())
+                .map(|_| Meter::new(&mixer.metering, config.sample_rate.0, channel_count))
+                .collect(),
+            master_meter: Meter::new(&mixer.metering, config.sample_rate.0, channel_count),
+            loudness: LoudnessMeter::new(config.sample_rate.0, channel_count),
+            config,
+            mix: Default::default(),
+            audio_end: 0,
+            scratch: Default::default(),
+            clip_audio: Default::default(),
+            position: 0,
+            render_times: Default::default(),
+        })
     }
 
     pub fn test(playback: Playback, engine_controller: Arc<Mutex<EngineController>>) {
-        let mut thread_handlers = Vec::new();
-
-        let mut channel_receivers = Vec::<Receiver<f32>>::with_capacity(playback.channels.len());
-        let (mixer_tx, mixer_rx) = channel::bounded::<f32>(playback.channels.len());
         println!("BFS {:?}", playback.config.buffer_size);
-        let buffer_size = match playback.config.buffer_size {
-            cpal::BufferSize::Fixed(buffer_size) => buffer_size as usize,
-            cpal::BufferSize::Default => 1024,
-        };
-
-        println!("Buffer Size {}", buffer_size);
-
-        // TODO: Only play channels with clips
-        // TODO: Only play channels with clips after the current play head
-        for channel in playback.channels {
-            let (channel_tx, channel_rx) = channel::bounded::<f32>(buffer_size * 2);
-            channel_receivers.push(channel_rx);
-
-            let t = thread::spawn(move || {
-                let mut clips = channel.clips;
-                println!("Spawned!");
-                // let clip_count = channel.clips.len();
-                if let Some(clip) = clips.get_mut(0) {
-                    while let Some(sample) = clip.reader.next() {
-                        // println!("Got sample");
-                        match channel_tx.send(sample) {
-                            Err(e) => {
-                                eprintln!("{}", e);
-                            }
-                            _ => {}
-                        }
-                    }
-
-                    loop {
-                        channel_tx.send(0f32).unwrap();
-                    }
-                }
-            });
-
-            thread_handlers.push(t);
-        }
-
-        let engine = engine_controller.clone();
-
-        let mixer_thread = thread::spawn(move || {
-            println!("WTF");
-            let engine_lock = engine.lock();
-            engine_lock.play();
-            loop {
-                let mut sample_total = 0f32;
-                // For now using this to start the stream
-
-                // For each channel's sender, get the next sample. These should all be
-                // in sync
-                for channel_receiver in channel_receivers.iter_mut() {
-                    match channel_receiver.recv() {
-                        Ok(sample) => {
-                            // println!("{}", sample * 10000000f32);
-                            // println!("{}", sample);
-                            sample_total += sample;
-                        }
-                        Err(e) => {
-                            eprintln!("BAD THINGS {}", e)
-                        }
-                    }
-                }
+        println!("Buffer Size {}", block_size(&playback.config));
+
+        let engine = engine_controller.lock();
+        engine
+            .load(playback)
+            .expect("Playback should match the output");
+        engine.play().expect("Output stream should start");
+        drop(engine);
+
+        // Once every channel has run out the transport keeps sending silence
+        loop {
+            thread::park();
+        }
+    }
+}
+
+/// Builds the processors for `inserts`, each following its sidechain channel
+/// if it has one.
+fn insert_chain(
+    inserts: &[InsertModel],
+    mixer: &MixerModel,
+    sample_rate: u32,
+) -> Result<InsertChain> {
+    let mut chain = InsertChain::new(
+        inserts
+            .iter()
+            .map(|insert| insert.instantiate(mixer.tempo_bpm, sample_rate))
+            .collect::<Result<_>>()?,
+    );
+
+    for (index, insert) in inserts.iter().enumerate() {
+        if let Some(id) = insert.sidechain() {
+            let channel = mixer
+                .channels
+                .iter()
+                .position(|channel| channel.id == id)
+                .ok_or_else(|| Error::UnknownChannel(id.to_string()))?;
+            chain.set_sidechain(index, Some(channel));
+        }
+    }
+
+    Ok(chain)
+}
 
-                // println!("Sample total {}", sample_total);
+/// Most frames the inserts run for before automated parameters are moved on.
+const AUTOMATION_BLOCK_FRAMES: usize = 32;
 
-                engine_lock
-                    .prod
-                    // .send(sample_total / 50f32)
-                    .send(sample_total)
-                    .expect("Should have seft");
-                // mixer_tx.send(sample_total).expect("Should have sent");
-                sample_total = 0f32;
+/// Number of frames to mix at a time for the given config.
+pub(crate) fn block_size(config: &StreamConfig) -> usize {
+    match config.buffer_size {
+        cpal::BufferSize::Fixed(buffer_size) => buffer_size as usize,
+        cpal::BufferSize::Default => 1024,
+    }
+}
+
+#[cfg(test)]
+mod builder_tests {
+    use crate::automation::{Breakpoint, Curve};
+    use crate::builder::*;
+    use crate::delay::DelayTime;
+    use crate::master::Protection;
+    use crate::mixer::db_to_gain;
+    use crate::render::OfflineRenderer;
+    use cpal::{BufferSize, SampleRate};
+
+    // sample-2.wav is 94208 mono frames at 44.1k
+    const PATH: &str = "sounds/sample-2.wav";
+
+    fn source() -> Vec<f32> {
+        Symphonia::new(PATH.to_string()).unwrap().collect()
+    }
+
+    fn clip(start_time_ms: i32, duration_ms: u32) -> ClipModel {
+        ClipModel {
+            path: PATH.to_string(),
+            start_time_ms,
+            duration_ms,
+            ..Default::default()
+        }
+    }
+
+    fn config() -> StreamConfig {
+        StreamConfig {
+            channels: 2,
+            sample_rate: SampleRate(44100),
+            buffer_size: BufferSize::Fixed(500),
+        }
+    }
+
+    fn playback(clips: Vec<ClipModel>) -> Playback {
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                id: "chan-1".to_string(),
+                clips,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        PlaybackBuilder::new(&mixer, config()).unwrap()
+    }
+
+    /// Renders a single channel and returns its left channel
+    fn render(clips: Vec<ClipModel>) -> Vec<f32> {
+        let playback = playback(clips);
+        let mut out = Vec::new();
+        OfflineRenderer::new(playback).render_to(&mut out).unwrap();
+
+        out.chunks(2).map(|frame| frame[0]).collect()
+    }
+
+    #[test]
+    fn clip_plays_for_its_duration() {
+        let source = source();
+        let out = render(vec![clip(0, 1000)]);
+
+        assert_eq!(out.len(), 44100);
+        assert_eq!(out[..], source[..44100]);
+    }
+
+    /// Delays its input by a number of frames
+    struct Delay {
+        frames: usize,
+        line: std::collections::VecDeque<f32>,
+    }
+
+    impl Processor for Delay {
+        fn prepare(&mut self, _: u32, channel_count: usize, _: usize) {
+            self.line = vec![0f32; self.frames * channel_count].into();
+        }
+
+        fn process(&mut self, samples: &mut [f32]) {
+            for sample in samples.iter_mut() {
+                self.line.push_back(*sample);
+                *sample = self.line.pop_front().unwrap();
             }
-        });
+        }
+
+        fn latency(&self) -> usize {
+            self.frames
+        }
+
+        fn reset(&mut self) {
+            self.line.iter_mut().for_each(|s| *s = 0f32);
+        }
+    }
+
+    #[test]
+    fn inserts_run_and_ring_out() {
+        let source = source();
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                clips: vec![clip(0, 1000)],
+                inserts: vec![
+                    InsertModel::Gain(-6f32),
+                    InsertModel::Custom(ProcessorFactory::new(|| {
+                        Box::new(Delay {
+                            frames: 100,
+                            line: Default::default(),
+                        })
+                    })),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+        let mut out = Vec::new();
+        OfflineRenderer::new(playback).render_to(&mut out).unwrap();
+
+        // The delayed end of the clip still comes out
+        assert_eq!(out.len(), (44100 + 100) * 2);
+        let gain = db_to_gain(-6f32);
+        assert!(out[..200].iter().all(|s| *s == 0f32));
+        for i in 0..44100 {
+            assert_eq!(out[(i + 100) * 2], source[i] * gain);
+        }
+
+        // Seeking clears out the delay
+        playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+        let mut block = vec![0f32; 1000];
+        playback.render(&mut block);
+        playback.seek(0);
+        playback.render(&mut block);
+        assert!(block[..200].iter().all(|s| *s == 0f32));
+    }
+
+    #[test]
+    fn channels_are_delayed_to_line_up() {
+        let delayed = ChannelModel {
+            id: "delayed".to_string(),
+            clips: vec![clip(0, 1000)],
+            inserts: vec![InsertModel::Custom(ProcessorFactory::new(|| {
+                Box::new(Delay {
+                    frames: 100,
+                    line: Default::default(),
+                })
+            }))],
+            ..Default::default()
+        };
+        let dry = ChannelModel {
+            id: "dry".to_string(),
+            clips: vec![clip(0, 1000)],
+            ..Default::default()
+        };
+        let mixer = MixerModel {
+            channels: vec![delayed, dry],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+
+        let mut out = vec![0f32; 2000];
+        let mut stems = vec![Vec::new(), Vec::new()];
+        playback.render_with_stems(&mut out, &mut stems);
+
+        // The dry channel waits for the delayed one
+        assert!(stems[1][..200].iter().all(|s| *s == 0f32));
+        assert!(stems[1][200..].iter().any(|s| *s != 0f32));
+        assert_eq!(stems[0], stems[1]);
+    }
+
+    #[test]
+    fn delay_and_reverb_tails_are_rendered() {
+        let render = |insert: InsertModel| {
+            let mixer = MixerModel {
+                channels: vec![ChannelModel {
+                    clips: vec![clip(0, 500)],
+                    inserts: vec![insert],
+                    ..Default::default()
+                }],
+                tempo_bpm: 240f32,
+                ..Default::default()
+            };
+            let mut out = Vec::new();
+            OfflineRenderer::new(PlaybackBuilder::new(&mixer, config()).unwrap())
+                .render_to(&mut out)
+                .unwrap();
+            out
+        };
+
+        // A beat at 240 bpm is 250 ms, and a single repeat
+        let out = render(InsertModel::Delay(DelaySettings {
+            time: DelayTime::Beats(1f32),
+            feedback: 0f32,
+            ..Default::default()
+        }));
+        assert_eq!(out.len(), (22050 + 11025) * 2);
+        assert!(out[44100..].iter().any(|s| s.abs() > 1e-3));
+
+        let out = render(InsertModel::Reverb(ReverbSettings {
+            pre_delay_ms: 10f32,
+            decay_ms: 1000f32,
+            ..Default::default()
+        }));
+        assert_eq!(out.len(), (22050 + 441 + 44100) * 2);
+        let (ringing, end) = out[44100..].split_at(22050);
+        assert!(ringing.iter().any(|s| s.abs() > 1e-3));
+        assert!(end[end.len() - 2000..].iter().all(|s| s.abs() < 1e-3));
+    }
+
+    #[test]
+    fn reverb_on_an_aux_bus_rings_out() {
+        let source = source();
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                id: "chan-1".to_string(),
+                clips: vec![clip(0, 500)],
+                sends: vec![SendModel {
+                    bus: "reverb".to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            buses: vec![BusModel {
+                id: "reverb".to_string(),
+                inserts: vec![InsertModel::Reverb(ReverbSettings {
+                    pre_delay_ms: 10f32,
+                    decay_ms: 1000f32,
+                    ..Default::default()
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+
+        // Only the dry channel until the pre-delay is up
+        let mut block = vec![0f32; 800];
+        playback.render(&mut block);
+        for (frame, sample) in block.chunks(2).zip(source.iter()) {
+            assert_eq!(frame[0], *sample);
+        }
+
+        // The return keeps going for as long as the reverb's tail
+        let mut out = Vec::new();
+        OfflineRenderer::new(PlaybackBuilder::new(&mixer, config()).unwrap())
+            .render_to(&mut out)
+            .unwrap();
+        assert_eq!(out.len(), (22050 + 441 + 44100) * 2);
+        assert!(out[44100..66150].iter().any(|s| s.abs() > 1e-3));
+    }
+
+    #[test]
+    fn buses_are_delayed_to_line_up() {
+        let source = source();
+        let dry = ChannelModel {
+            id: "dry".to_string(),
+            clips: vec![clip(0, 1000)],
+            ..Default::default()
+        };
+        // Through a bus with latency, so the dry channel waits for it
+        let wet = ChannelModel {
+            id: "wet".to_string(),
+            output: Some("fx".to_string()),
+            ..dry.clone()
+        };
+        let mixer = MixerModel {
+            channels: vec![dry, wet],
+            buses: vec![BusModel {
+                id: "fx".to_string(),
+                inserts: vec![InsertModel::Custom(ProcessorFactory::new(|| {
+                    Box::new(Delay {
+                        frames: 100,
+                        line: Default::default(),
+                    })
+                }))],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+        assert_eq!(playback.bus_latency(), 100);
+
+        let mut out = vec![0f32; 30000];
+        playback.render(&mut out);
+        assert!(out[..200].iter().all(|s| *s == 0f32));
+        for i in 0..14900 {
+            assert!((out[(i + 100) * 2] - source[i] * 2f32).abs() < 1e-6);
+        }
+
+        let mut out = Vec::new();
+        OfflineRenderer::new(PlaybackBuilder::new(&mixer, config()).unwrap())
+            .render_to(&mut out)
+            .unwrap();
+        assert_eq!(out.len(), (44100 + 100) * 2);
+    }
+
+    #[test]
+    fn bus_inserts_have_params_and_meters() {
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                clips: vec![clip(0, 1000)],
+                output: Some("comp".to_string()),
+                ..Default::default()
+            }],
+            buses: vec![BusModel {
+                id: "comp".to_string(),
+                inserts: vec![InsertModel::Compressor(CompressorSettings {
+                    threshold_db: -40f32,
+                    ratio: 20f32,
+                    ..Default::default()
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+        let reduction = playback.meters().bus_gain_reduction[0][0].clone().unwrap();
+        let threshold = playback.params().find_bus(0, 0, "Threshold").cloned();
+        assert_eq!(threshold.unwrap().get(), -40f32);
+        assert!(playback.params().find_bus(0, 1, "Threshold").is_none());
+
+        let mut out = vec![0f32; 20000];
+        playback.render(&mut out);
+        assert!(reduction.db() > 6f32);
+    }
+
+    /// Writes an impulse response `frames` long with a click of `level` at the
+    /// start of each channel.
+    fn write_ir(name: &str, levels: &[f32], frames: usize) -> String {
+        let path = std::env::temp_dir().join(name);
+        let spec = hound::WavSpec {
+            channels: levels.len() as u16,
+            sample_rate: 44100,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for frame in 0..frames {
+            for level in levels {
+                writer
+                    .write_sample(if frame == 0 { *level } else { 0f32 })
+                    .unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn convolution_loads_its_impulse_response() {
+        let source = source();
+        let convolution = |path: String| MixerModel {
+            channels: vec![ChannelModel {
+                clips: vec![clip(0, 200)],
+                inserts: vec![InsertModel::Convolution(ConvolutionSettings {
+                    path,
+                    ..Default::default()
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
 
-        // while let Ok(mixed_sample) = mixer_rx.recv() {
-        //     // println!("Mixed: {}", mixed_sample * 10000000f32);
-        //     println!("Mixed: {}", mixed_sample);
-        // }
+        // True stereo, left to left and right to right at half
+        let path = write_ir("builder_true_stereo.wav", &[0.5, 0f32, 0f32, 0.5], 10);
+        let playback = PlaybackBuilder::new(&convolution(path), config()).unwrap();
+        let mut out = Vec::new();
+        OfflineRenderer::new(playback).render_to(&mut out).unwrap();
 
-        for t in thread_handlers {
-            t.join().unwrap();
+        // Late by a partition and ringing on for the impulse response
+        assert_eq!(out.len(), (8820 + 128 + 10) * 2);
+        for i in 0..8820 {
+            assert!((out[(i + 128) * 2] - source[i] * 0.5).abs() < 1e-4);
+            assert!((out[(i + 128) * 2 + 1] - source[i] * 0.5).abs() < 1e-4);
         }
 
-        mixer_thread.join().unwrap();
+        let path = write_ir("builder_three_channels.wav", &[1f32; 3], 10);
+        let result = PlaybackBuilder::new(&convolution(path), config());
+        assert!(matches!(result, Err(Error::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn insert_params_change_while_playing() {
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                clips: vec![clip(0, 1000)],
+                inserts: vec![InsertModel::Gain(0f32)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+        let gain = playback.params().find(0, 0, "Gain").unwrap().clone();
+        assert_eq!(gain.get(), 0f32);
+
+        let source = source();
+        let mut out = vec![0f32; 2000];
+        playback.render(&mut out);
+        assert_eq!(out[998], source[499]);
+
+        // Ramps down over 20 ms rather than jumping
+        gain.set(-96f32);
+        playback.render(&mut out);
+        assert!(out[0].abs() > (source[1000] * db_to_gain(-96f32)).abs());
+        playback.render(&mut out);
+        assert_eq!(out[998], source[2499] * db_to_gain(-96f32));
+    }
+
+    fn step_at_quarter_second(db: f32) -> AutomationLane {
+        AutomationLane {
+            breakpoints: vec![
+                Breakpoint {
+                    time_ms: 0f64,
+                    value: 0f32,
+                    curve: Curve::Step,
+                },
+                Breakpoint {
+                    time_ms: 250f64,
+                    value: db,
+                    curve: Curve::Step,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn volume_automation_is_sample_accurate() {
+        let source = source();
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                clips: vec![clip(0, 1000)],
+                volume_automation: step_at_quarter_second(-20f32),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+
+        let mut out = vec![0f32; 30000];
+        playback.render(&mut out);
+        let gain = db_to_gain(-20f32);
+        assert_eq!(out[11024 * 2], source[11024]);
+        assert_eq!(out[11025 * 2], source[11025] * gain);
+        assert_eq!(playback.mixer().strips()[0].volume_db, -20f32);
+
+        // Back before the step after a seek
+        playback.seek(100);
+        let mut out = vec![0f32; 2000];
+        playback.render(&mut out);
+        assert_eq!(out[0], source[100]);
+        assert_eq!(playback.mixer().strips()[0].volume_db, 0f32);
+    }
+
+    #[test]
+    fn volume_automation_follows_latent_inserts() {
+        let source = source();
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                clips: vec![clip(0, 1000)],
+                inserts: vec![InsertModel::Custom(ProcessorFactory::new(|| {
+                    Box::new(Delay {
+                        frames: 100,
+                        line: Default::default(),
+                    })
+                }))],
+                volume_automation: step_at_quarter_second(-20f32),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+
+        // The step lands on the audio it was drawn against, 100 frames late
+        let mut out = vec![0f32; 30000];
+        playback.render(&mut out);
+        let gain = db_to_gain(-20f32);
+        assert_eq!(out[11124 * 2], source[11024]);
+        assert_eq!(out[11125 * 2], source[11025] * gain);
+
+        // And from further along the timeline
+        playback.seek(10000);
+        playback.render(&mut out);
+        assert_eq!(out[1124 * 2], source[11024]);
+        assert_eq!(out[1125 * 2], source[11025] * gain);
+    }
+
+    #[test]
+    fn param_automation_follows_the_timeline() {
+        let source = source();
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                clips: vec![clip(0, 1000)],
+                inserts: vec![InsertModel::Gain(0f32)],
+                param_automation: vec![ParamAutomation {
+                    insert: 0,
+                    param: "Gain".to_string(),
+                    lane: step_at_quarter_second(-20f32),
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+        let gain = db_to_gain(-20f32);
+
+        // Untouched up to the step, then straight down to the new level
+        let mut out = vec![0f32; 30000];
+        playback.render(&mut out);
+        assert_eq!(out[11024 * 2], source[11024]);
+        assert_eq!(out[11025 * 2], source[11025] * gain);
+
+        // Seeking jumps straight to the value there
+        playback.seek(12000);
+        playback.render(&mut out);
+        assert_eq!(out[0], source[12000] * gain);
+
+        let unknown = MixerModel {
+            channels: vec![ChannelModel {
+                inserts: vec![InsertModel::Gain(0f32)],
+                param_automation: vec![ParamAutomation {
+                    insert: 0,
+                    param: "Cutoff".to_string(),
+                    lane: step_at_quarter_second(1f32),
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let result = PlaybackBuilder::new(&unknown, config());
+        assert!(matches!(result, Err(Error::UnknownParam(name)) if name == "Cutoff"));
+    }
+
+    #[test]
+    fn param_automation_ramps_between_breakpoints() {
+        let source = source();
+        let lane = AutomationLane {
+            breakpoints: vec![
+                Breakpoint {
+                    time_ms: 0f64,
+                    value: 0f32,
+                    curve: Curve::Linear,
+                },
+                Breakpoint {
+                    time_ms: 1000f64,
+                    value: -20f32,
+                    curve: Curve::Linear,
+                },
+            ],
+        };
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                clips: vec![clip(0, 1000)],
+                inserts: vec![InsertModel::Gain(0f32)],
+                param_automation: vec![ParamAutomation {
+                    insert: 0,
+                    param: "Gain".to_string(),
+                    lane,
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+
+        let mut out = vec![0f32; 20000 * 2];
+        playback.render(&mut out);
+
+        // The gain falls a little every frame rather than once every part
+        let mut last = f32::MAX;
+        for frame in (1..20000).filter(|&frame| source[frame].abs() > 0.05) {
+            let gain = out[frame * 2] / source[frame];
+            let expected = db_to_gain(-20f32 * frame as f32 / 44100f32);
+            assert!((gain - expected).abs() < 1e-3, "frame {}", frame);
+            assert!(gain < last, "frame {}", frame);
+            last = gain;
+        }
+    }
+
+    #[test]
+    fn sidechain_comes_from_another_channel() {
+        let source = source();
+        let ducked = ChannelModel {
+            id: "ducked".to_string(),
+            clips: vec![clip(0, 1000)],
+            inserts: vec![InsertModel::Compressor(CompressorSettings {
+                threshold_db: -40f32,
+                ratio: 20f32,
+                sidechain: Some("key".to_string()),
+                ..Default::default()
+            })],
+            ..Default::default()
+        };
+        // Only heard through the compressor's sidechain
+        let key = ChannelModel {
+            id: "key".to_string(),
+            clips: vec![clip(0, 1000)],
+            mute: true,
+            ..Default::default()
+        };
+        let mixer = MixerModel {
+            channels: vec![ducked.clone(), key],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+        let reduction = playback.meters().gain_reduction[0][0].clone().unwrap();
+
+        let mut out = vec![0f32; 20000];
+        playback.render(&mut out);
+        let peak = |samples: &[f32]| samples.iter().fold(0f32, |p, s| p.max(s.abs()));
+        assert!(peak(&out[10000..]) < peak(&source[5000..10000]) / 2f32);
+        assert!(reduction.db() > 6f32);
+        assert!(playback.meters().gain_reduction[1].is_empty());
+
+        let unknown = MixerModel {
+            channels: vec![ducked],
+            ..Default::default()
+        };
+        let result = PlaybackBuilder::new(&unknown, config());
+        assert!(matches!(result, Err(Error::UnknownChannel(id)) if id == "key"));
+    }
+
+    #[test]
+    fn channel_strip_is_applied() {
+        let source = source();
+        let mut playback = playback(vec![clip(0, 1000)]);
+        let strip = playback.mixer_mut().strip_mut(0);
+        strip.volume_db = -6f32;
+        strip.pan = 1f32;
+
+        let mut out = vec![0f32; 2000];
+        playback.render(&mut out);
+
+        let (left, right) = PanLaw::default().gains(1f32);
+        let gain = crate::mixer::db_to_gain(-6f32);
+        for (frame, sample) in out.chunks(2).zip(source.iter()) {
+            assert_eq!(frame[0], sample * left * gain);
+            assert!((frame[1] - sample * right * gain).abs() < 1e-6);
+        }
+
+        playback.mixer_mut().strip_mut(0).mute = true;
+        playback.render(&mut out);
+        assert!(out.iter().all(|s| *s == 0f32));
+    }
+
+    #[test]
+    fn group_bus_feeds_the_master() {
+        let source = source();
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                id: "chan-1".to_string(),
+                clips: vec![clip(0, 100)],
+                output: Some("group".to_string()),
+                ..Default::default()
+            }],
+            buses: vec![BusModel {
+                id: "group".to_string(),
+                volume_db: -6f32,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+
+        let mut out = vec![0f32; 200];
+        playback.render(&mut out);
+
+        let gain = crate::mixer::db_to_gain(-6f32);
+        for (frame, sample) in out.chunks(2).zip(source.iter()) {
+            assert!((frame[0] - sample * gain).abs() < 1e-6);
+        }
+
+        let looped = MixerModel {
+            buses: vec![BusModel {
+                id: "group".to_string(),
+                output: Some("group".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(matches!(
+            PlaybackBuilder::new(&looped, config()),
+            Err(crate::error::Error::RoutingCycle(_))
+        ));
+    }
+
+    #[test]
+    fn master_latency_is_taken_off_offline_renders() {
+        let mut playback = playback(vec![clip(0, 1000)]);
+        playback
+            .master_mut()
+            .set_protection(Protection::Limiter(Default::default()));
+        assert!(playback.master().latency() > 0);
+
+        let mut master = Vec::new();
+        let mut stems = vec![Vec::new()];
+        OfflineRenderer::new(playback)
+            .render_stems_to(&mut master, &mut stems)
+            .unwrap();
+
+        // The limited mix lines up with the channel before the master bus,
+        // which nothing here is loud enough to turn down
+        assert_eq!(master.len(), 44100 * 2);
+        assert_eq!(stems[0].len(), master.len());
+        for (limited, unlimited) in master.iter().zip(stems[0].iter()) {
+            assert!((limited - unlimited).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn clip_starts_at_its_position() {
+        let source = source();
+        let out = render(vec![clip(500, 1000)]);
+
+        assert_eq!(out.len(), 22050 + 44100);
+        assert!(out[..22050].iter().all(|s| *s == 0f32));
+        assert_eq!(out[22050..], source[..44100]);
+    }
+
+    #[test]
+    fn negative_start_cuts_the_head() {
+        let source = source();
+        let out = render(vec![clip(-250, 500)]);
+
+        assert_eq!(out.len(), 11025);
+        assert_eq!(out[..], source[11025..22050]);
+    }
+
+    #[test]
+    fn clips_play_in_sequence() {
+        let source = source();
+        let out = render(vec![clip(200, 100), clip(0, 100)]);
+
+        assert_eq!(out.len(), 13230);
+        assert_eq!(out[..4410], source[..4410]);
+        assert!(out[4410..8820].iter().all(|s| *s == 0f32));
+        assert_eq!(out[8820..], source[..4410]);
+    }
+
+    #[test]
+    fn source_offset_trims_the_start() {
+        let source = source();
+        let out = render(vec![ClipModel {
+            source_offset_ms: 250,
+            ..clip(100, 500)
+        }]);
+
+        assert_eq!(out.len(), 4410 + 22050);
+        assert_eq!(out[4410..], source[11025..33075]);
+    }
+
+    #[test]
+    fn source_offset_and_negative_start_add_up() {
+        let source = source();
+        let out = render(vec![ClipModel {
+            source_offset_ms: 250,
+            ..clip(-100, 500)
+        }]);
+
+        assert_eq!(out.len(), 22050 - 4410);
+        assert_eq!(out[..], source[11025 + 4410..33075]);
+    }
+
+    #[test]
+    fn clip_loops_a_region() {
+        let source = source();
+        let out = render(vec![ClipModel {
+            loop_start_ms: Some(100),
+            loop_end_ms: Some(200),
+            ..clip(0, 500)
+        }]);
+
+        assert_eq!(out.len(), 22050);
+        // Plays up to the end of the loop, then repeats the region
+        assert_eq!(out[..8820], source[..8820]);
+        assert_eq!(out[8820..13230], source[4410..8820]);
+        assert_eq!(out[13230..17640], source[4410..8820]);
+        assert_eq!(out[17640..], source[4410..8820]);
+    }
+
+    #[test]
+    fn looping_clip_with_head_cut() {
+        let source = source();
+        let out = render(vec![ClipModel {
+            source_offset_ms: 50,
+            loop_start_ms: Some(100),
+            loop_end_ms: Some(200),
+            ..clip(-200, 300)
+        }]);
+
+        // 200ms into the clip is 250ms into the file, which has looped back
+        // to 150ms
+        assert_eq!(out.len(), 4410);
+        assert_eq!(out[..2205], source[6615..8820]);
+        assert_eq!(out[2205..], source[4410..6615]);
+    }
+
+    #[test]
+    fn clip_longer_than_source_is_padded_with_silence() {
+        let source = source();
+        let out = render(vec![clip(0, 3000)]);
+
+        assert_eq!(out.len(), 132300);
+        assert_eq!(out[..source.len()], source[..]);
+        assert!(out[source.len()..].iter().all(|s| *s == 0f32));
+    }
+
+    #[test]
+    fn seek_moves_the_playhead() {
+        let source = source();
+        let mut playback = playback(vec![clip(500, 1000)]);
+        let mut block = vec![0f32; 2000];
+        let left = |block: &[f32]| block.chunks(2).map(|f| f[0]).collect::<Vec<f32>>();
+
+        playback.seek(33075);
+        playback.render(&mut block);
+        assert_eq!(left(&block)[..], source[11025..12025]);
+        assert_eq!(playback.position(), 34075);
+
+        // Back before the clip starts
+        playback.seek(0);
+        playback.render(&mut block);
+        assert!(block.iter().all(|s| *s == 0f32));
+
+        playback.seek(22050);
+        playback.render(&mut block);
+        assert_eq!(left(&block)[..], source[..1000]);
     }
 }
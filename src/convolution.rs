@@ -0,0 +1,481 @@
+use std::sync::Arc;
+
+use cpal::StreamConfig;
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::{
+    error::{Error, Result},
+    mixer::{db_to_gain, Processor},
+    param::{Param, ParamHandle, ParamInfo, Smoothing, Unit},
+    source::Source,
+    source_reader::SourceReader,
+    symph::Symphonia,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConvolutionSettings {
+    /// The impulse response: mono, stereo (one per side), or true stereo
+    /// (left to left, left to right, right to left, right to right)
+    pub path: String,
+    /// How much of the output is the convolved signal rather than the input,
+    /// from 0 to 1. Turn it down when it is a channel insert rather than on
+    /// an aux.
+    pub mix: f32,
+    /// Level of the convolved signal in dB
+    pub gain_db: f32,
+    /// Frames the input is taken in, which is also the latency. Rounded up to
+    /// a power of two.
+    pub partition_frames: usize,
+}
+
+impl Default for ConvolutionSettings {
+    fn default() -> Self {
+        ConvolutionSettings {
+            path: String::new(),
+            mix: 1f32,
+            gain_db: 0f32,
+            partition_frames: 128,
+        }
+    }
+}
+
+/// An impulse response at the sample rate it is played at.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImpulseResponse {
+    /// One `Vec` per channel, all the same length
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl ImpulseResponse {
+    /// Decodes the file at `path` and resamples it to `sample_rate`.
+    pub fn load(path: &str, sample_rate: u32) -> Result<Self> {
+        let source = Symphonia::new(path.to_string())?;
+        let channel_count = source.channels();
+
+        if ![1, 2, 4].contains(&channel_count) {
+            return Err(Error::UnsupportedFormat(format!(
+                "{} has {} channels but an impulse response needs 1, 2 or 4",
+                path, channel_count
+            )));
+        }
+
+        let config = StreamConfig {
+            channels: channel_count as u16,
+            sample_rate: cpal::SampleRate(sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let mut reader = SourceReader::new(source, config)?;
+
+        let mut channels = vec![Vec::new(); channel_count];
+        let mut channel = 0;
+        while let Some(sample) = reader.next() {
+            channels[channel].push(sample);
+            channel = (channel + 1) % channel_count;
+        }
+
+        Ok(ImpulseResponse { channels })
+    }
+
+    /// Length in frames.
+    pub fn frames(&self) -> usize {
+        self.channels.first().map_or(0, |channel| channel.len())
+    }
+}
+
+/// Input channel convolved with an impulse response channel into an output
+/// channel.
+#[derive(Clone, Copy, Debug)]
+struct Path {
+    input: usize,
+    output: usize,
+    ir: usize,
+}
+
+/// Convolves its input with an impulse response by uniformly partitioned
+/// overlap-save. The impulse response is cut into partitions as long as the
+/// blocks the input is taken in, so the latency is one partition however long
+/// the impulse response is.
+pub struct Convolver {
+    mix: Param,
+    gain_db: Param,
+    partition: usize,
+    ir_frames: usize,
+    // Per impulse response channel, per partition, its spectrum
+    ir: Vec<Vec<Vec<Complex<f32>>>>,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    channel_count: usize,
+    paths: Vec<Path>,
+    // Per channel, the last block of input and then the one being filled
+    windows: Vec<Vec<f32>>,
+    // Per channel, spectra of the input for the last as many blocks as there
+    // are partitions, with `newest` the latest
+    history: Vec<Vec<Vec<Complex<f32>>>>,
+    newest: usize,
+    // Per channel, what came out of the last block
+    outputs: Vec<Vec<f32>>,
+    // How far into the block the input has been filled
+    position: usize,
+    fft_input: Vec<f32>,
+    fft_output: Vec<f32>,
+    sum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Convolver {
+    pub fn new(ir: &ImpulseResponse, settings: &ConvolutionSettings) -> Self {
+        let param = |name: &str, unit, min, max, value| {
+            let info = ParamInfo {
+                name: name.to_string(),
+                unit,
+                min,
+                max,
+                default: value,
+                smoothing: Smoothing::default(),
+            };
+            Param::with_value(info, value)
+        };
+
+        let partition = settings.partition_frames.max(1).next_power_of_two();
+        let fft_size = partition * 2;
+        let mut planner = RealFftPlanner::<f32>::new();
+        let forward = planner.plan_fft_forward(fft_size);
+        let inverse = planner.plan_fft_inverse(fft_size);
+
+        // Scaled here so the inverse FFT doesn't need to be
+        let scale = 1f32 / fft_size as f32;
+        let mut input = forward.make_input_vec();
+        let mut scratch = forward.make_scratch_vec();
+        let ir_spectra = ir
+            .channels
+            .iter()
+            .map(|channel| {
+                channel
+                    .chunks(partition)
+                    .map(|chunk| {
+                        input.fill(0f32);
+                        for (sample, ir) in input.iter_mut().zip(chunk) {
+                            *sample = ir * scale;
+                        }
+
+                        let mut spectrum = forward.make_output_vec();
+                        forward
+                            .process_with_scratch(&mut input, &mut spectrum, &mut scratch)
+                            .expect("FFT buffers are made to fit");
+                        spectrum
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Convolver {
+            mix: param("Mix", Unit::None, 0f32, 1f32, settings.mix),
+            gain_db: param("Gain", Unit::Decibels, -48f32, 12f32, settings.gain_db),
+            partition,
+            ir_frames: ir.frames(),
+            ir: ir_spectra,
+            fft_input: forward.make_input_vec(),
+            fft_output: inverse.make_output_vec(),
+            sum: inverse.make_input_vec(),
+            scratch: forward
+                .make_scratch_vec()
+                .into_iter()
+                .chain(inverse.make_scratch_vec())
+                .collect(),
+            forward,
+            inverse,
+            channel_count: 1,
+            paths: Vec::new(),
+            windows: Vec::new(),
+            history: Vec::new(),
+            newest: 0,
+            outputs: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Which impulse response channel takes each channel where. Mono plays
+    /// every channel through it, stereo gives the left and right channels one
+    /// each, and true stereo feeds each side into both.
+    fn paths(ir_channels: usize, channel_count: usize) -> Vec<Path> {
+        let mut paths = Vec::new();
+
+        for channel in 0..channel_count {
+            let side = channel % 2;
+            let pair = channel - side;
+
+            match ir_channels {
+                // Both sides of a pair, when there is a pair
+                4 if pair + 1 < channel_count => {
+                    for output in 0..2 {
+                        paths.push(Path {
+                            input: channel,
+                            output: pair + output,
+                            ir: side * 2 + output,
+                        });
+                    }
+                }
+                1 => paths.push(Path {
+                    input: channel,
+                    output: channel,
+                    ir: 0,
+                }),
+                _ => paths.push(Path {
+                    input: channel,
+                    output: channel,
+                    ir: side * (ir_channels / 2),
+                }),
+            }
+        }
+
+        paths
+    }
+
+    fn partitions(&self) -> usize {
+        self.ir.first().map_or(0, |ir| ir.len())
+    }
+
+    /// Convolves the block of input just filled.
+    fn run_block(&mut self) {
+        let partition = self.partition;
+        let partitions = self.partitions();
+        if partitions == 0 {
+            return;
+        }
+
+        self.newest = (self.newest + 1) % partitions;
+        let scratch_len = self.forward.get_scratch_len();
+
+        for (window, history) in self.windows.iter_mut().zip(self.history.iter_mut()) {
+            self.fft_input.copy_from_slice(window);
+            self.forward
+                .process_with_scratch(
+                    &mut self.fft_input,
+                    &mut history[self.newest],
+                    &mut self.scratch[..scratch_len],
+                )
+                .expect("FFT buffers are made to fit");
+
+            // This block is the last one for next time
+            window.copy_within(partition.., 0);
+        }
+
+        let scratch_len = self.inverse.get_scratch_len();
+        for (channel, output) in self.outputs.iter_mut().enumerate() {
+            self.sum.fill(Complex::default());
+
+            for path in self.paths.iter().filter(|path| path.output == channel) {
+                let history = &self.history[path.input];
+
+                for (index, ir) in self.ir[path.ir].iter().enumerate() {
+                    let input = &history[(self.newest + partitions - index) % partitions];
+
+                    for ((sum, x), h) in self.sum.iter_mut().zip(input).zip(ir) {
+                        *sum += x * h;
+                    }
+                }
+            }
+
+            self.inverse
+                .process_with_scratch(
+                    &mut self.sum,
+                    &mut self.fft_output,
+                    &mut self.scratch[..scratch_len],
+                )
+                .expect("FFT buffers are made to fit");
+
+            // The first half wrapped round, so only the second is kept
+            output.copy_from_slice(&self.fft_output[partition..]);
+        }
+    }
+}
+
+impl Processor for Convolver {
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize, _max_block_frames: usize) {
+        self.channel_count = channel_count;
+        self.mix.prepare(sample_rate);
+        self.gain_db.prepare(sample_rate);
+
+        let bins = self.partition + 1;
+        self.paths = Self::paths(self.ir.len(), channel_count);
+        self.windows = vec![vec![0f32; self.partition * 2]; channel_count];
+        self.history = vec![vec![vec![Complex::default(); bins]; self.partitions()]; channel_count];
+        self.outputs = vec![vec![0f32; self.partition]; channel_count];
+        self.newest = 0;
+        self.position = 0;
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        let partition = self.partition;
+
+        for frame in samples.chunks_exact_mut(self.channel_count) {
+            let mix = self.mix.next_value();
+            let gain = db_to_gain(self.gain_db.next_value());
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                // The dry signal is held back as long as the convolved one
+                let dry = self.windows[channel][self.position];
+                self.windows[channel][partition + self.position] = *sample;
+
+                let wet = self.outputs[channel][self.position] * gain;
+                *sample = dry + (wet - dry) * mix;
+            }
+
+            self.position += 1;
+            if self.position == partition {
+                self.run_block();
+                self.position = 0;
+            }
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.partition
+    }
+
+    fn tail(&self) -> usize {
+        self.ir_frames
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        vec![self.mix.handle(), self.gain_db.handle()]
+    }
+
+    fn reset(&mut self) {
+        self.mix.reset();
+        self.gain_db.reset();
+
+        for window in self.windows.iter_mut() {
+            window.fill(0f32);
+        }
+        for spectrum in self.history.iter_mut().flatten() {
+            spectrum.fill(Complex::default());
+        }
+        for output in self.outputs.iter_mut() {
+            output.fill(0f32);
+        }
+        self.newest = 0;
+        self.position = 0;
+    }
+}
+
+#[cfg(test)]
+mod convolution_tests {
+    use crate::convolution::*;
+
+    const PATH: &str = "sounds/sample-2.wav";
+
+    /// Something that isn't silent or regular, in -1 to 1
+    fn noise(length: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 8) as f32 / (1 << 23) as f32 - 1f32
+            })
+            .collect()
+    }
+
+    fn convolver(channels: Vec<Vec<f32>>, channel_count: usize) -> Convolver {
+        let mut convolver = Convolver::new(
+            &ImpulseResponse { channels },
+            &ConvolutionSettings {
+                partition_frames: 64,
+                ..Default::default()
+            },
+        );
+        convolver.prepare(44100, channel_count, 512);
+        convolver
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        let ir = noise(300, 1);
+        let input = noise(1000, 2);
+        let mut convolver = convolver(vec![ir.clone()], 1);
+
+        let mut samples = input.clone();
+        samples.resize(1000 + 64 + 300, 0f32);
+        for block in samples.chunks_mut(100) {
+            convolver.process(block);
+        }
+
+        // One partition late
+        assert!(samples[..64].iter().all(|s| *s == 0f32));
+        for (frame, sample) in samples[64..].iter().enumerate() {
+            let expected: f32 = (0..ir.len())
+                .filter(|i| *i <= frame && frame - i < input.len())
+                .map(|i| ir[i] * input[frame - i])
+                .sum();
+            assert!((sample - expected).abs() < 1e-3, "frame {}", frame);
+        }
+
+        assert_eq!(convolver.latency(), 64);
+        assert_eq!(convolver.tail(), 300);
+    }
+
+    #[test]
+    fn true_stereo_feeds_both_sides() {
+        // Each path is a single click at a different time
+        let click = |at: usize| {
+            let mut ir = vec![0f32; 200];
+            ir[at] = 1f32;
+            ir
+        };
+        let mut convolver = convolver(vec![click(10), click(20), click(30), click(40)], 2);
+
+        // Only the left has anything in
+        let mut samples = vec![0f32; 1000];
+        samples[0] = 1f32;
+        convolver.process(&mut samples);
+
+        let at = |frame: usize| (samples[frame * 2], samples[frame * 2 + 1]);
+        let (left, right) = at(64 + 10);
+        assert!((left - 1f32).abs() < 1e-4 && right.abs() < 1e-4);
+        let (left, right) = at(64 + 20);
+        assert!(left.abs() < 1e-4 && (right - 1f32).abs() < 1e-4);
+        assert!(samples.iter().filter(|s| s.abs() > 1e-4).count() == 2);
+    }
+
+    #[test]
+    fn stereo_keeps_sides_apart_and_mix_keeps_dry() {
+        let mut convolver = Convolver::new(
+            &ImpulseResponse {
+                channels: vec![vec![0.5], vec![0.25]],
+            },
+            &ConvolutionSettings {
+                partition_frames: 16,
+                mix: 0.5,
+                ..Default::default()
+            },
+        );
+        convolver.prepare(44100, 2, 512);
+
+        let mut samples = vec![1f32; 100];
+        convolver.process(&mut samples);
+
+        // Half of the dry 1 and half of the convolved
+        assert!((samples[16 * 2] - 0.75).abs() < 1e-5);
+        assert!((samples[16 * 2 + 1] - 0.625).abs() < 1e-5);
+    }
+
+    #[test]
+    fn loads_and_resamples() {
+        let source: Vec<f32> = Symphonia::new(PATH.to_string()).unwrap().collect();
+
+        let ir = ImpulseResponse::load(PATH, 44100).unwrap();
+        assert_eq!(ir.channels, [source.clone()]);
+
+        let ir = ImpulseResponse::load(PATH, 88200).unwrap();
+        assert_eq!(ir.channels.len(), 1);
+        assert!(ir.frames() >= source.len() * 2);
+
+        let ir = ImpulseResponse::load("sounds/sample-1.wav", 44100).unwrap();
+        assert_eq!(ir.channels.len(), 2);
+
+        assert!(matches!(
+            ImpulseResponse::load("sounds/missing.wav", 44100),
+            Err(Error::FileNotFound(_))
+        ));
+    }
+}
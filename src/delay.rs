@@ -0,0 +1,380 @@
+use std::f32::consts::PI;
+
+use crate::{
+    mixer::Processor,
+    param::{Param, ParamHandle, ParamInfo, Smoothing, Unit},
+};
+
+/// Longest delay time, which is what the delay lines are sized for
+const MAX_DELAY_MS: f32 = 4000f32;
+
+/// Feedback is taken to have died away once it is this far down
+const SILENCE_DB: f32 = -60f32;
+
+/// How long the delay is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DelayTime {
+    Ms(f32),
+    /// Synced to the tempo, e.g. 0.75 for a dotted eighth
+    Beats(f32),
+}
+
+impl DelayTime {
+    pub fn ms(&self, tempo_bpm: f32) -> f32 {
+        match self {
+            DelayTime::Ms(ms) => *ms,
+            DelayTime::Beats(beats) => beats * 60000f32 / tempo_bpm,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DelaySettings {
+    pub time: DelayTime,
+    /// How much of each repeat comes back round, from 0 to 0.95
+    pub feedback: f32,
+    /// Repeats bounce between left and right. Only for stereo.
+    pub ping_pong: bool,
+    /// Each repeat loses everything under this
+    pub low_cut: f32,
+    /// Each repeat loses everything over this
+    pub high_cut: f32,
+    /// How much of the output is the repeats rather than the input, from 0
+    /// to 1. On a bus that channels send to, leave it at 1 so the dry signal
+    /// isn't heard twice.
+    pub mix: f32,
+}
+
+impl Default for DelaySettings {
+    fn default() -> Self {
+        DelaySettings {
+            time: DelayTime::Beats(0.5),
+            feedback: 0.4,
+            ping_pong: false,
+            low_cut: 100f32,
+            high_cut: 8000f32,
+            mix: 1f32,
+        }
+    }
+}
+
+/// A one pole low-pass, also used for a high-pass by taking it away from its
+/// input.
+#[derive(Clone, Copy, Debug, Default)]
+struct OnePole {
+    state: f32,
+}
+
+impl OnePole {
+    /// How far the filter moves towards its input each sample for a cutoff of
+    /// `frequency`.
+    fn coefficient(frequency: f32, sample_rate: u32) -> f32 {
+        1f32 - (-2f32 * PI * frequency / sample_rate as f32).exp()
+    }
+
+    #[inline]
+    fn low_pass(&mut self, sample: f32, coefficient: f32) -> f32 {
+        self.state += coefficient * (sample - self.state);
+        self.state
+    }
+}
+
+struct Line {
+    buffer: Vec<f32>,
+    low_cut: OnePole,
+    high_cut: OnePole,
+}
+
+/// A delay with feedback, filtered each time round. The time can be synced to
+/// the tempo when it is made and changed through its parameters after.
+pub struct Delay {
+    time_ms: Param,
+    feedback: Param,
+    low_cut: Param,
+    high_cut: Param,
+    mix: Param,
+    ping_pong: bool,
+    sample_rate: u32,
+    channel_count: usize,
+    // One per channel, all written at the same place
+    lines: Vec<Line>,
+    write: usize,
+}
+
+impl Delay {
+    pub fn new(settings: &DelaySettings, tempo_bpm: f32) -> Self {
+        let param = |name: &str, unit, min, max, value, smoothing| {
+            let info = ParamInfo {
+                name: name.to_string(),
+                unit,
+                min,
+                max,
+                default: value,
+                smoothing,
+            };
+            Param::with_value(info, value)
+        };
+
+        Delay {
+            time_ms: param(
+                "Time",
+                Unit::Milliseconds,
+                1f32,
+                MAX_DELAY_MS,
+                settings.time.ms(tempo_bpm),
+                Smoothing::Linear(100f32),
+            ),
+            feedback: param(
+                "Feedback",
+                Unit::None,
+                0f32,
+                0.95,
+                settings.feedback,
+                Smoothing::default(),
+            ),
+            low_cut: param(
+                "Low Cut",
+                Unit::Hertz,
+                10f32,
+                2000f32,
+                settings.low_cut,
+                Smoothing::None,
+            ),
+            high_cut: param(
+                "High Cut",
+                Unit::Hertz,
+                1000f32,
+                22000f32,
+                settings.high_cut,
+                Smoothing::None,
+            ),
+            mix: param(
+                "Mix",
+                Unit::None,
+                0f32,
+                1f32,
+                settings.mix,
+                Smoothing::default(),
+            ),
+            ping_pong: settings.ping_pong,
+            sample_rate: 44100,
+            channel_count: 1,
+            lines: Vec::new(),
+            write: 0,
+        }
+    }
+
+    fn params_mut(&mut self) -> [&mut Param; 5] {
+        [
+            &mut self.time_ms,
+            &mut self.feedback,
+            &mut self.low_cut,
+            &mut self.high_cut,
+            &mut self.mix,
+        ]
+    }
+
+    /// Reads `delay` frames back from the write position of `buffer`, between
+    /// samples if it has to.
+    #[inline]
+    fn read(buffer: &[f32], write: usize, delay: f32) -> f32 {
+        let length = buffer.len();
+        let position = write as f32 + length as f32 - delay;
+        let index = position as usize;
+        let fraction = position - index as f32;
+
+        let a = buffer[index % length];
+        let b = buffer[(index + 1) % length];
+        a + (b - a) * fraction
+    }
+}
+
+impl Processor for Delay {
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize, _max_block_frames: usize) {
+        self.sample_rate = sample_rate;
+        self.channel_count = channel_count;
+
+        for param in self.params_mut() {
+            param.prepare(sample_rate);
+        }
+
+        let length = (MAX_DELAY_MS / 1000f32 * sample_rate as f32) as usize + 2;
+        self.lines = (0..channel_count)
+            .map(|_| Line {
+                buffer: vec![0f32; length],
+                low_cut: OnePole::default(),
+                high_cut: OnePole::default(),
+            })
+            .collect();
+        self.write = 0;
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        let channel_count = self.channel_count;
+        let low_cut = OnePole::coefficient(self.low_cut.next_value(), self.sample_rate);
+        let high_cut = OnePole::coefficient(self.high_cut.next_value(), self.sample_rate);
+        let ping_pong = self.ping_pong && channel_count == 2;
+        let length = self.lines.first().map_or(1, |line| line.buffer.len());
+
+        for frame in samples.chunks_exact_mut(channel_count) {
+            let delay = (self.time_ms.next_value() / 1000f32 * self.sample_rate as f32).max(1f32);
+            let feedback = self.feedback.next_value();
+            let mix = self.mix.next_value();
+
+            let mut repeats = [0f32; 2];
+            for (channel, line) in self.lines.iter_mut().enumerate() {
+                let delayed = Self::read(&line.buffer, self.write, delay);
+                let filtered = line.high_cut.low_pass(delayed, high_cut);
+                let filtered = filtered - line.low_cut.low_pass(filtered, low_cut);
+
+                if ping_pong {
+                    repeats[channel] = filtered;
+                } else {
+                    let dry = frame[channel];
+                    line.buffer[self.write] = dry + filtered * feedback;
+                    frame[channel] = dry + (filtered - dry) * mix;
+                }
+            }
+
+            if ping_pong {
+                // Both sides go in on the left, then each repeat crosses over
+                let input = (frame[0] + frame[1]) * 0.5;
+                self.lines[0].buffer[self.write] = input + repeats[1] * feedback;
+                self.lines[1].buffer[self.write] = repeats[0] * feedback;
+
+                for (sample, repeat) in frame.iter_mut().zip(repeats) {
+                    *sample += (repeat - *sample) * mix;
+                }
+            }
+
+            self.write = (self.write + 1) % length;
+        }
+    }
+
+    fn tail(&self) -> usize {
+        let delay = self.time_ms.value() / 1000f32 * self.sample_rate as f32;
+        let feedback = self.feedback.value();
+
+        // One repeat, and then however many it takes feedback to die away
+        let repeats = if feedback > 0f32 {
+            1f32 + SILENCE_DB / (20f32 * feedback.log10())
+        } else {
+            1f32
+        };
+
+        // Ping-pong takes two trips to go round once
+        let trips = if self.ping_pong { 2f32 } else { 1f32 };
+        (delay * repeats * trips).ceil() as usize
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        [
+            &self.time_ms,
+            &self.feedback,
+            &self.low_cut,
+            &self.high_cut,
+            &self.mix,
+        ]
+        .iter()
+        .map(|param| param.handle())
+        .collect()
+    }
+
+    fn reset(&mut self) {
+        for param in self.params_mut() {
+            param.reset();
+        }
+
+        for line in self.lines.iter_mut() {
+            line.buffer.fill(0f32);
+            line.low_cut = OnePole::default();
+            line.high_cut = OnePole::default();
+        }
+        self.write = 0;
+    }
+}
+
+#[cfg(test)]
+mod delay_tests {
+    use crate::delay::*;
+
+    fn delay(settings: DelaySettings, channel_count: usize) -> Delay {
+        let mut delay = Delay::new(
+            &DelaySettings {
+                low_cut: 10f32,
+                high_cut: 22000f32,
+                ..settings
+            },
+            120f32,
+        );
+        delay.prepare(1000, channel_count, 64);
+        delay
+    }
+
+    #[test]
+    fn repeats_die_away_with_feedback() {
+        // Half a beat at 120 bpm is 250 ms, which is 250 frames at 1 kHz
+        let mut delay = delay(
+            DelaySettings {
+                feedback: 0.5,
+                ..Default::default()
+            },
+            1,
+        );
+
+        let mut samples = vec![0f32; 1000];
+        samples[0] = 1f32;
+        delay.process(&mut samples);
+
+        assert_eq!(samples[0], 0f32);
+        assert!(samples[250] > 0.5);
+        let ratio = samples[500] / samples[250];
+        assert!((ratio - 0.5).abs() < 0.05, "ratio {}", ratio);
+        assert!(samples[251..500].iter().all(|s| s.abs() < 0.1));
+
+        // -60 dB at half each time is just under 10 repeats, and one more for
+        // the first
+        assert_eq!(delay.tail(), 2742);
+    }
+
+    #[test]
+    fn ping_pong_bounces_between_sides() {
+        let mut delay = delay(
+            DelaySettings {
+                time: DelayTime::Ms(100f32),
+                feedback: 0.5,
+                ping_pong: true,
+                ..Default::default()
+            },
+            2,
+        );
+
+        let mut samples = vec![0f32; 1000];
+        samples[0] = 1f32;
+        samples[1] = 1f32;
+        delay.process(&mut samples);
+
+        let frame = |index: usize| (samples[index * 2], samples[index * 2 + 1]);
+        let (left, right) = frame(100);
+        assert!(left > 0.5 && right.abs() < 1e-3);
+        let (left, right) = frame(200);
+        assert!(left.abs() < 1e-3 && right > 0.25);
+        let (left, right) = frame(300);
+        assert!(left > 0.1 && right.abs() < 1e-3);
+    }
+
+    #[test]
+    fn mix_keeps_the_dry_signal() {
+        let mut delay = delay(
+            DelaySettings {
+                mix: 0f32,
+                ..Default::default()
+            },
+            1,
+        );
+
+        let mut samples = vec![0.5f32; 100];
+        delay.process(&mut samples);
+        assert!(samples.iter().all(|s| *s == 0.5));
+    }
+}
@@ -0,0 +1,344 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{
+    Device, Host, SampleFormat, SampleRate, SupportedBufferSize, SupportedStreamConfig,
+    SupportedStreamConfigRange,
+};
+
+use crate::error::{Error, Result};
+
+/// Frames the output stream asks for at a time when no buffer size is given
+pub const DEFAULT_BUFFER_SIZE: u32 = 64;
+
+/// An output device and what it can play.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// Position in `output_devices`, for `DeviceSelector::Index`
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+    pub default_config: Option<SupportedStreamConfig>,
+    pub configs: Vec<SupportedStreamConfigRange>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DeviceSelector {
+    #[default]
+    Default,
+    Name(String),
+    Index(usize),
+}
+
+/// What to do when the device can't play the requested config.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FallbackPolicy {
+    /// Fail
+    Strict,
+    /// Use the supported config closest to the one asked for
+    #[default]
+    Nearest,
+    /// Use the device's default config
+    DeviceDefault,
+}
+
+/// The output to open. Anything left as `None` uses the device's default.
+#[derive(Clone, Debug, Default)]
+pub struct OutputConfig {
+    /// Name of the audio host (e.g. "ALSA", "CoreAudio", "WASAPI")
+    pub host: Option<String>,
+    pub device: DeviceSelector,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub sample_format: Option<SampleFormat>,
+    /// Frames per callback
+    pub buffer_size: Option<u32>,
+    pub fallback: FallbackPolicy,
+}
+
+/// An opened output device with the config it will be run at.
+pub struct Output {
+    pub host: Host,
+    pub device: Device,
+    pub config: SupportedStreamConfig,
+    pub buffer_size: u32,
+}
+
+/// Names of the audio hosts available on this platform.
+pub fn hosts() -> Vec<String> {
+    cpal::available_hosts()
+        .iter()
+        .map(|id| id.name().to_string())
+        .collect()
+}
+
+/// Every output device of `host`, or of the default host when `None`.
+pub fn output_devices(host: Option<&str>) -> Result<Vec<DeviceInfo>> {
+    let host = open_host(host)?;
+    let default_name = host.default_output_device().and_then(|d| d.name().ok());
+
+    let devices = host.output_devices()?;
+
+    Ok(devices
+        .enumerate()
+        .map(|(index, device)| {
+            let name = device.name().unwrap_or_default();
+
+            DeviceInfo {
+                index,
+                is_default: default_name.as_ref() == Some(&name),
+                default_config: device.default_output_config().ok(),
+                configs: device
+                    .supported_output_configs()
+                    .map(|configs| configs.collect())
+                    .unwrap_or_default(),
+                name,
+            }
+        })
+        .collect())
+}
+
+/// Finds the host and device asked for and works out the config to run it at.
+pub fn open_output(request: &OutputConfig) -> Result<Output> {
+    let host = open_host(request.host.as_deref())?;
+
+    let device = match &request.device {
+        DeviceSelector::Default => host.default_output_device(),
+        DeviceSelector::Name(name) => host
+            .output_devices()?
+            .find(|d| d.name().is_ok_and(|n| n == *name)),
+        DeviceSelector::Index(index) => host.output_devices()?.nth(*index),
+    }
+    .ok_or_else(|| Error::DeviceUnavailable(format!("no output device {:?}", request.device)))?;
+
+    let default_config = device.default_output_config()?;
+    let supported: Vec<_> = device.supported_output_configs()?.collect();
+
+    let (config, buffer_size) = choose_config(request, &default_config, &supported)?;
+
+    Ok(Output {
+        host,
+        device,
+        config,
+        buffer_size,
+    })
+}
+
+fn open_host(name: Option<&str>) -> Result<Host> {
+    match name {
+        None => Ok(cpal::default_host()),
+        Some(name) => {
+            let id = cpal::available_hosts()
+                .into_iter()
+                .find(|id| id.name().eq_ignore_ascii_case(name))
+                .ok_or_else(|| Error::DeviceUnavailable(format!("no audio host {}", name)))?;
+
+            Ok(cpal::host_from_id(id)?)
+        }
+    }
+}
+
+/// Picks the config and buffer size to open the device with. Whatever the
+/// request leaves out comes from `default_config`.
+pub(crate) fn choose_config(
+    request: &OutputConfig,
+    default_config: &SupportedStreamConfig,
+    supported: &[SupportedStreamConfigRange],
+) -> Result<(SupportedStreamConfig, u32)> {
+    let sample_rate = request
+        .sample_rate
+        .unwrap_or(default_config.sample_rate().0);
+    let channels = request.channels.unwrap_or(default_config.channels());
+    let sample_format = request
+        .sample_format
+        .unwrap_or(default_config.sample_format());
+    let buffer_size = request.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+
+    let exact = supported.iter().find(|range| {
+        range.channels() == channels
+            && range.sample_format() == sample_format
+            && (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&sample_rate)
+            && buffer_size_supported(range.buffer_size(), buffer_size)
+    });
+
+    if let Some(range) = exact {
+        return Ok((
+            range.clone().with_sample_rate(SampleRate(sample_rate)),
+            buffer_size,
+        ));
+    }
+
+    match request.fallback {
+        FallbackPolicy::Strict => Err(Error::UnsupportedStreamConfig(format!(
+            "{} channels of {:?} at {}Hz in buffers of {}",
+            channels, sample_format, sample_rate, buffer_size
+        ))),
+        FallbackPolicy::DeviceDefault => {
+            let buffer_size = clamp_buffer_size(default_config.buffer_size(), buffer_size);
+            Ok((default_config.clone(), buffer_size))
+        }
+        FallbackPolicy::Nearest => {
+            // Prefer keeping the channel layout, then the sample rate (to
+            // avoid resampling), then the sample format
+            let range = supported
+                .iter()
+                .min_by_key(|range| {
+                    let rate =
+                        sample_rate.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+
+                    (
+                        range.channels().abs_diff(channels),
+                        rate.abs_diff(sample_rate),
+                        range.sample_format() != sample_format,
+                    )
+                })
+                .ok_or_else(|| {
+                    Error::UnsupportedStreamConfig("the device has no output configs".to_string())
+                })?;
+
+            let rate = sample_rate.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+            let buffer_size = clamp_buffer_size(range.buffer_size(), buffer_size);
+
+            Ok((
+                range.clone().with_sample_rate(SampleRate(rate)),
+                buffer_size,
+            ))
+        }
+    }
+}
+
+fn buffer_size_supported(supported: &SupportedBufferSize, buffer_size: u32) -> bool {
+    match supported {
+        SupportedBufferSize::Range { min, max } => (*min..=*max).contains(&buffer_size),
+        SupportedBufferSize::Unknown => true,
+    }
+}
+
+fn clamp_buffer_size(supported: &SupportedBufferSize, buffer_size: u32) -> u32 {
+    match supported {
+        SupportedBufferSize::Range { min, max } => buffer_size.clamp(*min, *max),
+        SupportedBufferSize::Unknown => buffer_size,
+    }
+}
+
+#[cfg(test)]
+mod device_tests {
+    use crate::device::*;
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Range { min: 32, max: 4096 },
+            format,
+        )
+    }
+
+    fn supported() -> Vec<SupportedStreamConfigRange> {
+        vec![
+            range(2, 44100, 48000, SampleFormat::I16),
+            range(2, 88200, 96000, SampleFormat::F32),
+            range(6, 44100, 48000, SampleFormat::F32),
+        ]
+    }
+
+    fn default_config() -> SupportedStreamConfig {
+        range(2, 44100, 48000, SampleFormat::I16).with_sample_rate(SampleRate(48000))
+    }
+
+    fn choose(request: OutputConfig) -> Result<(SupportedStreamConfig, u32)> {
+        choose_config(&request, &default_config(), &supported())
+    }
+
+    #[test]
+    fn defaults_come_from_the_device() {
+        let (config, buffer_size) = choose(OutputConfig::default()).unwrap();
+
+        assert_eq!(config, default_config());
+        assert_eq!(buffer_size, DEFAULT_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn exact_match_is_used() {
+        let (config, buffer_size) = choose(OutputConfig {
+            sample_rate: Some(96000),
+            sample_format: Some(SampleFormat::F32),
+            buffer_size: Some(256),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(config.channels(), 2);
+        assert_eq!(config.sample_rate().0, 96000);
+        assert_eq!(config.sample_format(), SampleFormat::F32);
+        assert_eq!(buffer_size, 256);
+    }
+
+    #[test]
+    fn strict_fails_when_unsupported() {
+        let result = choose(OutputConfig {
+            sample_rate: Some(192000),
+            fallback: FallbackPolicy::Strict,
+            ..Default::default()
+        });
+
+        assert!(matches!(result, Err(Error::UnsupportedStreamConfig(_))));
+
+        let result = choose(OutputConfig {
+            buffer_size: Some(8),
+            fallback: FallbackPolicy::Strict,
+            ..Default::default()
+        });
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn device_default_fallback() {
+        let (config, _) = choose(OutputConfig {
+            channels: Some(8),
+            fallback: FallbackPolicy::DeviceDefault,
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(config, default_config());
+    }
+
+    #[test]
+    fn nearest_fallback() {
+        // Closest rate in a stereo config, even if the format differs
+        let (config, _) = choose(OutputConfig {
+            sample_rate: Some(192000),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(config.channels(), 2);
+        assert_eq!(config.sample_rate().0, 96000);
+        assert_eq!(config.sample_format(), SampleFormat::F32);
+
+        // Closest channel count
+        let (config, _) = choose(OutputConfig {
+            channels: Some(8),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(config.channels(), 6);
+        assert_eq!(config.sample_rate().0, 48000);
+
+        // Buffer size is clamped to what the device allows
+        let (_, buffer_size) = choose(OutputConfig {
+            buffer_size: Some(10000),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(buffer_size, 4096);
+    }
+}
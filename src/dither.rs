@@ -0,0 +1,153 @@
+/// How to treat the rounding error when converting float samples to integers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dither {
+    /// Round to the nearest integer. Cheapest, but the rounding error is
+    /// correlated with the signal and can be heard on quiet material.
+    None,
+    /// Add triangular (TPDF) noise of ±1 LSB before rounding, which turns the
+    /// rounding error into a constant, signal independent noise floor.
+    Triangular,
+    /// TPDF dither with first order error feedback. The noise floor is pushed
+    /// up towards Nyquist where it is harder to hear.
+    NoiseShaped,
+}
+
+/// Quantizes interleaved float samples to integers of a given bit depth,
+/// keeping any dither state per channel.
+pub struct Ditherer {
+    dither: Dither,
+    scale: f64,
+    min: f64,
+    max: f64,
+    rng: Xorshift,
+    errors: Vec<f64>,
+}
+
+impl Ditherer {
+    pub fn new(dither: Dither, bits_per_sample: u32, channel_count: usize) -> Self {
+        let scale = (1u64 << (bits_per_sample - 1)) as f64;
+
+        Ditherer {
+            dither,
+            scale,
+            min: -scale,
+            max: scale - 1f64,
+            rng: Xorshift::new(0x9e37_79b9),
+            errors: vec![0f64; channel_count],
+        }
+    }
+
+    /// Converts a sample in [-1.0, 1.0] to an integer sample for `channel`.
+    /// Values outside that range are clipped.
+    #[inline]
+    pub fn quantize(&mut self, channel: usize, sample: f32) -> i32 {
+        let scaled = sample as f64 * self.scale;
+
+        let quantized = match self.dither {
+            Dither::None => scaled.round(),
+            Dither::Triangular => (scaled + self.rng.triangular()).round(),
+            Dither::NoiseShaped => {
+                let wanted = scaled - self.errors[channel];
+                let quantized = (wanted + self.rng.triangular()).round();
+
+                // Keep the feedback bounded when the signal clips, otherwise
+                // the error would keep growing for as long as it stays clipped.
+                self.errors[channel] =
+                    (quantized.clamp(self.min, self.max) - wanted).clamp(-2f64, 2f64);
+
+                quantized
+            }
+        };
+
+        quantized.clamp(self.min, self.max) as i32
+    }
+}
+
+/// Small, fast and deterministic noise source so that renders are
+/// reproducible.
+struct Xorshift(u32);
+
+impl Xorshift {
+    fn new(seed: u32) -> Self {
+        Xorshift(seed)
+    }
+
+    #[inline]
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+
+        x
+    }
+
+    /// Uniform noise in [0, 1)
+    #[inline]
+    fn uniform(&mut self) -> f64 {
+        self.next_u32() as f64 / (u32::MAX as f64 + 1f64)
+    }
+
+    /// Triangular noise in (-1, 1)
+    #[inline]
+    fn triangular(&mut self) -> f64 {
+        self.uniform() - self.uniform()
+    }
+}
+
+#[cfg(test)]
+mod dither_tests {
+    use crate::dither::*;
+
+    #[test]
+    fn no_dither_rounds_to_nearest() {
+        let mut ditherer = Ditherer::new(Dither::None, 16, 1);
+
+        assert_eq!(ditherer.quantize(0, 0f32), 0);
+        assert_eq!(ditherer.quantize(0, 0.5f32), 16384);
+        assert_eq!(ditherer.quantize(0, -1f32), -32768);
+        assert_eq!(ditherer.quantize(0, 1f32), 32767);
+        assert_eq!(ditherer.quantize(0, 2f32), 32767);
+        assert_eq!(ditherer.quantize(0, -2f32), -32768);
+    }
+
+    #[test]
+    fn bit_depth_sets_range() {
+        let mut ditherer = Ditherer::new(Dither::None, 24, 1);
+
+        assert_eq!(ditherer.quantize(0, 1f32), 8388607);
+        assert_eq!(ditherer.quantize(0, -1f32), -8388608);
+    }
+
+    #[test]
+    fn triangular_dither_stays_within_one_lsb() {
+        let mut ditherer = Ditherer::new(Dither::Triangular, 16, 2);
+        let sample = 0.25f32;
+        let exact = (sample * 32768f32) as i32;
+        let mut total = 0i64;
+
+        for i in 0..10_000 {
+            let quantized = ditherer.quantize(i % 2, sample);
+            assert!((quantized - exact).abs() <= 1);
+            total += quantized as i64;
+        }
+
+        // The dither noise averages out
+        assert_eq!((total as f64 / 10_000f64).round() as i32, exact);
+    }
+
+    #[test]
+    fn noise_shaping_keeps_the_average() {
+        let mut ditherer = Ditherer::new(Dither::NoiseShaped, 16, 1);
+        let sample = 0.1f32;
+        let exact = sample as f64 * 32768f64;
+        let mut total = 0f64;
+
+        for _ in 0..10_000 {
+            total += ditherer.quantize(0, sample) as f64;
+        }
+
+        assert!((total / 10_000f64 - exact).abs() < 0.01);
+    }
+}
@@ -0,0 +1,644 @@
+use std::collections::VecDeque;
+
+use crate::{
+    meter::{GainReduction, GainReductionHandle},
+    mixer::{db_to_gain, Processor},
+    param::{Param, ParamHandle, ParamInfo, Smoothing, Unit},
+};
+
+/// Gain a level of 0 is measured as, to keep silence out of the logs
+const SILENCE_DB: f32 = -120f32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompressorSettings {
+    /// Level above which it starts turning down, in dBFS
+    pub threshold_db: f32,
+    /// How many dB over the threshold in it takes to come out 1 dB over
+    pub ratio: f32,
+    /// Width in dB of the bend around the threshold, 0 for a hard knee
+    pub knee_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    /// Gain after compressing, to make up for what it took off
+    pub makeup_db: f32,
+    /// How far ahead it sees, so it can turn down before a peak rather than
+    /// on it. Delays the channel by as much, and the other channels with it.
+    pub lookahead_ms: f32,
+    /// Id of the channel whose level it follows instead of its own
+    pub sidechain: Option<String>,
+}
+
+impl Default for CompressorSettings {
+    fn default() -> Self {
+        CompressorSettings {
+            threshold_db: -18f32,
+            ratio: 4f32,
+            knee_db: 6f32,
+            attack_ms: 10f32,
+            release_ms: 100f32,
+            makeup_db: 0f32,
+            lookahead_ms: 0f32,
+            sidechain: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GateSettings {
+    /// Level it opens at, in dBFS
+    pub threshold_db: f32,
+    /// How far under the threshold the level has to fall before it closes,
+    /// so it doesn't chatter on a level right at the threshold
+    pub hysteresis_db: f32,
+    /// How steeply it turns down whatever is under the threshold when closed.
+    /// 2 is a gentle expander, the top of the range is a gate.
+    pub ratio: f32,
+    /// The most it turns down, in dB
+    pub range_db: f32,
+    pub attack_ms: f32,
+    /// How long it stays open after the level falls under it
+    pub hold_ms: f32,
+    pub release_ms: f32,
+    /// Id of the channel whose level it follows instead of its own
+    pub sidechain: Option<String>,
+}
+
+impl Default for GateSettings {
+    fn default() -> Self {
+        GateSettings {
+            threshold_db: -40f32,
+            hysteresis_db: 6f32,
+            ratio: 100f32,
+            range_db: -80f32,
+            attack_ms: 1f32,
+            hold_ms: 50f32,
+            release_ms: 100f32,
+            sidechain: None,
+        }
+    }
+}
+
+fn param(name: &str, unit: Unit, min: f32, max: f32, value: f32) -> Param {
+    let info = ParamInfo {
+        name: name.to_string(),
+        unit,
+        min,
+        max,
+        default: value,
+        smoothing: Smoothing::None,
+    };
+
+    Param::with_value(info, value)
+}
+
+/// How much of the way an envelope is left from its target after a frame,
+/// for it to get most of the way in `ms`.
+fn coefficient(ms: f32, sample_rate: u32) -> f32 {
+    let frames = ms / 1000f32 * sample_rate as f32;
+
+    if frames < 1f32 {
+        0f32
+    } else {
+        (-1f32 / frames).exp()
+    }
+}
+
+/// Level of the loudest channel of a frame.
+#[inline]
+fn frame_peak(frame: &[f32]) -> f32 {
+    frame.iter().fold(0f32, |peak, s| peak.max(s.abs()))
+}
+
+#[inline]
+fn gain_to_db(gain: f32) -> f32 {
+    if gain > 0f32 {
+        (20f32 * gain.log10()).max(SILENCE_DB)
+    } else {
+        SILENCE_DB
+    }
+}
+
+/// A feed-forward compressor. The loudest channel sets the gain for all of
+/// them, so the stereo image doesn't shift.
+pub struct Compressor {
+    threshold_db: Param,
+    ratio: Param,
+    knee_db: Param,
+    attack_ms: Param,
+    release_ms: Param,
+    makeup_db: Param,
+    lookahead_ms: f32,
+    sample_rate: u32,
+    channel_count: usize,
+    attack: f32,
+    release: f32,
+    // Gain reduction in dB, 0 or more: the peak of it, released, and that
+    // smoothed by the attack
+    peak: f32,
+    envelope: f32,
+    lookahead: usize,
+    delay: VecDeque<f32>,
+    gain_reduction: GainReduction,
+}
+
+impl Compressor {
+    pub fn new(settings: &CompressorSettings) -> Self {
+        Compressor {
+            threshold_db: param(
+                "Threshold",
+                Unit::Decibels,
+                -60f32,
+                0f32,
+                settings.threshold_db,
+            ),
+            ratio: param("Ratio", Unit::Ratio, 1f32, 20f32, settings.ratio),
+            knee_db: param("Knee", Unit::Decibels, 0f32, 24f32, settings.knee_db),
+            attack_ms: param(
+                "Attack",
+                Unit::Milliseconds,
+                0f32,
+                200f32,
+                settings.attack_ms,
+            ),
+            release_ms: param(
+                "Release",
+                Unit::Milliseconds,
+                1f32,
+                2000f32,
+                settings.release_ms,
+            ),
+            makeup_db: param("Makeup", Unit::Decibels, 0f32, 24f32, settings.makeup_db),
+            lookahead_ms: settings.lookahead_ms.max(0f32),
+            sample_rate: 44100,
+            channel_count: 1,
+            attack: 0f32,
+            release: 0f32,
+            peak: 0f32,
+            envelope: 0f32,
+            lookahead: 0,
+            delay: VecDeque::new(),
+            gain_reduction: GainReduction::default(),
+        }
+    }
+
+    /// Gain change in dB for a level of `level_db`, 0 or less. Bends smoothly
+    /// from no compression to the full ratio across the knee.
+    fn gain_computer(&self, level_db: f32) -> f32 {
+        let threshold = self.threshold_db.value();
+        let slope = 1f32 / self.ratio.value() - 1f32;
+        let knee = self.knee_db.value();
+        let over = level_db - threshold;
+
+        if 2f32 * over <= -knee {
+            0f32
+        } else if 2f32 * over.abs() <= knee {
+            slope * (over + knee / 2f32).powi(2) / (2f32 * knee)
+        } else {
+            slope * over
+        }
+    }
+
+    /// Runs `samples` through, following the level of `sidechain` when
+    /// there is one and of `samples` when there isn't.
+    fn run(&mut self, samples: &mut [f32], sidechain: Option<&[f32]>) {
+        self.update(samples.len() / self.channel_count);
+
+        let channel_count = self.channel_count;
+        let makeup = self.makeup_db.value();
+        let mut most_reduction = 0f32;
+
+        for (i, frame) in samples.chunks_exact_mut(channel_count).enumerate() {
+            let level = match sidechain {
+                Some(sidechain) => {
+                    gain_to_db(frame_peak(&sidechain[i * channel_count..][..channel_count]))
+                }
+                None => gain_to_db(frame_peak(frame)),
+            };
+            // A smooth decoupled peak detector (Giannoulis, Massberg and
+            // Reiss): reduction jumps up and releases slowly, then the attack
+            // smooths the jumps
+            let reduction = -self.gain_computer(level);
+            self.peak = reduction.max(reduction + (self.peak - reduction) * self.release);
+            self.envelope = self.peak + (self.envelope - self.peak) * self.attack;
+            most_reduction = most_reduction.max(self.envelope);
+
+            let gain = db_to_gain(makeup - self.envelope);
+            for sample in frame.iter_mut() {
+                self.delay.push_back(*sample);
+                *sample = self.delay.pop_front().unwrap_or(0f32) * gain;
+            }
+        }
+
+        self.gain_reduction.set(most_reduction);
+    }
+
+    fn update(&mut self, frames: usize) {
+        for param in [
+            &mut self.threshold_db,
+            &mut self.ratio,
+            &mut self.knee_db,
+            &mut self.makeup_db,
+        ] {
+            param.advance(frames);
+        }
+
+        self.attack = coefficient(self.attack_ms.advance(frames), self.sample_rate);
+        self.release = coefficient(self.release_ms.advance(frames), self.sample_rate);
+    }
+}
+
+impl Processor for Compressor {
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize, _max_block_frames: usize) {
+        self.sample_rate = sample_rate;
+        self.channel_count = channel_count;
+        self.lookahead = (self.lookahead_ms / 1000f32 * sample_rate as f32).round() as usize;
+
+        for param in [
+            &mut self.threshold_db,
+            &mut self.ratio,
+            &mut self.knee_db,
+            &mut self.attack_ms,
+            &mut self.release_ms,
+            &mut self.makeup_db,
+        ] {
+            param.prepare(sample_rate);
+        }
+
+        self.reset();
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        self.run(samples, None);
+    }
+
+    fn process_with_sidechain(&mut self, samples: &mut [f32], sidechain: &[f32]) {
+        self.run(samples, Some(sidechain));
+    }
+
+    fn latency(&self) -> usize {
+        self.lookahead
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        [
+            &self.threshold_db,
+            &self.ratio,
+            &self.knee_db,
+            &self.attack_ms,
+            &self.release_ms,
+            &self.makeup_db,
+        ]
+        .iter()
+        .map(|param| param.handle())
+        .collect()
+    }
+
+    fn gain_reduction(&self) -> Option<GainReductionHandle> {
+        Some(self.gain_reduction.handle())
+    }
+
+    fn reset(&mut self) {
+        self.update(0);
+        self.peak = 0f32;
+        self.envelope = 0f32;
+        self.delay.clear();
+        self.delay.resize(self.lookahead * self.channel_count, 0f32);
+        self.gain_reduction.set(0f32);
+    }
+}
+
+/// A gate, or an expander with a gentle ratio: turns down whatever is under
+/// the threshold. Like the compressor, the loudest channel opens it for all of
+/// them.
+pub struct Gate {
+    threshold_db: Param,
+    hysteresis_db: Param,
+    ratio: Param,
+    range_db: Param,
+    attack_ms: Param,
+    hold_ms: Param,
+    release_ms: Param,
+    sample_rate: u32,
+    channel_count: usize,
+    attack: f32,
+    release: f32,
+    hold: u64,
+    // Peak level of the detector, falling at the release
+    level: f32,
+    open: bool,
+    hold_left: u64,
+    // Gain reduction in dB, 0 or less
+    envelope: f32,
+    gain_reduction: GainReduction,
+}
+
+impl Gate {
+    pub fn new(settings: &GateSettings) -> Self {
+        Gate {
+            threshold_db: param(
+                "Threshold",
+                Unit::Decibels,
+                -90f32,
+                0f32,
+                settings.threshold_db,
+            ),
+            hysteresis_db: param(
+                "Hysteresis",
+                Unit::Decibels,
+                0f32,
+                24f32,
+                settings.hysteresis_db,
+            ),
+            ratio: param("Ratio", Unit::Ratio, 1f32, 100f32, settings.ratio),
+            range_db: param("Range", Unit::Decibels, -120f32, 0f32, settings.range_db),
+            attack_ms: param(
+                "Attack",
+                Unit::Milliseconds,
+                0f32,
+                100f32,
+                settings.attack_ms,
+            ),
+            hold_ms: param("Hold", Unit::Milliseconds, 0f32, 2000f32, settings.hold_ms),
+            release_ms: param(
+                "Release",
+                Unit::Milliseconds,
+                1f32,
+                4000f32,
+                settings.release_ms,
+            ),
+            sample_rate: 44100,
+            channel_count: 1,
+            attack: 0f32,
+            release: 0f32,
+            hold: 0,
+            level: 0f32,
+            open: false,
+            hold_left: 0,
+            envelope: 0f32,
+            gain_reduction: GainReduction::default(),
+        }
+    }
+
+    /// Runs `samples` through, following the level of `sidechain` when
+    /// there is one and of `samples` when there isn't.
+    fn run(&mut self, samples: &mut [f32], sidechain: Option<&[f32]>) {
+        self.update(samples.len() / self.channel_count);
+
+        let channel_count = self.channel_count;
+        let threshold = self.threshold_db.value();
+        let close_at = threshold - self.hysteresis_db.value();
+        let slope = self.ratio.value() - 1f32;
+        let range = self.range_db.value();
+        let mut most_reduction = 0f32;
+
+        for (i, frame) in samples.chunks_exact_mut(channel_count).enumerate() {
+            let peak = match sidechain {
+                Some(sidechain) => frame_peak(&sidechain[i * channel_count..][..channel_count]),
+                None => frame_peak(frame),
+            };
+            self.level = peak.max(self.level * self.release);
+            let level = gain_to_db(self.level);
+
+            if level >= threshold {
+                self.open = true;
+                self.hold_left = self.hold;
+            } else if self.open && level < close_at {
+                if self.hold_left > 0 {
+                    self.hold_left -= 1;
+                } else {
+                    self.open = false;
+                }
+            }
+
+            let target = if self.open {
+                0f32
+            } else {
+                ((level - threshold) * slope).clamp(range, 0f32)
+            };
+            let coefficient = if target > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope = target + (self.envelope - target) * coefficient;
+            most_reduction = most_reduction.min(self.envelope);
+
+            let gain = db_to_gain(self.envelope);
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+        }
+
+        self.gain_reduction.set(-most_reduction);
+    }
+
+    fn update(&mut self, frames: usize) {
+        for param in [
+            &mut self.threshold_db,
+            &mut self.hysteresis_db,
+            &mut self.ratio,
+            &mut self.range_db,
+        ] {
+            param.advance(frames);
+        }
+
+        self.attack = coefficient(self.attack_ms.advance(frames), self.sample_rate);
+        self.release = coefficient(self.release_ms.advance(frames), self.sample_rate);
+        self.hold = (self.hold_ms.advance(frames) / 1000f32 * self.sample_rate as f32) as u64;
+    }
+}
+
+impl Processor for Gate {
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize, _max_block_frames: usize) {
+        self.sample_rate = sample_rate;
+        self.channel_count = channel_count;
+
+        for param in [
+            &mut self.threshold_db,
+            &mut self.hysteresis_db,
+            &mut self.ratio,
+            &mut self.range_db,
+            &mut self.attack_ms,
+            &mut self.hold_ms,
+            &mut self.release_ms,
+        ] {
+            param.prepare(sample_rate);
+        }
+
+        self.reset();
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        self.run(samples, None);
+    }
+
+    fn process_with_sidechain(&mut self, samples: &mut [f32], sidechain: &[f32]) {
+        self.run(samples, Some(sidechain));
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        [
+            &self.threshold_db,
+            &self.hysteresis_db,
+            &self.ratio,
+            &self.range_db,
+            &self.attack_ms,
+            &self.hold_ms,
+            &self.release_ms,
+        ]
+        .iter()
+        .map(|param| param.handle())
+        .collect()
+    }
+
+    fn gain_reduction(&self) -> Option<GainReductionHandle> {
+        Some(self.gain_reduction.handle())
+    }
+
+    fn reset(&mut self) {
+        self.update(0);
+        self.level = 0f32;
+        self.open = false;
+        self.hold_left = 0;
+        self.envelope = 0f32;
+        self.gain_reduction.set(0f32);
+    }
+}
+
+#[cfg(test)]
+mod dynamics_tests {
+    use crate::dynamics::*;
+
+    fn tone(level_db: f32, frames: usize) -> Vec<f32> {
+        let amplitude = db_to_gain(level_db);
+
+        (0..frames)
+            .flat_map(|i| {
+                let sample = amplitude * (i as f32 * 0.1).sin();
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    fn peak_db(samples: &[f32]) -> f32 {
+        20f32 * samples.iter().fold(0f32, |p, s| p.max(s.abs())).log10()
+    }
+
+    #[test]
+    fn compresses_above_the_threshold() {
+        let mut compressor = Compressor::new(&CompressorSettings {
+            threshold_db: -20f32,
+            ratio: 4f32,
+            knee_db: 0f32,
+            makeup_db: 3f32,
+            ..Default::default()
+        });
+        compressor.prepare(48000, 2, 512);
+
+        // 20 dB over comes out 5 dB over, plus the makeup
+        let mut samples = tone(0f32, 48000);
+        compressor.process(&mut samples);
+        assert!((peak_db(&samples[48000..]) - -12f32).abs() < 0.2);
+        assert!((compressor.gain_reduction().unwrap().db() - 15f32).abs() < 0.2);
+
+        // Under the threshold only the makeup applies, once it has released
+        let mut samples = tone(-30f32, 48000);
+        compressor.process(&mut samples);
+        assert!((peak_db(&samples[48000..]) - -27f32).abs() < 0.1);
+    }
+
+    #[test]
+    fn soft_knee_bends_around_the_threshold() {
+        let compressor = Compressor::new(&CompressorSettings {
+            threshold_db: -20f32,
+            ratio: 4f32,
+            knee_db: 10f32,
+            ..Default::default()
+        });
+
+        assert_eq!(compressor.gain_computer(-26f32), 0f32);
+        assert!(compressor.gain_computer(-20f32) < 0f32);
+        assert!(compressor.gain_computer(-20f32) > -0.75 * 5f32);
+        assert!((compressor.gain_computer(-10f32) - -7.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn lookahead_delays_and_catches_peaks() {
+        let mut compressor = Compressor::new(&CompressorSettings {
+            threshold_db: -20f32,
+            ratio: 20f32,
+            knee_db: 0f32,
+            attack_ms: 1f32,
+            lookahead_ms: 5f32,
+            ..Default::default()
+        });
+        compressor.prepare(48000, 2, 512);
+        assert_eq!(compressor.latency(), 240);
+
+        let mut samples = tone(-40f32, 2000);
+        samples.extend(tone(0f32, 2000));
+        compressor.process(&mut samples);
+
+        // The step up is already turned down by the time it comes out
+        assert!(peak_db(&samples[(2000 + 240) * 2..(2000 + 300) * 2]) < -10f32);
+    }
+
+    #[test]
+    fn gate_opens_holds_and_closes() {
+        let mut gate = Gate::new(&GateSettings {
+            threshold_db: -30f32,
+            ..Default::default()
+        });
+        gate.prepare(1000, 2, 512);
+
+        // Closed on quiet, open on loud
+        let mut quiet = tone(-50f32, 500);
+        gate.process(&mut quiet);
+        assert!(peak_db(&quiet[500..]) < -100f32);
+
+        let mut loud = tone(-10f32, 500);
+        gate.process(&mut loud);
+        assert!((peak_db(&loud[500..]) - -10f32).abs() < 0.1);
+        assert!(gate.gain_reduction().unwrap().db() > 60f32);
+        gate.process(&mut tone(-10f32, 500));
+        assert!(gate.gain_reduction().unwrap().db() < 0.1);
+
+        // Just under the threshold, within the hysteresis, stays open
+        let mut between = tone(-33f32, 500);
+        gate.process(&mut between);
+        assert!((peak_db(&between[500..]) - -33f32).abs() < 0.1);
+    }
+
+    #[test]
+    fn expander_turns_down_by_its_ratio() {
+        let mut expander = Gate::new(&GateSettings {
+            threshold_db: -30f32,
+            hysteresis_db: 0f32,
+            ratio: 2f32,
+            hold_ms: 0f32,
+            ..Default::default()
+        });
+        expander.prepare(48000, 2, 512);
+
+        // 10 dB under goes another 10 dB down
+        let mut samples = tone(-40f32, 48000);
+        expander.process(&mut samples);
+        assert!((peak_db(&samples[48000..]) - -50f32).abs() < 0.2);
+    }
+
+    #[test]
+    fn sidechain_drives_the_gain() {
+        let mut compressor = Compressor::new(&CompressorSettings {
+            threshold_db: -20f32,
+            ratio: 20f32,
+            knee_db: 0f32,
+            ..Default::default()
+        });
+        compressor.prepare(48000, 2, 512);
+
+        // A quiet signal ducked by a loud sidechain
+        let mut samples = tone(-30f32, 48000);
+        compressor.process_with_sidechain(&mut samples, &tone(0f32, 48000));
+        assert!(peak_db(&samples[48000..]) < -45f32);
+    }
+}
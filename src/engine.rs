@@ -1,81 +1,54 @@
+use crate::backend::{Backend, CpalBackend, OutputCallback, OutputStream};
+use crate::builder::Playback;
+use crate::device::OutputConfig;
+use crate::error::{Error, Result};
+use crate::master::soft_clip;
+use crate::meter::Meters;
+use crate::param::InsertParams;
+use crate::playhead::{Playhead, PlayheadMarker, PlayheadTracker};
+use crate::ring_buffer::{ring_buffer, Consumer};
 use crate::source_reader::SourceReader;
+use crate::stats::{EngineStats, StatsCollector};
 use crate::symph::Symphonia;
 use crate::track::Track;
-use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::{Device, Host, SampleFormat, Stream, SupportedStreamConfig};
+use crate::transport::Transport;
+use cpal::SupportedStreamConfig;
 use crossbeam::channel::{bounded, select, Receiver, Sender};
 use parking_lot::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread;
+use std::thread::{self, Thread};
 use tokio::sync::{broadcast, mpsc};
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
-}
+/// Frames the transport can get ahead of the output stream by, at least. It
+/// always has room for a few of the stream's buffers.
+const RING_BUFFER_SIZE: usize = 2048;
 
 pub struct Engine {
-    rx: Receiver<f32>,
-    host: Host,
-    device: Device,
-    config: SupportedStreamConfig,
+    // Taken by the stream's callback once it starts
+    consumer: Option<Consumer>,
+    playhead: Arc<Playhead>,
+    markers: Receiver<PlayheadMarker>,
+    stats: Arc<StatsCollector>,
+    backend: Box<dyn Backend>,
 }
 
 impl Engine {
-    pub fn start_stream(&self) -> Result<Stream, ()> {
-        let rx_clone = self.rx.clone();
+    /// Starts the backend's output. The stream can only be started once, as
+    /// it takes over the receiving end of the ring buffer.
+    pub fn start_stream(&mut self) -> Result<Box<dyn OutputStream>> {
         println!("Starting stream...");
-        let mut config = self.config.config();
-        config.buffer_size = cpal::BufferSize::Fixed(64);
-
-        let stream_result = match self.config.sample_format() {
-            SampleFormat::I16 => self.device.build_output_stream(
-                // &self.config.config(),
-                &config,
-                move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
-                    for s in data.iter_mut() {
-                        *s = match rx_clone.recv() {
-                            Ok(sample) => cpal::Sample::from::<f32>(&sample),
-                            Err(_) => cpal::Sample::from::<f32>(&0f32),
-                        }
-                    }
-                },
-                err_fn,
-            ),
-            SampleFormat::U16 => self.device.build_output_stream(
-                // &self.config.config(),
-                &config,
-                move |data: &mut [u16], _: &cpal::OutputCallbackInfo| {
-                    for s in data.iter_mut() {
-                        *s = match rx_clone.recv() {
-                            Ok(sample) => cpal::Sample::from::<f32>(&sample),
-                            Err(_) => cpal::Sample::from::<f32>(&0f32),
-                        }
-                    }
-                },
-                err_fn,
-            ),
-            SampleFormat::F32 => self.device.build_output_stream(
-                // &self.config.config(),
-                &config,
-                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                    for s in data.iter_mut() {
-                        *s = match rx_clone.recv() {
-                            Ok(sample) => cpal::Sample::from::<f32>(&sample),
-                            Err(_) => cpal::Sample::from::<f32>(&0f32),
-                        }
-                    }
-                },
-                err_fn,
-            ),
-        };
 
-        let stream = stream_result.unwrap();
+        let output = OutputCallback::new(
+            self.consumer.take().ok_or(Error::StreamAlreadyStarted)?,
+            PlayheadTracker::new(self.playhead.clone(), self.markers.clone()),
+            self.stats.clone(),
+            self.backend.config(),
+            self.backend.buffer_size(),
+        );
 
-        Ok(stream)
+        self.backend.start(output)
     }
-
-    // pub fn send(&self, sample: f32) {
-    // }
 }
 
 pub struct EngineController {
@@ -83,100 +56,272 @@ pub struct EngineController {
     tracks: Vec<Arc<Track>>,
     engine: Arc<Mutex<Engine>>,
     command_tx: Sender<EngineCommand>,
-    command_rx: Receiver<EngineCommand>,
+    playback_tx: Sender<Playback>,
     // command_tx: broadcast::Sender<EngineCommand>,
     // command_rx: broadcast::Receiver<EngineCommand>,
-    pub prod: Sender<f32>,
     config: SupportedStreamConfig,
+    buffer_size: u32,
     engine_state: Arc<RwLock<EngineStateMachine>>,
+    // Parked for as long as the output stream should run
+    stream_thread: Mutex<Option<Thread>>,
+    closing: Arc<AtomicBool>,
+    playhead: Arc<Playhead>,
+    stats: Arc<StatsCollector>,
+    meters: Mutex<Option<Meters>>,
+    params: Mutex<Option<InsertParams>>,
 }
 
 impl EngineController {
-    pub fn new() -> Result<EngineController, ()> {
-        // TODO: Make sure this buffer is good.
-        let (tx, rx) = bounded::<f32>(1024);
-        let (command_tx, command_rx) = bounded::<EngineCommand>(1);
+    /// Opens the default output device at its default config.
+    pub fn new() -> Result<EngineController> {
+        Self::with_output(&OutputConfig::default())
+    }
+
+    /// Opens the output device asked for, see `device::output_devices` for
+    /// what is available.
+    pub fn with_output(output: &OutputConfig) -> Result<EngineController> {
+        Self::with_backend(Box::new(CpalBackend::open(output)?))
+    }
+
+    /// Plays through `backend`, e.g. a `NullBackend` where there is no sound
+    /// card.
+    pub fn with_backend(backend: Box<dyn Backend>) -> Result<EngineController> {
+        let (command_tx, command_rx) = bounded::<EngineCommand>(64);
+        let (playback_tx, playback_rx) = bounded::<Playback>(1);
+        let (marker_tx, marker_rx) = bounded::<PlayheadMarker>(64);
         // let (mut command_tx, command_rx) = broadcast::channel::<EngineCommand>(1);
 
-        let host = cpal::default_host();
-        let device = host.default_output_device().unwrap();
-        let config = device.default_output_config().unwrap();
+        let config = backend.config().clone();
+        let buffer_size = backend.buffer_size();
 
-        println!("Default Output: {:?}", config);
+        let ring_buffer_size = RING_BUFFER_SIZE.max(buffer_size as usize * 4);
+        let (producer, consumer) = ring_buffer(ring_buffer_size * config.channels() as usize);
+        let stats = Arc::new(StatsCollector::new(consumer.capacity()));
+
+        let engine_state = Arc::new(RwLock::new(EngineStateMachine::new()));
+        let transport = Transport::new(
+            engine_state.clone(),
+            command_rx,
+            playback_rx,
+            producer,
+            marker_tx,
+            stats.clone(),
+            &config.config(),
+        );
+        thread::spawn(move || transport.run());
+
+        let playhead = Arc::new(Playhead::new(config.sample_rate().0));
 
         let engine = Engine {
-            rx,
-            host,
-            config: config.clone(),
-            device,
+            consumer: Some(consumer),
+            playhead: playhead.clone(),
+            markers: marker_rx,
+            stats: stats.clone(),
+            backend,
         };
 
         let controller = EngineController {
-            engine_state: Arc::new(RwLock::new(EngineStateMachine::new())),
-            prod: tx,
+            engine_state,
             command_tx,
-            command_rx,
+            playback_tx,
+            stream_thread: Mutex::new(None),
+            closing: Arc::new(AtomicBool::new(false)),
+            playhead,
+            stats,
+            meters: Mutex::new(None),
+            params: Mutex::new(None),
             sources: Arc::new(Mutex::new(Sources::new())),
             engine: Arc::new(Mutex::new(engine)),
             tracks: Default::default(),
             config: config.clone(),
+            buffer_size,
         };
 
         Ok(controller)
     }
 
+    /// The config the output device is run at
+    pub fn output_config(&self) -> &SupportedStreamConfig {
+        &self.config
+    }
+
+    /// Frames the output device asks for per callback
+    pub fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
     pub fn engine(&self) -> Arc<Mutex<Engine>> {
         self.engine.clone()
     }
 
-    pub fn add(&self, decoder: Symphonia) {
-        let reader = SourceReader::new(decoder, self.config.config());
+    pub fn add(&self, decoder: Symphonia) -> Result<()> {
+        let reader = SourceReader::new(decoder, self.config.config())?;
         self.sources.lock().add(reader);
+
+        Ok(())
+    }
+
+    pub fn open_source_reader(&self, path: String) -> Result<()> {
+        let source = Symphonia::new(path)?;
+        self.add(source)
+    }
+
+    /// Hands `playback` to the transport, replacing whatever was loaded. It
+    /// starts from the current state, so load before playing.
+    ///
+    /// The playback has to be built for the output's channel count and
+    /// sample rate, see `output_config`.
+    pub fn load(&self, playback: Playback) -> Result<()> {
+        let output = self.config.config();
+        let config = playback.config();
+
+        if config.channels != output.channels || config.sample_rate != output.sample_rate {
+            return Err(Error::PlaybackMismatch(format!(
+                "built for {} channels at {} Hz, the output is {} channels at {} Hz",
+                config.channels, config.sample_rate.0, output.channels, output.sample_rate.0
+            )));
+        }
+
+        *self.meters.lock() = Some(playback.meters());
+        *self.params.lock() = Some(playback.params());
+
+        self.playback_tx
+            .send(playback)
+            .expect("Transport thread should be running");
+
+        Ok(())
+    }
+
+    /// Starts or resumes playback, opening the output stream the first time.
+    pub fn play(&self) -> Result<()> {
+        let mut stream_thread = self.stream_thread.lock();
+
+        if stream_thread.is_none() {
+            let engine = self.engine.clone();
+            let closing = self.closing.clone();
+            let (started_tx, started_rx) = bounded::<Result<()>>(1);
+
+            // The stream stops when dropped, so it lives on its own thread
+            let handle = thread::spawn(move || {
+                let _stream = match engine.lock().start_stream() {
+                    Ok(stream) => {
+                        let _ = started_tx.send(Ok(()));
+                        stream
+                    }
+                    Err(err) => {
+                        let _ = started_tx.send(Err(err));
+                        return;
+                    }
+                };
+
+                while !closing.load(Ordering::Acquire) {
+                    thread::park();
+                }
+            });
+
+            started_rx
+                .recv()
+                .map_err(|_| Error::Stream("the stream thread panicked".to_string()))??;
+
+            *stream_thread = Some(handle.thread().clone());
+        }
+
+        self.send(EngineCommand::Play);
+
+        Ok(())
+    }
+
+    /// Holds the playhead where it is and outputs silence until `play`.
+    pub fn pause(&self) {
+        self.send(EngineCommand::Pause);
     }
 
-    pub fn open_source_reader(&self, path: String) {
-        let source = Symphonia::new(path).unwrap();
-        self.add(source);
+    /// Stops playback and rewinds to the start of the timeline.
+    pub fn stop(&self) {
+        self.send(EngineCommand::Stop);
     }
 
-    // pub async fn play(&self) {
-    pub fn play(&self) {
-        let sources = self.sources.clone();
-        let sender = self.prod.clone();
-        let engine = self.engine.clone();
-        let command_rx = self.command_rx.clone();
-        let state_machine = self.engine_state.clone();
+    /// Moves the playhead to `frame` on the timeline without changing whether
+    /// it is playing.
+    pub fn seek(&self, frame: u64) {
+        self.send(EngineCommand::Seek(frame));
+    }
 
-        thread::spawn(move || {
-            let engine_lock = engine.lock();
-            let stream = engine_lock.start_stream().unwrap();
+    /// Where on the timeline the output currently is. Cheap to read from any
+    /// thread, e.g. to draw a cursor.
+    pub fn playhead(&self) -> Arc<Playhead> {
+        self.playhead.clone()
+    }
 
-            loop {}
-        });
+    /// Meters of the loaded playback, `None` until one is loaded.
+    pub fn meters(&self) -> Option<Meters> {
+        self.meters.lock().clone()
+    }
 
-        // Starting the stream can probably be moved to the other spawned process below
-        // tokio::spawn(async move {
-        //     let engine_lock = engine.lock();
-        //     let stream = engine_lock.start_stream().unwrap();
-        //
-        //     loop {}
-        // });
+    /// Insert parameters of the loaded playback, `None` until one is loaded.
+    /// Take them once and keep them: setting a parameter through its handle
+    /// doesn't need the controller at all.
+    pub fn params(&self) -> Option<InsertParams> {
+        self.params.lock().clone()
+    }
 
-        // tokio::spawn(async move {
-        // thread::spawn(move || {
-        //     let mut sources_lock = sources.lock();
-        //     while let Some(sample) = sources_lock.next() {
-        //         match sender.send(sample) {
-        //             Err(err) => eprintln!("Send Err: {}", err),
-        //             _ => (),
-        //         }
-        //     }
-        // });
+    /// How the engine has been keeping up since it started, or since the last
+    /// `reset_stats`.
+    pub fn stats(&self) -> EngineStats {
+        self.stats.snapshot()
+    }
 
-        self.engine_state.write().play();
+    /// Zeroes the counters and maximums in `stats`.
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// The transport's state as of the last block it rendered.
+    pub fn state(&self) -> EngineState {
+        self.engine_state.read().state()
+    }
+
+    fn send(&self, command: EngineCommand) {
+        self.command_tx
+            .send(command)
+            .expect("Transport thread should be running");
     }
 
     pub fn play_tracks(&self) {}
+
+    /// Pulls the next frames from every open source straight into `out` as
+    /// interleaved samples, without going through the output device. Returns
+    /// the number of frames written, 0 once every source has finished.
+    pub fn render_offline(&self, out: &mut [f32]) -> usize {
+        let channel_count = self.config.channels() as usize;
+        let mut sources = self.sources.lock();
+        let mut written = 0;
+
+        for sample in out.iter_mut() {
+            match sources.next() {
+                Some(s) => {
+                    *sample = s;
+                    written += 1;
+                }
+                None => break,
+            }
+        }
+
+        out[written..].fill(0f32);
+
+        written.div_ceil(channel_count)
+    }
+}
+
+impl Drop for EngineController {
+    /// Stops the output stream, which in turn stops the transport.
+    fn drop(&mut self) {
+        self.closing.store(true, Ordering::Release);
+
+        if let Some(thread) = self.stream_thread.lock().take() {
+            thread.unpark();
+        }
+    }
 }
 
 struct Sources {
@@ -200,13 +345,15 @@ impl Iterator for Sources {
 
     #[inline]
     fn next(&mut self) -> Option<f32> {
-        let mut samp = 0f32;
+        // Summed at double precision and only clipped once at the end, so the
+        // result doesn't depend on the order the sources are added in
+        let mut samp = 0f64;
         let mut full_decoders = self.source_readers.len();
 
         for source in self.source_readers.iter_mut() {
             match source.next() {
                 Some(sample) => {
-                    samp = (samp + sample).clamp(-1f32, 1f32);
+                    samp += sample as f64;
                 }
                 None => {
                     full_decoders = full_decoders - 1;
@@ -218,24 +365,28 @@ impl Iterator for Sources {
             };
         }
 
-        Some(samp)
+        Some(soft_clip(samp) as f32)
     }
 }
 
-#[derive(Debug, Clone)]
-enum EngineState {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineState {
     Idle,
     Playing,
     Paused,
 }
 
-#[derive(Debug)]
-enum EngineCommand {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineCommand {
     Play,
     Pause,
+    /// Stop and rewind to the start
+    Stop,
+    /// Move the playhead to a frame on the timeline
+    Seek(u64),
 }
 
-struct EngineStateMachine {
+pub(crate) struct EngineStateMachine {
     state: EngineState,
 }
 
@@ -246,16 +397,126 @@ impl EngineStateMachine {
         }
     }
 
+    pub fn state(&self) -> EngineState {
+        self.state
+    }
+
     pub fn next(&mut self, comm: EngineCommand) {
-        self.state = match (self.state.clone(), comm) {
+        self.state = match (self.state, comm) {
             (EngineState::Idle, EngineCommand::Play) => EngineState::Playing,
             (EngineState::Playing, EngineCommand::Pause) => EngineState::Paused,
             (EngineState::Paused, EngineCommand::Play) => EngineState::Playing,
+            (_, EngineCommand::Stop) => EngineState::Idle,
             (s, _) => s,
         };
     }
+}
+
+#[cfg(test)]
+mod engine_tests {
+    use crate::backend::{CaptureBackend, Pacing};
+    use crate::builder::{ChannelModel, ClipModel, MixerModel, PlaybackBuilder};
+    use crate::engine::*;
+    use cpal::{BufferSize, SampleRate, StreamConfig};
+    use std::time::Duration;
+
+    const PATH: &str = "sounds/sample-2.wav";
+
+    #[test]
+    fn load_rejects_a_different_config() {
+        let (backend, _) = CaptureBackend::new(44100, 1, 64, Pacing::Fast);
+        let controller = EngineController::with_backend(Box::new(backend)).unwrap();
+        let load = |channels, sample_rate| {
+            let config = StreamConfig {
+                channels,
+                sample_rate: SampleRate(sample_rate),
+                buffer_size: BufferSize::Fixed(64),
+            };
+            controller.load(PlaybackBuilder::new(&MixerModel::default(), config).unwrap())
+        };
+
+        assert!(matches!(load(2, 44100), Err(Error::PlaybackMismatch(_))));
+        assert!(matches!(load(1, 48000), Err(Error::PlaybackMismatch(_))));
+        assert!(load(1, 44100).is_ok());
+    }
+
+    #[test]
+    fn plays_through_a_capture_backend() {
+        let (backend, capture) = CaptureBackend::new(44100, 1, 64, Pacing::Fast);
+        let controller = EngineController::with_backend(Box::new(backend)).unwrap();
+
+        let mixer = MixerModel {
+            channels: vec![ChannelModel {
+                id: "chan-1".to_string(),
+                clips: vec![ClipModel {
+                    path: PATH.to_string(),
+                    duration_ms: 1000,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config = StreamConfig {
+            channels: 1,
+            sample_rate: SampleRate(44100),
+            buffer_size: BufferSize::Fixed(256),
+        };
+
+        controller
+            .load(PlaybackBuilder::new(&mixer, config).unwrap())
+            .unwrap();
+        controller.seek(1000);
+        controller.play().unwrap();
+
+        assert!(capture.wait_for(88200, Duration::from_secs(10)));
+        assert_eq!(controller.state(), EngineState::Playing);
+
+        // Silence until the transport picked up the commands, then the clip
+        // from where it was seeked to
+        let source: Vec<f32> = Symphonia::new(PATH.to_string()).unwrap().collect();
+        let captured = capture.samples();
+        let start = captured.iter().position(|s| *s != 0f32).unwrap();
+        let expected_start = source[1000..].iter().position(|s| *s != 0f32).unwrap();
+        let start = start - expected_start;
+
+        assert!(captured[..start].iter().all(|s| *s == 0f32));
+        assert_eq!(captured[start..start + 43100], source[1000..44100]);
+
+        let meters = controller.meters().unwrap();
+        assert_eq!(meters.channels.len(), 1);
+        assert!(meters.master.levels()[0].peak_hold > 0f32);
+
+        controller.pause();
+        assert!(capture.wait_for(captured.len() + 44100, Duration::from_secs(10)));
+        assert_eq!(controller.state(), EngineState::Paused);
+    }
+
+    #[test]
+    fn state_machine_transitions() {
+        let mut machine = EngineStateMachine::new();
+        assert_eq!(machine.state(), EngineState::Idle);
+
+        machine.next(EngineCommand::Pause);
+        assert_eq!(machine.state(), EngineState::Idle);
+
+        machine.next(EngineCommand::Play);
+        assert_eq!(machine.state(), EngineState::Playing);
+
+        machine.next(EngineCommand::Seek(100));
+        assert_eq!(machine.state(), EngineState::Playing);
+
+        machine.next(EngineCommand::Pause);
+        assert_eq!(machine.state(), EngineState::Paused);
+
+        machine.next(EngineCommand::Play);
+        assert_eq!(machine.state(), EngineState::Playing);
+
+        machine.next(EngineCommand::Stop);
+        assert_eq!(machine.state(), EngineState::Idle);
 
-    pub fn play(&mut self) {
-        self.next(EngineCommand::Play);
+        machine.next(EngineCommand::Pause);
+        machine.next(EngineCommand::Stop);
+        assert_eq!(machine.state(), EngineState::Idle);
     }
 }
@@ -0,0 +1,505 @@
+use std::f64::consts::PI;
+
+use crate::{
+    mixer::Processor,
+    param::{Param, ParamHandle, ParamInfo, Smoothing, Unit},
+};
+
+/// What an EQ band does to the frequencies around its own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BandKind {
+    /// Raises or cuts everything below the frequency
+    LowShelf,
+    /// Raises or cuts everything above the frequency
+    HighShelf,
+    /// Raises or cuts a bell around the frequency, as wide as the Q says
+    #[default]
+    Peak,
+    /// Cuts everything above the frequency
+    LowPass,
+    /// Cuts everything below the frequency
+    HighPass,
+    /// Cuts a narrow band around the frequency right out
+    Notch,
+}
+
+/// How steeply a low or high-pass band cuts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Slope {
+    #[default]
+    Db12,
+    Db24,
+    Db36,
+    Db48,
+}
+
+impl Slope {
+    /// Second order sections it takes
+    fn stages(&self) -> usize {
+        match self {
+            Slope::Db12 => 1,
+            Slope::Db24 => 2,
+            Slope::Db36 => 3,
+            Slope::Db48 => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EqBand {
+    pub kind: BandKind,
+    pub frequency: f32,
+    /// Only used by shelves and peaks
+    pub gain_db: f32,
+    /// Width of peaks and notches and the resonance of shelves and passes.
+    /// Steeper than 12 dB passes are Butterworth and ignore it.
+    pub q: f32,
+    /// Only used by passes
+    pub slope: Slope,
+}
+
+impl Default for EqBand {
+    fn default() -> Self {
+        EqBand {
+            kind: BandKind::Peak,
+            frequency: 1000f32,
+            gain_db: 0f32,
+            q: std::f32::consts::FRAC_1_SQRT_2,
+            slope: Slope::Db12,
+        }
+    }
+}
+
+const MIN_FREQUENCY: f32 = 10f32;
+const MAX_FREQUENCY: f32 = 22000f32;
+
+/// One state variable filter section (Andrew Simper's trapezoidal SVF). Its
+/// state is the charge on two integrators rather than past samples, so it
+/// stays well behaved when the coefficients change from one sample to the
+/// next, unlike a direct form biquad.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Coefficients {
+    g: f64,
+    k: f64,
+    a1: f64,
+    a2: f64,
+    a3: f64,
+    // How much of the input, band-pass and low-pass make up the output
+    m0: f64,
+    m1: f64,
+    m2: f64,
+}
+
+impl Coefficients {
+    fn new(kind: BandKind, frequency: f64, gain_db: f64, q: f64, sample_rate: u32) -> Self {
+        let frequency = frequency.min(sample_rate as f64 * 0.49);
+        let g = (PI * frequency / sample_rate as f64).tan();
+        let k = 1f64 / q;
+        let a = 10f64.powf(gain_db / 40f64);
+
+        let (g, k, m0, m1, m2) = match kind {
+            BandKind::LowPass => (g, k, 0f64, 0f64, 1f64),
+            BandKind::HighPass => (g, k, 1f64, -k, -1f64),
+            BandKind::Notch => (g, k, 1f64, -k, 0f64),
+            BandKind::Peak => {
+                let k = 1f64 / (q * a);
+                (g, k, 1f64, k * (a * a - 1f64), 0f64)
+            }
+            BandKind::LowShelf => (g / a.sqrt(), k, 1f64, k * (a - 1f64), a * a - 1f64),
+            BandKind::HighShelf => (g * a.sqrt(), k, a * a, k * (1f64 - a) * a, 1f64 - a * a),
+        };
+
+        let a1 = 1f64 / (1f64 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        Coefficients {
+            g,
+            k,
+            a1,
+            a2,
+            a3,
+            m0,
+            m1,
+            m2,
+        }
+    }
+
+    /// Gain at `frequency`, as the magnitude of the section's transfer
+    /// function `(m0 (s² + ks + 1) + m1 s + m2) / (s² + ks + 1)` with `s`
+    /// prewarped the same way the filter is.
+    fn magnitude(&self, frequency: f64, sample_rate: u32) -> f64 {
+        let frequency = frequency.min(sample_rate as f64 * 0.4999);
+        let w = (PI * frequency / sample_rate as f64).tan() / self.g;
+
+        // s = jw, so s² = -w²
+        let (den_re, den_im) = (1f64 - w * w, self.k * w);
+        let (num_re, num_im) = (self.m0 * den_re + self.m2, self.m0 * den_im + self.m1 * w);
+
+        (num_re.hypot(num_im)) / den_re.hypot(den_im)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct SectionState {
+    ic1eq: f64,
+    ic2eq: f64,
+}
+
+impl SectionState {
+    #[inline]
+    fn process(&mut self, c: &Coefficients, v0: f64) -> f64 {
+        let v3 = v0 - self.ic2eq;
+        let v1 = c.a1 * self.ic1eq + c.a2 * v3;
+        let v2 = self.ic2eq + c.a2 * self.ic1eq + c.a3 * v3;
+        self.ic1eq = 2f64 * v1 - self.ic1eq;
+        self.ic2eq = 2f64 * v2 - self.ic2eq;
+
+        c.m0 * v0 + c.m1 * v1 + c.m2 * v2
+    }
+}
+
+/// Q of each section of a Butterworth filter made of `stages` sections
+fn butterworth_q(stages: usize, stage: usize) -> f64 {
+    let order = 2 * stages;
+    1f64 / (2f64 * ((2 * stage + 1) as f64 * PI / (2 * order) as f64).cos())
+}
+
+/// The sections a band is made of.
+fn sections(band: &EqBand, sample_rate: u32) -> Vec<Coefficients> {
+    let stages = match band.kind {
+        BandKind::LowPass | BandKind::HighPass => band.slope.stages(),
+        _ => 1,
+    };
+
+    let mut sections = vec![Coefficients::default(); stages];
+    update_sections(band, sample_rate, &mut sections);
+    sections
+}
+
+/// Works `band`'s sections out again into `out`, which already holds one per
+/// section. Doesn't allocate, so it can run on the audio thread.
+fn update_sections(band: &EqBand, sample_rate: u32, out: &mut [Coefficients]) {
+    let stages = out.len();
+
+    for (stage, section) in out.iter_mut().enumerate() {
+        let q = if stages == 1 {
+            band.q as f64
+        } else {
+            butterworth_q(stages, stage)
+        };
+
+        *section = Coefficients::new(
+            band.kind,
+            band.frequency as f64,
+            band.gain_db as f64,
+            q,
+            sample_rate,
+        );
+    }
+}
+
+/// Gain in dB of `bands` together at each of `frequencies`, for plotting.
+pub fn frequency_response(bands: &[EqBand], sample_rate: u32, frequencies: &[f32]) -> Vec<f32> {
+    let sections: Vec<Coefficients> = bands
+        .iter()
+        .flat_map(|band| sections(band, sample_rate))
+        .collect();
+
+    frequencies
+        .iter()
+        .map(|frequency| {
+            let magnitude: f64 = sections
+                .iter()
+                .map(|section| section.magnitude(*frequency as f64, sample_rate))
+                .product();
+
+            (20f64 * magnitude.log10()) as f32
+        })
+        .collect()
+}
+
+struct Band {
+    kind: BandKind,
+    slope: Slope,
+    frequency: Param,
+    gain_db: Param,
+    q: Param,
+    sections: Vec<Coefficients>,
+    // Per section, per channel
+    states: Vec<SectionState>,
+}
+
+impl Band {
+    fn new(index: usize, band: &EqBand) -> Self {
+        let param = |name: &str, unit, min, max, default, smoothing| {
+            let info = ParamInfo {
+                name: format!("Band {} {}", index + 1, name),
+                unit,
+                min,
+                max,
+                default,
+                smoothing,
+            };
+            Param::with_value(info, default)
+        };
+
+        Band {
+            kind: band.kind,
+            slope: band.slope,
+            frequency: param(
+                "Frequency",
+                Unit::Hertz,
+                MIN_FREQUENCY,
+                MAX_FREQUENCY,
+                band.frequency,
+                Smoothing::Exponential(20f32),
+            ),
+            gain_db: param(
+                "Gain",
+                Unit::Decibels,
+                -24f32,
+                24f32,
+                band.gain_db,
+                Smoothing::default(),
+            ),
+            q: param("Q", Unit::None, 0.1, 18f32, band.q, Smoothing::default()),
+            sections: Vec::new(),
+            states: Vec::new(),
+        }
+    }
+
+    fn band(&self) -> EqBand {
+        EqBand {
+            kind: self.kind,
+            frequency: self.frequency.value(),
+            gain_db: self.gain_db.value(),
+            q: self.q.value(),
+            slope: self.slope,
+        }
+    }
+
+    fn is_smoothing(&self) -> bool {
+        self.frequency.is_smoothing() || self.gain_db.is_smoothing() || self.q.is_smoothing()
+    }
+
+    /// Moves the parameters one frame on and works the sections out again.
+    fn update(&mut self, sample_rate: u32) {
+        self.frequency.next_value();
+        self.gain_db.next_value();
+        self.q.next_value();
+
+        update_sections(&self.band(), sample_rate, &mut self.sections);
+    }
+}
+
+/// A parametric EQ: any number of bands, run one after the other.
+pub struct ParametricEq {
+    bands: Vec<Band>,
+    sample_rate: u32,
+    channel_count: usize,
+}
+
+impl ParametricEq {
+    pub fn new(bands: &[EqBand]) -> Self {
+        ParametricEq {
+            bands: bands
+                .iter()
+                .enumerate()
+                .map(|(index, band)| Band::new(index, band))
+                .collect(),
+            sample_rate: 44100,
+            channel_count: 1,
+        }
+    }
+
+    /// The bands as they are set now.
+    pub fn bands(&self) -> Vec<EqBand> {
+        self.bands.iter().map(|band| band.band()).collect()
+    }
+
+    /// Gain in dB of the EQ as it is set now at each of `frequencies`.
+    pub fn response(&self, frequencies: &[f32]) -> Vec<f32> {
+        frequency_response(&self.bands(), self.sample_rate, frequencies)
+    }
+}
+
+impl Processor for ParametricEq {
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize, _max_block_frames: usize) {
+        self.sample_rate = sample_rate;
+        self.channel_count = channel_count;
+
+        for band in self.bands.iter_mut() {
+            band.frequency.prepare(sample_rate);
+            band.gain_db.prepare(sample_rate);
+            band.q.prepare(sample_rate);
+
+            band.sections = sections(&band.band(), sample_rate);
+            band.states = vec![SectionState::default(); band.sections.len() * channel_count];
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        let channel_count = self.channel_count;
+
+        for frame in samples.chunks_exact_mut(channel_count) {
+            for band in self.bands.iter_mut() {
+                if band.is_smoothing() {
+                    band.update(self.sample_rate);
+                }
+
+                for (section, states) in band
+                    .sections
+                    .iter()
+                    .zip(band.states.chunks_exact_mut(channel_count))
+                {
+                    for (sample, state) in frame.iter_mut().zip(states.iter_mut()) {
+                        *sample = state.process(section, *sample as f64) as f32;
+                    }
+                }
+            }
+        }
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        self.bands
+            .iter()
+            .flat_map(|band| {
+                [
+                    band.frequency.handle(),
+                    band.gain_db.handle(),
+                    band.q.handle(),
+                ]
+            })
+            .collect()
+    }
+
+    fn reset(&mut self) {
+        for band in self.bands.iter_mut() {
+            band.frequency.reset();
+            band.gain_db.reset();
+            band.q.reset();
+            update_sections(&band.band(), self.sample_rate, &mut band.sections);
+            band.states.fill(SectionState::default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod eq_tests {
+    use crate::eq::*;
+
+    fn band(kind: BandKind, frequency: f32, gain_db: f32) -> EqBand {
+        EqBand {
+            kind,
+            frequency,
+            gain_db,
+            ..Default::default()
+        }
+    }
+
+    fn response(band: EqBand, frequency: f32) -> f32 {
+        frequency_response(&[band], 48000, &[frequency])[0]
+    }
+
+    /// Level in dB of a sine at `frequency` after running it through `eq`
+    fn measure(eq: &mut ParametricEq, frequency: f32) -> f32 {
+        eq.prepare(48000, 1, 512);
+
+        let sine: Vec<f32> = (0..48000)
+            .map(|i| (2f32 * std::f32::consts::PI * frequency * i as f32 / 48000f32).sin())
+            .collect();
+        let mut out = sine.clone();
+        for block in out.chunks_mut(512) {
+            eq.process(block);
+        }
+
+        // Skip the first half while the filter settles, and go by the RMS as
+        // the samples of high frequencies miss their peaks
+        let settled = &out[24000..];
+        let rms = (settled.iter().map(|s| s * s).sum::<f32>() / settled.len() as f32).sqrt();
+        20f32 * (rms * std::f32::consts::SQRT_2).log10()
+    }
+
+    #[test]
+    fn response_of_each_kind() {
+        let peak = band(BandKind::Peak, 1000f32, 6f32);
+        assert!((response(peak, 1000f32) - 6f32).abs() < 1e-3);
+        assert!(response(peak, 50f32).abs() < 0.1);
+
+        let low_shelf = band(BandKind::LowShelf, 200f32, -6f32);
+        assert!((response(low_shelf, 20f32) - -6f32).abs() < 0.1);
+        assert!(response(low_shelf, 10000f32).abs() < 0.1);
+
+        let high_shelf = band(BandKind::HighShelf, 5000f32, 6f32);
+        assert!((response(high_shelf, 20000f32) - 6f32).abs() < 0.2);
+
+        // Butterworth: 3 dB down at the cutoff
+        let low_pass = band(BandKind::LowPass, 1000f32, 0f32);
+        assert!((response(low_pass, 1000f32) - -3.01).abs() < 0.01);
+        assert!(response(low_pass, 100f32).abs() < 0.01);
+
+        let notch = band(BandKind::Notch, 1000f32, 0f32);
+        assert!(response(notch, 1000f32) < -100f32);
+    }
+
+    #[test]
+    fn steeper_slopes_cut_more() {
+        let cut = |slope| {
+            let band = EqBand {
+                kind: BandKind::HighPass,
+                frequency: 1000f32,
+                slope,
+                ..Default::default()
+            };
+            (response(band, 1000f32), response(band, 250f32))
+        };
+
+        for (slope, per_octave) in [
+            (Slope::Db12, 12f32),
+            (Slope::Db24, 24f32),
+            (Slope::Db48, 48f32),
+        ] {
+            let (at_cutoff, two_octaves_down) = cut(slope);
+            assert!((at_cutoff - -3.01).abs() < 0.01);
+            assert!((two_octaves_down - -2f32 * per_octave).abs() < 1f32);
+        }
+    }
+
+    #[test]
+    fn filters_match_their_response() {
+        let bands = [
+            band(BandKind::Peak, 1000f32, 9f32),
+            band(BandKind::HighShelf, 8000f32, -6f32),
+        ];
+        let mut eq = ParametricEq::new(&bands);
+
+        for frequency in [100f32, 1000f32, 12000f32] {
+            let expected = eq.response(&[frequency])[0];
+            assert!((measure(&mut eq, frequency) - expected).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn stays_stable_while_sweeping() {
+        let mut eq = ParametricEq::new(&[EqBand {
+            kind: BandKind::LowPass,
+            q: 10f32,
+            ..Default::default()
+        }]);
+        eq.prepare(48000, 2, 64);
+        let frequency = eq.params()[0].clone();
+
+        // Noise-ish input with the cutoff jumping around every block
+        let mut samples = vec![0f32; 128];
+        for block in 0..2000 {
+            for (i, sample) in samples.iter_mut().enumerate() {
+                *sample = (((block * 128 + i) * 7919 % 1000) as f32 / 500f32) - 1f32;
+            }
+            frequency.set(if block % 2 == 0 { 50f32 } else { 20000f32 });
+            eq.process(&mut samples);
+
+            assert!(samples.iter().all(|s| s.is_finite() && s.abs() < 100f32));
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::{fmt, io};
+
+/// Everything that can go wrong opening files and devices and setting up
+/// playback.
+#[derive(Debug)]
+pub enum Error {
+    /// There is no file at this path
+    FileNotFound(String),
+    /// Reading a file failed for some other reason
+    Io(io::Error),
+    /// The file isn't audio we can read, or uses a codec we don't have
+    UnsupportedFormat(String),
+    /// The file looked fine but its audio couldn't be decoded
+    Decode(String),
+    /// A seek went past the end of a source
+    SeekOutOfRange(u64),
+    /// The audio host or output device asked for isn't there
+    DeviceUnavailable(String),
+    /// The device can't play the config asked for
+    UnsupportedStreamConfig(String),
+    /// The output stream couldn't be built or started
+    Stream(String),
+    /// The engine's output stream can only be started once
+    StreamAlreadyStarted,
+    /// The resampler couldn't be made for these sample rates
+    Resampler(String),
+    /// Something outputs or sends to a bus that doesn't exist
+    UnknownBus(String),
+    /// More than one bus has this id
+    DuplicateBus(String),
+    /// These buses feed back into each other
+    RoutingCycle(Vec<String>),
+    /// Automation points at an insert or parameter that doesn't exist
+    UnknownParam(String),
+    /// A sidechain comes from a channel that doesn't exist
+    UnknownChannel(String),
+    /// A playback was built for a different channel count or sample rate
+    /// than the output plays at
+    PlaybackMismatch(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Turns an error opening `path` into `FileNotFound` if that's what it was
+    pub(crate) fn open(path: &str, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Error::FileNotFound(path.to_string()),
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::FileNotFound(path) => write!(f, "no file at {}", path),
+            Error::Io(err) => write!(f, "io error: {}", err),
+            Error::UnsupportedFormat(reason) => write!(f, "unsupported format: {}", reason),
+            Error::Decode(reason) => write!(f, "could not decode: {}", reason),
+            Error::SeekOutOfRange(frame) => write!(f, "frame {} is past the end", frame),
+            Error::DeviceUnavailable(reason) => write!(f, "device unavailable: {}", reason),
+            Error::UnsupportedStreamConfig(reason) => {
+                write!(f, "unsupported stream config: {}", reason)
+            }
+            Error::Stream(reason) => write!(f, "output stream error: {}", reason),
+            Error::StreamAlreadyStarted => write!(f, "the output stream was already started"),
+            Error::Resampler(reason) => write!(f, "could not make resampler: {}", reason),
+            Error::UnknownBus(id) => write!(f, "there is no bus {}", id),
+            Error::DuplicateBus(id) => write!(f, "more than one bus has the id {}", id),
+            Error::RoutingCycle(ids) => {
+                write!(f, "buses feed back into each other: {}", ids.join(", "))
+            }
+            Error::UnknownParam(name) => write!(f, "there is no parameter {}", name),
+            Error::UnknownChannel(id) => write!(f, "there is no channel {}", id),
+            Error::PlaybackMismatch(reason) => {
+                write!(f, "playback doesn't match the output: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<symphonia::core::errors::Error> for Error {
+    fn from(err: symphonia::core::errors::Error) -> Self {
+        use symphonia::core::errors::Error as SymphoniaError;
+
+        match err {
+            SymphoniaError::IoError(err) => Error::Io(err),
+            SymphoniaError::Unsupported(reason) => Error::UnsupportedFormat(reason.to_string()),
+            err => Error::Decode(err.to_string()),
+        }
+    }
+}
+
+impl From<hound::Error> for Error {
+    fn from(err: hound::Error) -> Self {
+        match err {
+            hound::Error::IoError(err) => Error::Io(err),
+            hound::Error::FormatError(reason) => Error::UnsupportedFormat(reason.to_string()),
+            err => Error::UnsupportedFormat(err.to_string()),
+        }
+    }
+}
+
+impl From<cpal::DevicesError> for Error {
+    fn from(err: cpal::DevicesError) -> Self {
+        Error::DeviceUnavailable(err.to_string())
+    }
+}
+
+impl From<cpal::DefaultStreamConfigError> for Error {
+    fn from(err: cpal::DefaultStreamConfigError) -> Self {
+        match err {
+            cpal::DefaultStreamConfigError::StreamTypeNotSupported => {
+                Error::UnsupportedStreamConfig(err.to_string())
+            }
+            err => Error::DeviceUnavailable(err.to_string()),
+        }
+    }
+}
+
+impl From<cpal::SupportedStreamConfigsError> for Error {
+    fn from(err: cpal::SupportedStreamConfigsError) -> Self {
+        Error::DeviceUnavailable(err.to_string())
+    }
+}
+
+impl From<cpal::HostUnavailable> for Error {
+    fn from(err: cpal::HostUnavailable) -> Self {
+        Error::DeviceUnavailable(err.to_string())
+    }
+}
+
+impl From<cpal::BuildStreamError> for Error {
+    fn from(err: cpal::BuildStreamError) -> Self {
+        match err {
+            cpal::BuildStreamError::DeviceNotAvailable => Error::DeviceUnavailable(err.to_string()),
+            cpal::BuildStreamError::StreamConfigNotSupported
+            | cpal::BuildStreamError::InvalidArgument => {
+                Error::UnsupportedStreamConfig(err.to_string())
+            }
+            err => Error::Stream(err.to_string()),
+        }
+    }
+}
+
+impl From<cpal::PlayStreamError> for Error {
+    fn from(err: cpal::PlayStreamError) -> Self {
+        match err {
+            cpal::PlayStreamError::DeviceNotAvailable => Error::DeviceUnavailable(err.to_string()),
+            err => Error::Stream(err.to_string()),
+        }
+    }
+}
+
+impl From<rubato::ResamplerConstructionError> for Error {
+    fn from(err: rubato::ResamplerConstructionError) -> Self {
+        Error::Resampler(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use crate::error::*;
+
+    #[test]
+    fn missing_file_is_not_found() {
+        let err = std::fs::File::open("sounds/missing.wav").unwrap_err();
+
+        assert!(matches!(
+            Error::open("sounds/missing.wav", err),
+            Error::FileNotFound(path) if path == "sounds/missing.wav"
+        ));
+    }
+
+    #[test]
+    fn symphonia_errors_are_sorted() {
+        use symphonia::core::errors::Error as SymphoniaError;
+
+        assert!(matches!(
+            Error::from(SymphoniaError::Unsupported(
+                "core (probe): no suitable format reader found"
+            )),
+            Error::UnsupportedFormat(_)
+        ));
+        assert!(matches!(
+            Error::from(SymphoniaError::DecodeError("bad header")),
+            Error::Decode(_)
+        ));
+    }
+}
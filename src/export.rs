@@ -0,0 +1,617 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+};
+
+use cpal::StreamConfig;
+use hound::{WavSpec, WavWriter};
+
+use crate::{
+    builder::{MixerModel, PlaybackBuilder},
+    dither::{Dither, Ditherer},
+    flac::FlacWriter,
+    loudness::LoudnessMeter,
+    render::{OfflineRenderer, RenderSink},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Wav,
+    Flac,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+}
+
+/// A loudness to export at. The gain is the same for the whole export, so
+/// the dynamics are untouched, and is held back if it would push the true
+/// peak over the ceiling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Normalize {
+    /// Integrated loudness in LUFS
+    pub target_lufs: f64,
+    /// Highest true peak allowed in dBTP
+    pub true_peak_ceiling_db: f64,
+}
+
+impl Default for Normalize {
+    fn default() -> Self {
+        Normalize {
+            target_lufs: -14f64,
+            true_peak_ceiling_db: -1f64,
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Wav => "wav",
+            ExportFormat::Flac => "flac",
+        }
+    }
+}
+
+impl BitDepth {
+    pub fn bits(&self) -> u32 {
+        match self {
+            BitDepth::Int16 => 16,
+            BitDepth::Int24 => 24,
+            BitDepth::Int32 | BitDepth::Float32 => 32,
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, BitDepth::Float32)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    pub bit_depth: BitDepth,
+    /// Only used for integer bit depths. Float output is written as is.
+    pub dither: Dither,
+    /// Turns the whole export up or down to hit a loudness target
+    pub normalize: Option<Normalize>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            format: ExportFormat::Wav,
+            bit_depth: BitDepth::Int24,
+            dither: Dither::Triangular,
+            normalize: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    /// The mixer model couldn't be built into a playback
+    Playback(crate::error::Error),
+    /// The format can't store samples of this bit depth
+    UnsupportedBitDepth(ExportFormat, BitDepth),
+    /// More than one channel has this id, so their stems would overwrite each
+    /// other
+    DuplicateStem(String),
+    /// A channel's id can't be used as a file name, e.g. it is empty or has a
+    /// path separator in it
+    InvalidStemName(String),
+    Io(io::Error),
+    Wav(hound::Error),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Playback(err) => {
+                write!(f, "could not build playback from the mixer: {}", err)
+            }
+            ExportError::UnsupportedBitDepth(format, bit_depth) => {
+                write!(f, "{:?} files can't be written as {:?}", format, bit_depth)
+            }
+            ExportError::DuplicateStem(id) => {
+                write!(f, "more than one channel has the id {}", id)
+            }
+            ExportError::InvalidStemName(id) => {
+                write!(f, "channel id {:?} can't be used as a stem file name", id)
+            }
+            ExportError::Io(err) => write!(f, "io error: {}", err),
+            ExportError::Wav(err) => write!(f, "wav error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<crate::error::Error> for ExportError {
+    fn from(err: crate::error::Error) -> Self {
+        ExportError::Playback(err)
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> Self {
+        ExportError::Io(err)
+    }
+}
+
+impl From<hound::Error> for ExportError {
+    fn from(err: hound::Error) -> Self {
+        ExportError::Wav(err)
+    }
+}
+
+/// Renders `mixer` offline and measures the loudness of the mix.
+pub fn measure_loudness(
+    mixer: &MixerModel,
+    config: StreamConfig,
+) -> Result<LoudnessMeter, ExportError> {
+    let playback = PlaybackBuilder::new(mixer, config.clone())?;
+    let mut meter = LoudnessMeter::new(config.sample_rate.0, config.channels as usize);
+
+    OfflineRenderer::new(playback)
+        .render_to(&mut meter)
+        .unwrap_or_else(|never| match never {});
+
+    Ok(meter)
+}
+
+/// Gain that takes `mixer` to the normalize target, which takes an extra
+/// render pass to measure. Silence is left alone.
+fn normalize_gain(
+    mixer: &MixerModel,
+    config: StreamConfig,
+    normalize: &Normalize,
+) -> Result<f32, ExportError> {
+    let meter = measure_loudness(mixer, config)?;
+    let integrated = meter.integrated();
+
+    if !integrated.is_finite() {
+        return Ok(1f32);
+    }
+
+    let gain_db = (normalize.target_lufs - integrated)
+        .min(normalize.true_peak_ceiling_db - meter.true_peak_db());
+
+    Ok(10f64.powf(gain_db / 20f64) as f32)
+}
+
+/// Renders `mixer` offline and writes the mix to `path`. Returns the number of
+/// frames written.
+pub fn export<P: AsRef<Path>>(
+    mixer: &MixerModel,
+    config: StreamConfig,
+    path: P,
+    options: &ExportOptions,
+) -> Result<u64, ExportError> {
+    let playback = PlaybackBuilder::new(mixer, config.clone())?;
+    let mut renderer = OfflineRenderer::new(playback);
+
+    let mut file = AudioFileWriter::create(
+        path,
+        config.channels as usize,
+        config.sample_rate.0,
+        options,
+    )?;
+    if let Some(normalize) = &options.normalize {
+        file.set_gain(normalize_gain(mixer, config.clone(), normalize)?);
+    }
+
+    let frames = renderer.render_to(&mut file)?;
+    file.finalize()?;
+
+    Ok(frames)
+}
+
+/// Renders `mixer` offline, writing the mix to `master_path` and every channel
+/// to its own file in `stem_dir` named after the channel's id (for example
+/// `stem_dir/drums.wav`). Everything comes from the same render pass, so the
+/// stems line up sample for sample with the mix. Normalizing turns the stems
+/// by the same amount as the mix, so they still add up to it. Returns the
+/// number of frames written to each file.
+pub fn export_stems<P: AsRef<Path>, D: AsRef<Path>>(
+    mixer: &MixerModel,
+    config: StreamConfig,
+    master_path: P,
+    stem_dir: D,
+    options: &ExportOptions,
+) -> Result<u64, ExportError> {
+    for (i, channel) in mixer.channels.iter().enumerate() {
+        let id = channel.id.as_str();
+
+        if !is_file_name(id) {
+            return Err(ExportError::InvalidStemName(id.to_string()));
+        }
+        if mixer.channels[..i].iter().any(|other| other.id == id) {
+            return Err(ExportError::DuplicateStem(id.to_string()));
+        }
+    }
+
+    let playback = PlaybackBuilder::new(mixer, config.clone())?;
+    let ids = playback.channel_ids();
+
+    let channel_count = config.channels as usize;
+    let sample_rate = config.sample_rate.0;
+
+    let mut master = AudioFileWriter::create(master_path, channel_count, sample_rate, options)?;
+    let mut stems = ids
+        .iter()
+        .map(|id| {
+            let path = stem_dir
+                .as_ref()
+                .join(format!("{}.{}", id, options.format.extension()));
+
+            AudioFileWriter::create(path, channel_count, sample_rate, options)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some(normalize) = &options.normalize {
+        let gain = normalize_gain(mixer, config.clone(), normalize)?;
+
+        master.set_gain(gain);
+        for stem in stems.iter_mut() {
+            stem.set_gain(gain);
+        }
+    }
+
+    let mut renderer = OfflineRenderer::new(playback);
+    let frames = renderer.render_stems_to(&mut master, &mut stems)?;
+
+    master.finalize()?;
+    for stem in stems {
+        stem.finalize()?;
+    }
+
+    Ok(frames)
+}
+
+/// Whether `id` names a file in the stem directory rather than somewhere
+/// else.
+fn is_file_name(id: &str) -> bool {
+    !id.is_empty() && id != "." && id != ".." && !id.contains(['/', '\\', '\0'])
+}
+
+enum Encoder {
+    Wav(WavWriter<BufWriter<File>>),
+    Flac(FlacWriter<BufWriter<File>>),
+}
+
+/// Writes interleaved float samples to a WAV or FLAC file, converting them to
+/// the requested bit depth on the way.
+pub struct AudioFileWriter {
+    encoder: Encoder,
+    bit_depth: BitDepth,
+    ditherer: Ditherer,
+    gain: f32,
+    channel_count: usize,
+    next_channel: usize,
+}
+
+impl AudioFileWriter {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        channel_count: usize,
+        sample_rate: u32,
+        options: &ExportOptions,
+    ) -> Result<Self, ExportError> {
+        let bit_depth = options.bit_depth;
+
+        let encoder = match options.format {
+            ExportFormat::Wav => {
+                let spec = WavSpec {
+                    channels: channel_count as u16,
+                    sample_rate,
+                    bits_per_sample: bit_depth.bits() as u16,
+                    sample_format: if bit_depth.is_float() {
+                        hound::SampleFormat::Float
+                    } else {
+                        hound::SampleFormat::Int
+                    },
+                };
+
+                Encoder::Wav(WavWriter::create(path, spec)?)
+            }
+            ExportFormat::Flac => {
+                if !matches!(bit_depth, BitDepth::Int16 | BitDepth::Int24) {
+                    return Err(ExportError::UnsupportedBitDepth(options.format, bit_depth));
+                }
+
+                let file = BufWriter::new(File::create(path)?);
+                Encoder::Flac(FlacWriter::new(
+                    file,
+                    channel_count,
+                    sample_rate,
+                    bit_depth.bits(),
+                )?)
+            }
+        };
+
+        Ok(AudioFileWriter {
+            encoder,
+            bit_depth,
+            ditherer: Ditherer::new(options.dither, bit_depth.bits(), channel_count),
+            gain: 1f32,
+            channel_count,
+            next_channel: 0,
+        })
+    }
+
+    /// Scales everything written from now on by `gain`.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    pub fn finalize(self) -> Result<(), ExportError> {
+        match self.encoder {
+            Encoder::Wav(writer) => writer.finalize()?,
+            Encoder::Flac(writer) => {
+                writer.finalize()?;
+            }
+        };
+
+        Ok(())
+    }
+}
+
+impl RenderSink for AudioFileWriter {
+    type Error = ExportError;
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), Self::Error> {
+        for sample in samples {
+            let sample = sample * self.gain;
+            let channel = self.next_channel;
+            self.next_channel = (self.next_channel + 1) % self.channel_count;
+
+            match (&mut self.encoder, self.bit_depth) {
+                (Encoder::Wav(writer), BitDepth::Float32) => writer.write_sample(sample)?,
+                (Encoder::Wav(writer), BitDepth::Int16) => {
+                    writer.write_sample(self.ditherer.quantize(channel, sample) as i16)?
+                }
+                (Encoder::Wav(writer), _) => {
+                    writer.write_sample(self.ditherer.quantize(channel, sample))?
+                }
+                (Encoder::Flac(writer), _) => {
+                    writer.write_sample(self.ditherer.quantize(channel, sample))?
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod export_tests {
+    use crate::builder::{ChannelModel, ClipModel, MixerModel, PlaybackBuilder};
+    use crate::dither::Dither;
+    use crate::export::*;
+    use crate::render::OfflineRenderer;
+    use crate::source::Source;
+    use crate::symph::Symphonia;
+    use cpal::{BufferSize, SampleRate, StreamConfig};
+
+    fn config() -> StreamConfig {
+        StreamConfig {
+            channels: 2,
+            sample_rate: SampleRate(44100),
+            buffer_size: BufferSize::Default,
+        }
+    }
+
+    fn mixer() -> MixerModel {
+        MixerModel {
+            channels: vec![ChannelModel {
+                id: "chan-1".to_string(),
+                clips: vec![ClipModel {
+                    path: "sounds/sample-2.wav".to_string(),
+                    start_time_ms: 0,
+                    duration_ms: 2000,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn rendered() -> Vec<f32> {
+        let playback = PlaybackBuilder::new(&mixer(), config()).unwrap();
+        let mut out = Vec::new();
+        OfflineRenderer::new(playback).render_to(&mut out).unwrap();
+
+        out
+    }
+
+    fn export_file(name: &str, format: ExportFormat, bit_depth: BitDepth) -> (String, u64) {
+        let path = std::env::temp_dir().join(name);
+        let options = ExportOptions {
+            format,
+            bit_depth,
+            dither: Dither::None,
+            normalize: None,
+        };
+
+        let frames = export(&mixer(), config(), &path, &options).unwrap();
+
+        (path.to_str().unwrap().to_string(), frames)
+    }
+
+    fn assert_matches_render(path: String, frames: u64, tolerance: f32) {
+        let rendered = rendered();
+        let source = Symphonia::new(path).unwrap();
+        assert_eq!(source.channels(), 2);
+        assert_eq!(source.sample_rate().0, 44100);
+
+        let read: Vec<f32> = source.collect();
+        assert_eq!(read.len() as u64, frames * 2);
+        assert_eq!(read.len(), rendered.len());
+
+        for (a, b) in read.iter().zip(rendered.iter()) {
+            assert!((a - b).abs() <= tolerance, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn exports_float_wav() {
+        let (path, frames) = export_file("export_float.wav", ExportFormat::Wav, BitDepth::Float32);
+        assert_matches_render(path, frames, f32::EPSILON);
+    }
+
+    #[test]
+    fn exports_int_wav() {
+        let (path, frames) = export_file("export_16.wav", ExportFormat::Wav, BitDepth::Int16);
+        assert_matches_render(path, frames, 1f32 / 32768f32);
+
+        let (path, frames) = export_file("export_24.wav", ExportFormat::Wav, BitDepth::Int24);
+        assert_matches_render(path, frames, 1f32 / 8388608f32);
+    }
+
+    #[test]
+    fn normalizes_to_a_loudness_target() {
+        let before = measure_loudness(&mixer(), config()).unwrap();
+
+        let path = std::env::temp_dir().join("export_normalized.wav");
+        let options = ExportOptions {
+            bit_depth: BitDepth::Float32,
+            normalize: Some(Normalize {
+                target_lufs: -30f64,
+                true_peak_ceiling_db: -1f64,
+            }),
+            ..Default::default()
+        };
+        export(&mixer(), config(), &path, &options).unwrap();
+
+        let read: Vec<f32> = Symphonia::new(path.to_str().unwrap().to_string())
+            .unwrap()
+            .collect();
+        let mut after = LoudnessMeter::new(44100, 2);
+        after.process(&read);
+
+        // Turned down to the target, or as far up as the ceiling allows
+        let expected = (-30f64).min(before.integrated() - before.true_peak_db() - 1f64);
+        assert!((after.integrated() - expected).abs() < 0.05);
+        assert!(after.true_peak_db() <= -1f64 + 0.05);
+    }
+
+    #[test]
+    fn flac_matches_wav() {
+        let (wav, _) = export_file("export_match.wav", ExportFormat::Wav, BitDepth::Int24);
+        let (flac, _) = export_file("export_match.flac", ExportFormat::Flac, BitDepth::Int24);
+
+        let wav: Vec<f32> = Symphonia::new(wav).unwrap().collect();
+        let flac: Vec<f32> = Symphonia::new(flac).unwrap().collect();
+
+        assert_eq!(wav, flac);
+    }
+
+    #[test]
+    fn exports_stems_next_to_master() {
+        let mut mixer = mixer();
+        mixer.channels.push(ChannelModel {
+            id: "chan-2".to_string(),
+            clips: vec![ClipModel {
+                path: "sounds/sample-1.wav".to_string(),
+                start_time_ms: 0,
+                duration_ms: 2000,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+
+        let dir = std::env::temp_dir().join("export_stems");
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = ExportOptions {
+            format: ExportFormat::Wav,
+            bit_depth: BitDepth::Float32,
+            dither: Dither::None,
+            normalize: None,
+        };
+
+        let frames =
+            export_stems(&mixer, config(), dir.join("master.wav"), &dir, &options).unwrap();
+
+        let read = |name: &str| -> Vec<f32> {
+            Symphonia::new(dir.join(name).to_str().unwrap().to_string())
+                .unwrap()
+                .collect()
+        };
+        let master = read("master.wav");
+        let one = read("chan-1.wav");
+        let two = read("chan-2.wav");
+
+        assert_eq!(master.len() as u64, frames * 2);
+        assert_eq!(one.len(), master.len());
+        assert_eq!(two.len(), master.len());
+        for i in 0..master.len() {
+            assert!((master[i] - (one[i] + two[i])).abs() <= f32::EPSILON);
+        }
+    }
+
+    #[test]
+    fn stems_need_unique_ids() {
+        let mut mixer = mixer();
+        mixer.channels.push(mixer.channels[0].clone());
+        let dir = std::env::temp_dir();
+
+        assert!(matches!(
+            export_stems(
+                &mixer,
+                config(),
+                dir.join("dupes.wav"),
+                &dir,
+                &ExportOptions::default()
+            ),
+            Err(ExportError::DuplicateStem(id)) if id == "chan-1"
+        ));
+    }
+
+    #[test]
+    fn stems_need_file_names() {
+        let dir = std::env::temp_dir();
+
+        for id in ["", "..", "../escape", "nested/stem", "back\\slash"] {
+            let mut mixer = mixer();
+            mixer.channels[0].id = id.to_string();
+
+            assert!(matches!(
+                export_stems(
+                    &mixer,
+                    config(),
+                    dir.join("bad_names.wav"),
+                    &dir,
+                    &ExportOptions::default()
+                ),
+                Err(ExportError::InvalidStemName(bad)) if bad == id
+            ));
+        }
+    }
+
+    #[test]
+    fn flac_rejects_float() {
+        let options = ExportOptions {
+            format: ExportFormat::Flac,
+            bit_depth: BitDepth::Float32,
+            dither: Dither::None,
+            normalize: None,
+        };
+        let path = std::env::temp_dir().join("export_float.flac");
+
+        assert!(matches!(
+            export(&mixer(), config(), path, &options),
+            Err(ExportError::UnsupportedBitDepth(
+                ExportFormat::Flac,
+                BitDepth::Float32
+            ))
+        ));
+    }
+}
@@ -0,0 +1,528 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Number of samples per channel in every frame but the last.
+const BLOCK_SIZE: usize = 4096;
+
+/// Smallest block size STREAMINFO can report.
+const MIN_BLOCK_SIZE: usize = 16;
+
+/// Highest rice parameter that can be written with a 4 bit parameter field.
+/// 0b1111 is reserved as the escape code.
+const MAX_RICE_PARAMETER: u32 = 14;
+
+/// The STREAMINFO block comes straight after the "fLaC" marker.
+const STREAMINFO_OFFSET: u64 = 4;
+
+/// A minimal FLAC encoder.
+///
+/// Every channel is coded independently with the best of FLAC's fixed
+/// polynomial predictors and a single rice partition. That gets most of the
+/// way to what the reference encoder does for a fraction of the code. No MD5
+/// of the audio is written, which the format allows.
+pub struct FlacWriter<W>
+where
+    W: Write + Seek,
+{
+    writer: W,
+    channel_count: usize,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    // One block of samples per channel, de-interleaved
+    block: Vec<Vec<i32>>,
+    next_channel: usize,
+    frame_number: u64,
+    total_samples: u64,
+    min_frame_size: u32,
+    max_frame_size: u32,
+    residuals: Vec<i32>,
+}
+
+impl<W> FlacWriter<W>
+where
+    W: Write + Seek,
+{
+    pub fn new(
+        mut writer: W,
+        channel_count: usize,
+        sample_rate: u32,
+        bits_per_sample: u32,
+    ) -> io::Result<Self> {
+        if !(1..=8).contains(&channel_count) {
+            return Err(invalid_input("FLAC supports between 1 and 8 channels"));
+        }
+
+        if bits_per_sample != 16 && bits_per_sample != 24 {
+            return Err(invalid_input("FLAC writer supports 16 and 24 bit samples"));
+        }
+
+        if sample_rate == 0 || sample_rate >= 1 << 20 {
+            return Err(invalid_input("sample rate can't be stored in FLAC"));
+        }
+
+        writer.write_all(b"fLaC")?;
+
+        let mut flac = FlacWriter {
+            writer,
+            channel_count,
+            sample_rate,
+            bits_per_sample,
+            block: vec![Vec::with_capacity(BLOCK_SIZE); channel_count],
+            next_channel: 0,
+            frame_number: 0,
+            total_samples: 0,
+            min_frame_size: 0,
+            max_frame_size: 0,
+            residuals: Vec::with_capacity(BLOCK_SIZE),
+        };
+
+        // Placeholder until the stream is finalized and we know the totals
+        flac.write_stream_info()?;
+
+        Ok(flac)
+    }
+
+    /// Writes the next sample. Samples are interleaved, so they are expected to
+    /// cycle through every channel.
+    pub fn write_sample(&mut self, sample: i32) -> io::Result<()> {
+        self.block[self.next_channel].push(sample);
+        self.next_channel += 1;
+
+        if self.next_channel == self.channel_count {
+            self.next_channel = 0;
+
+            if self.block[0].len() == BLOCK_SIZE {
+                self.write_frame()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes any buffered samples and fills in the stream totals. A partially
+    /// written frame (not every channel got its sample) is dropped.
+    pub fn finalize(mut self) -> io::Result<W> {
+        let block_len = self.block_len_written();
+        for channel in self.block.iter_mut() {
+            channel.truncate(block_len);
+        }
+
+        // The last block is allowed to be shorter than the 16 samples every
+        // other one has to be, so it is written as it is
+        if !self.block[0].is_empty() {
+            self.write_frame()?;
+        }
+
+        self.writer.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        self.write_stream_info()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn block_len_written(&self) -> usize {
+        self.block[self.channel_count - 1].len()
+    }
+
+    fn write_stream_info(&mut self) -> io::Result<()> {
+        // A stream made of a single short frame reports that frame's size,
+        // but no less than the 16 STREAMINFO allows
+        let block_size = if self.frame_number <= 1 && self.total_samples > 0 {
+            (self.total_samples as usize).max(MIN_BLOCK_SIZE) as u32
+        } else {
+            BLOCK_SIZE as u32
+        };
+
+        let mut bits = BitWriter::with_capacity(42);
+        // Last metadata block, type 0 (STREAMINFO), 34 bytes long
+        bits.write(1, 1);
+        bits.write(0, 7);
+        bits.write(34, 24);
+
+        bits.write(block_size as u64, 16);
+        bits.write(block_size as u64, 16);
+        bits.write(self.min_frame_size as u64, 24);
+        bits.write(self.max_frame_size as u64, 24);
+        bits.write(self.sample_rate as u64, 20);
+        bits.write(self.channel_count as u64 - 1, 3);
+        bits.write(self.bits_per_sample as u64 - 1, 5);
+        bits.write(self.total_samples, 36);
+        // Unknown MD5
+        bits.write(0, 64);
+        bits.write(0, 64);
+
+        self.writer.write_all(&bits.into_bytes())
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let block_len = self.block[0].len();
+        let mut bits = BitWriter::with_capacity(block_len * self.channel_count * 3);
+
+        // Sync code, reserved bit and the fixed block size strategy
+        bits.write(0b11_1111_1111_1110, 14);
+        bits.write(0, 1);
+        bits.write(0, 1);
+        // Block size as a 16 bit value at the end of the header
+        bits.write(0b0111, 4);
+        // Sample rate from STREAMINFO
+        bits.write(0b0000, 4);
+        // Independent channels
+        bits.write(self.channel_count as u64 - 1, 4);
+        bits.write(sample_size_code(self.bits_per_sample), 3);
+        bits.write(0, 1);
+        write_utf8(&mut bits, self.frame_number);
+        bits.write(block_len as u64 - 1, 16);
+
+        let crc = crc8(bits.bytes());
+        bits.write(crc as u64, 8);
+
+        for channel in 0..self.channel_count {
+            write_subframe(
+                &mut bits,
+                &self.block[channel],
+                self.bits_per_sample,
+                &mut self.residuals,
+            );
+        }
+
+        bits.align();
+        let crc = crc16(bits.bytes());
+        bits.write(crc as u64, 16);
+
+        let frame = bits.into_bytes();
+        let frame_size = frame.len() as u32;
+        self.writer.write_all(&frame)?;
+
+        if self.frame_number == 0 || frame_size < self.min_frame_size {
+            self.min_frame_size = frame_size;
+        }
+        self.max_frame_size = self.max_frame_size.max(frame_size);
+        self.frame_number += 1;
+        self.total_samples += block_len as u64;
+
+        for channel in self.block.iter_mut() {
+            channel.clear();
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn sample_size_code(bits_per_sample: u32) -> u64 {
+    match bits_per_sample {
+        16 => 0b100,
+        24 => 0b110,
+        _ => unreachable!("unsupported bits per sample"),
+    }
+}
+
+/// Writes a single channel of a frame as a fixed predictor subframe, picking
+/// whichever order leaves the smallest residual.
+fn write_subframe(
+    bits: &mut BitWriter,
+    samples: &[i32],
+    bits_per_sample: u32,
+    residuals: &mut Vec<i32>,
+) {
+    let max_order = 4.min(samples.len().saturating_sub(1));
+    let order = (0..=max_order)
+        .min_by_key(|order| residual_magnitude(samples, *order))
+        .unwrap_or(0);
+
+    fixed_residuals(samples, order, residuals);
+
+    // Subframe header: zero pad, SUBFRAME_FIXED with the order, no wasted bits
+    bits.write(0, 1);
+    bits.write(0b001000 | order as u64, 6);
+    bits.write(0, 1);
+
+    for warm_up in samples.iter().take(order) {
+        bits.write_signed(*warm_up as i64, bits_per_sample);
+    }
+
+    let parameter = rice_parameter(residuals);
+
+    // Rice coding with 4 bit parameters and a single partition
+    bits.write(0b00, 2);
+    bits.write(0, 4);
+    bits.write(parameter as u64, 4);
+
+    for residual in residuals.iter() {
+        bits.write_rice(*residual, parameter);
+    }
+}
+
+#[inline]
+fn predict(samples: &[i32], n: usize, order: usize) -> i64 {
+    let s = |i: usize| samples[n - i] as i64;
+
+    match order {
+        0 => 0,
+        1 => s(1),
+        2 => 2 * s(1) - s(2),
+        3 => 3 * s(1) - 3 * s(2) + s(3),
+        4 => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4),
+        _ => unreachable!("fixed predictors only go up to order 4"),
+    }
+}
+
+fn residual_magnitude(samples: &[i32], order: usize) -> u64 {
+    (order..samples.len())
+        .map(|n| (samples[n] as i64 - predict(samples, n, order)).unsigned_abs())
+        .sum()
+}
+
+fn fixed_residuals(samples: &[i32], order: usize, residuals: &mut Vec<i32>) {
+    residuals.clear();
+    residuals.extend(
+        (order..samples.len()).map(|n| (samples[n] as i64 - predict(samples, n, order)) as i32),
+    );
+}
+
+#[inline]
+fn fold(residual: i32) -> u64 {
+    ((residual << 1) ^ (residual >> 31)) as u32 as u64
+}
+
+/// Finds the rice parameter that codes `residuals` in the fewest bits.
+fn rice_parameter(residuals: &[i32]) -> u32 {
+    (0..=MAX_RICE_PARAMETER)
+        .min_by_key(|parameter| {
+            residuals
+                .iter()
+                .map(|r| (fold(*r) >> parameter) + 1 + *parameter as u64)
+                .sum::<u64>()
+        })
+        .unwrap_or(0)
+}
+
+/// Frame and sample numbers use the same variable length coding as UTF-8,
+/// extended to 36 bits.
+fn write_utf8(bits: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        bits.write(value, 8);
+        return;
+    }
+
+    let continuation_bytes = match value {
+        0..=0x7ff => 1,
+        0x800..=0xffff => 2,
+        0x1_0000..=0x1f_ffff => 3,
+        0x20_0000..=0x3ff_ffff => 4,
+        0x400_0000..=0x7fff_ffff => 5,
+        _ => 6,
+    };
+
+    let first_byte_marker = (0xff00u64 >> (continuation_bytes + 1)) & 0xff;
+    bits.write(first_byte_marker | (value >> (6 * continuation_bytes)), 8);
+
+    for i in (0..continuation_bytes).rev() {
+        bits.write(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Packs values MSB first into bytes.
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn with_capacity(capacity: usize) -> Self {
+        BitWriter {
+            bytes: Vec::with_capacity(capacity),
+            accumulator: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Writes the lowest `count` bits of `value`. `count` can be at most 64.
+    #[inline]
+    fn write(&mut self, value: u64, count: u32) {
+        if count > 32 {
+            self.write(value >> 32, count - 32);
+            self.write(value & 0xffff_ffff, 32);
+            return;
+        }
+
+        let value = value & ((1u64 << count) - 1);
+        self.accumulator = (self.accumulator << count) | value;
+        self.bit_count += count;
+
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            self.bytes.push((self.accumulator >> self.bit_count) as u8);
+        }
+    }
+
+    #[inline]
+    fn write_signed(&mut self, value: i64, count: u32) {
+        self.write(value as u64, count);
+    }
+
+    #[inline]
+    fn write_rice(&mut self, residual: i32, parameter: u32) {
+        let folded = fold(residual);
+        let mut quotient = folded >> parameter;
+
+        while quotient >= 32 {
+            self.write(0, 32);
+            quotient -= 32;
+        }
+
+        // Unary coded quotient followed by the stop bit and the remainder
+        self.write(1, quotient as u32 + 1);
+        self.write(folded, parameter);
+    }
+
+    /// Pads with zeros up to the next byte boundary.
+    fn align(&mut self) {
+        if self.bit_count > 0 {
+            self.write(0, 8 - self.bit_count);
+        }
+    }
+
+    /// The bytes written so far. Bits that don't make up a whole byte yet
+    /// aren't included.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod flac_tests {
+    use crate::flac::*;
+    use crate::source::Source;
+    use crate::symph::Symphonia;
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    fn write_file(name: &str, channels: usize, bits: u32, samples: &[i32]) -> String {
+        let path = std::env::temp_dir().join(name);
+        let file = BufWriter::new(File::create(&path).unwrap());
+        let mut writer = FlacWriter::new(file, channels, 44100, bits).unwrap();
+
+        for sample in samples {
+            writer.write_sample(*sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        path.to_str().unwrap().to_string()
+    }
+
+    fn read_file(path: String, bits: u32) -> Vec<i32> {
+        let scale = (1 << (bits - 1)) as f32;
+        Symphonia::new(path)
+            .unwrap()
+            .map(|s| (s * scale).round() as i32)
+            .collect()
+    }
+
+    #[test]
+    fn utf8_frame_numbers() {
+        let encode = |value| {
+            let mut bits = BitWriter::with_capacity(8);
+            write_utf8(&mut bits, value);
+            bits.into_bytes()
+        };
+
+        assert_eq!(encode(0x7f), vec![0x7f]);
+        assert_eq!(encode(0x80), vec![0xc2, 0x80]);
+        assert_eq!(encode(0x800), vec![0xe0, 0xa0, 0x80]);
+        assert_eq!(encode(0x1_0000), vec![0xf0, 0x90, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn round_trips_16_bit_stereo() {
+        // A couple of frames worth plus a short last frame
+        let samples: Vec<i32> = (0..(BLOCK_SIZE * 2 + 100) * 2)
+            .map(|i| ((i as f32 * 0.01).sin() * 20000f32) as i32 * if i % 2 == 0 { 1 } else { -1 })
+            .collect();
+
+        let path = write_file("flac_round_trip_16.flac", 2, 16, &samples);
+        let source = Symphonia::new(path.clone()).unwrap();
+        assert_eq!(source.channels(), 2);
+        assert_eq!(source.sample_rate().0, 44100);
+
+        assert_eq!(read_file(path, 16), samples);
+    }
+
+    #[test]
+    fn round_trips_24_bit_mono() {
+        let samples: Vec<i32> = (0..BLOCK_SIZE + 7)
+            .map(|i| (((i * 7919) % 16_000_000) as i32) - 8_000_000)
+            .collect();
+
+        let path = write_file("flac_round_trip_24.flac", 1, 24, &samples);
+
+        assert_eq!(read_file(path, 24), samples);
+    }
+
+    #[test]
+    fn very_short_stream_has_a_valid_block_size() {
+        let samples = [1, -2, 3, -4, 5];
+        let path = write_file("flac_very_short.flac", 1, 16, &samples);
+
+        // Minimum and maximum block size straight after the block header
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes[8..12], [0, 16, 0, 16]);
+
+        // The short frame comes back at its own length
+        assert_eq!(read_file(path, 16), samples);
+
+        // So does a single sample last frame after a full one
+        let samples: Vec<i32> = (0..BLOCK_SIZE as i32 + 1).map(|i| i % 7).collect();
+        let path = write_file("flac_short_last_frame.flac", 1, 16, &samples);
+        assert_eq!(read_file(path, 16), samples);
+    }
+
+    #[test]
+    fn rejects_unsupported_bit_depths() {
+        let file = std::io::Cursor::new(Vec::new());
+        assert!(FlacWriter::new(file, 2, 44100, 32).is_err());
+    }
+}
@@ -1,9 +1,32 @@
+pub mod automation;
+pub mod backend;
 pub mod builder;
 pub mod channer;
+pub mod convolution;
+pub mod delay;
+pub mod device;
+pub mod dither;
+pub mod dynamics;
 pub mod engine;
+pub mod eq;
+pub mod error;
+pub mod export;
+pub mod flac;
 pub mod frame;
+pub mod loudness;
+pub mod master;
+pub mod meter;
 pub mod mixer;
+pub mod param;
+pub mod playhead;
+pub mod render;
+pub mod reverb;
+pub mod ring_buffer;
+pub mod routing;
+pub mod sample_rate;
 pub mod source;
 pub mod source_reader;
+pub mod stats;
 pub mod symph;
 pub mod track;
+pub mod transport;
@@ -0,0 +1,508 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    f64::consts::PI,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::{meter::TruePeak, render::RenderSink};
+
+/// Loudness is measured in 100 ms steps
+const HOP_MS: u32 = 100;
+/// Steps in the 400 ms momentary window
+const MOMENTARY_HOPS: usize = 4;
+/// Steps in the 3 s short-term window
+const SHORT_TERM_HOPS: usize = 30;
+
+const ABSOLUTE_GATE: f64 = -70f64;
+/// Below the ungated loudness, for the integrated loudness
+const RELATIVE_GATE: f64 = -10f64;
+/// Below the ungated short-term loudness, for the loudness range
+const RANGE_RELATIVE_GATE: f64 = -20f64;
+
+/// Loudness the histograms cover, from the absolute gate up, in 0.1 LU bins
+const HISTOGRAM_BINS: usize = 1000;
+
+/// Loudness in LUFS of a K-weighted mean square.
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10f64 * power.log10()
+}
+
+/// Loudness measured as in ITU-R BS.1770-4 and EBU R128: momentary,
+/// short-term and integrated loudness, loudness range (EBU Tech 3342) and
+/// true peak.
+///
+/// Feed it interleaved audio with `process`, or render straight into it as a
+/// `RenderSink`. Loudness that hasn't been measured yet, or is silence, is
+/// negative infinity.
+pub struct LoudnessMeter {
+    channel_count: usize,
+    weights: Vec<f64>,
+    filters: Vec<KWeighting>,
+    oversamplers: Vec<TruePeak>,
+    hop_frames: usize,
+    // Frames and per channel sums of squares of the step being filled
+    hop_filled: usize,
+    hop_squares: Vec<f64>,
+    // Weighted mean squares of the last `SHORT_TERM_HOPS` steps, newest last
+    hops: VecDeque<f64>,
+    // 400 ms blocks, every 100 ms, for the integrated loudness
+    blocks: Histogram,
+    // Short-term loudness every 100 ms, for the loudness range
+    short_terms: Histogram,
+    true_peak: f32,
+    shared: Arc<SharedLoudness>,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channel_count: usize) -> Self {
+        LoudnessMeter {
+            channel_count,
+            weights: channel_weights(channel_count),
+            filters: (0..channel_count)
+                .map(|_| KWeighting::new(sample_rate))
+                .collect(),
+            oversamplers: vec![TruePeak::default(); channel_count],
+            hop_frames: (sample_rate * HOP_MS / 1000) as usize,
+            hop_filled: 0,
+            hop_squares: vec![0f64; channel_count],
+            hops: VecDeque::with_capacity(SHORT_TERM_HOPS + 1),
+            blocks: Histogram::new(),
+            short_terms: Histogram::new(),
+            true_peak: 0f32,
+            shared: Default::default(),
+        }
+    }
+
+    /// For reading the loudness from another thread while this one measures.
+    pub fn handle(&self) -> LoudnessHandle {
+        LoudnessHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Measures the next `samples`.
+    pub fn process(&mut self, samples: &[f32]) {
+        if self.shared.reset.swap(false, Ordering::Relaxed) {
+            self.reset();
+        }
+
+        for frame in samples.chunks_exact(self.channel_count) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let weighted = self.filters[channel].process(*sample as f64);
+                self.hop_squares[channel] += weighted * weighted;
+
+                let peak = self.oversamplers[channel].next(*sample);
+                self.true_peak = self.true_peak.max(peak.max(sample.abs()));
+            }
+
+            self.hop_filled += 1;
+            if self.hop_filled == self.hop_frames {
+                self.finish_hop();
+            }
+        }
+    }
+
+    fn finish_hop(&mut self) {
+        let power = self
+            .hop_squares
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(squares, weight)| weight * squares / self.hop_frames as f64)
+            .sum();
+
+        self.hop_squares.fill(0f64);
+        self.hop_filled = 0;
+
+        if self.hops.len() == SHORT_TERM_HOPS {
+            self.hops.pop_front();
+        }
+        self.hops.push_back(power);
+
+        if let Some(power) = self.window_power(MOMENTARY_HOPS) {
+            self.blocks.add(power);
+        }
+        if let Some(power) = self.window_power(SHORT_TERM_HOPS) {
+            self.short_terms.add(power);
+        }
+
+        self.publish();
+    }
+
+    /// Mean square of the last `hops` steps, once there are that many.
+    fn window_power(&self, hops: usize) -> Option<f64> {
+        if self.hops.len() < hops {
+            return None;
+        }
+
+        Some(self.hops.iter().rev().take(hops).sum::<f64>() / hops as f64)
+    }
+
+    fn publish(&self) {
+        let store =
+            |value: &AtomicU64, loudness: f64| value.store(loudness.to_bits(), Ordering::Relaxed);
+
+        store(&self.shared.momentary, self.momentary());
+        store(&self.shared.short_term, self.short_term());
+        store(&self.shared.integrated, self.integrated());
+        store(&self.shared.range, self.range());
+        store(&self.shared.true_peak, self.true_peak_db());
+    }
+
+    /// Loudness of the last 400 ms, in LUFS
+    pub fn momentary(&self) -> f64 {
+        self.window_power(MOMENTARY_HOPS)
+            .map_or(f64::NEG_INFINITY, lufs)
+    }
+
+    /// Loudness of the last 3 s, in LUFS
+    pub fn short_term(&self) -> f64 {
+        self.window_power(SHORT_TERM_HOPS)
+            .map_or(f64::NEG_INFINITY, lufs)
+    }
+
+    /// Gated loudness of everything measured, in LUFS
+    pub fn integrated(&self) -> f64 {
+        self.blocks
+            .mean_power_above(ABSOLUTE_GATE)
+            .and_then(|power| self.blocks.mean_power_above(lufs(power) + RELATIVE_GATE))
+            .map_or(f64::NEG_INFINITY, lufs)
+    }
+
+    /// Spread between the quiet and loud parts of everything measured
+    /// (the 10th to 95th percentile of the gated short-term loudness), in LU
+    pub fn range(&self) -> f64 {
+        let gate = match self.short_terms.mean_power_above(ABSOLUTE_GATE) {
+            Some(power) => lufs(power) + RANGE_RELATIVE_GATE,
+            None => return 0f64,
+        };
+
+        match (
+            self.short_terms.percentile(gate, 0.1),
+            self.short_terms.percentile(gate, 0.95),
+        ) {
+            (Some(low), Some(high)) => high - low,
+            _ => 0f64,
+        }
+    }
+
+    /// Highest true peak measured, in dBTP
+    pub fn true_peak_db(&self) -> f64 {
+        20f64 * (self.true_peak as f64).log10()
+    }
+
+    /// Starts measuring over.
+    pub fn reset(&mut self) {
+        for filter in self.filters.iter_mut() {
+            filter.reset();
+        }
+        for oversampler in self.oversamplers.iter_mut() {
+            *oversampler = TruePeak::default();
+        }
+
+        self.hop_filled = 0;
+        self.hop_squares.fill(0f64);
+        self.hops.clear();
+        self.blocks.clear();
+        self.short_terms.clear();
+        self.true_peak = 0f32;
+        self.publish();
+    }
+}
+
+impl RenderSink for LoudnessMeter {
+    type Error = Infallible;
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), Self::Error> {
+        self.process(samples);
+        Ok(())
+    }
+}
+
+struct SharedLoudness {
+    momentary: AtomicU64,
+    short_term: AtomicU64,
+    integrated: AtomicU64,
+    range: AtomicU64,
+    true_peak: AtomicU64,
+    reset: AtomicBool,
+}
+
+impl Default for SharedLoudness {
+    fn default() -> Self {
+        let silence = || AtomicU64::new(f64::NEG_INFINITY.to_bits());
+
+        SharedLoudness {
+            momentary: silence(),
+            short_term: silence(),
+            integrated: silence(),
+            range: AtomicU64::new(0f64.to_bits()),
+            true_peak: silence(),
+            reset: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Reads a `LoudnessMeter` from another thread. Updated every 100 ms of audio.
+#[derive(Clone)]
+pub struct LoudnessHandle {
+    shared: Arc<SharedLoudness>,
+}
+
+impl LoudnessHandle {
+    fn load(value: &AtomicU64) -> f64 {
+        f64::from_bits(value.load(Ordering::Relaxed))
+    }
+
+    pub fn momentary(&self) -> f64 {
+        Self::load(&self.shared.momentary)
+    }
+
+    pub fn short_term(&self) -> f64 {
+        Self::load(&self.shared.short_term)
+    }
+
+    pub fn integrated(&self) -> f64 {
+        Self::load(&self.shared.integrated)
+    }
+
+    pub fn range(&self) -> f64 {
+        Self::load(&self.shared.range)
+    }
+
+    pub fn true_peak_db(&self) -> f64 {
+        Self::load(&self.shared.true_peak)
+    }
+
+    /// Asks the meter to start over, which it does before its next block.
+    pub fn reset(&self) {
+        self.shared.reset.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Weight of each channel's loudness: surrounds count more, the LFE not at
+/// all (for 5.1 in the usual L R C LFE Ls Rs order).
+fn channel_weights(channel_count: usize) -> Vec<f64> {
+    match channel_count {
+        6 => vec![1f64, 1f64, 1f64, 0f64, 1.41, 1.41],
+        _ => vec![1f64; channel_count],
+    }
+}
+
+/// Counts of loudness values in 0.1 LU bins, along with the sum of their mean
+/// squares so the gated loudness comes out exact apart from where the gate
+/// falls inside a bin. Keeps memory fixed however long it measures.
+struct Histogram {
+    counts: Vec<u64>,
+    powers: Vec<f64>,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            counts: vec![0; HISTOGRAM_BINS],
+            powers: vec![0f64; HISTOGRAM_BINS],
+        }
+    }
+
+    fn bin(loudness: f64) -> usize {
+        (((loudness - ABSOLUTE_GATE) * 10f64).max(0f64) as usize).min(HISTOGRAM_BINS - 1)
+    }
+
+    fn bin_loudness(bin: usize) -> f64 {
+        ABSOLUTE_GATE + (bin as f64 + 0.5) / 10f64
+    }
+
+    /// Adds a value, unless it is quieter than the absolute gate.
+    fn add(&mut self, power: f64) {
+        let loudness = lufs(power);
+
+        if loudness > ABSOLUTE_GATE {
+            let bin = Self::bin(loudness);
+            self.counts[bin] += 1;
+            self.powers[bin] += power;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.counts.fill(0);
+        self.powers.fill(0f64);
+    }
+
+    /// Mean square of the values louder than `gate`.
+    fn mean_power_above(&self, gate: f64) -> Option<f64> {
+        let from = Self::bin(gate);
+        let count: u64 = self.counts[from..].iter().sum();
+        let power: f64 = self.powers[from..].iter().sum();
+
+        (count > 0).then(|| power / count as f64)
+    }
+
+    /// Loudness that `fraction` of the values louder than `gate` are below.
+    fn percentile(&self, gate: f64, fraction: f64) -> Option<f64> {
+        let from = Self::bin(gate);
+        let count: u64 = self.counts[from..].iter().sum();
+
+        if count == 0 {
+            return None;
+        }
+
+        let target = (fraction * (count - 1) as f64).round() as u64;
+        let mut seen = 0;
+
+        for bin in from..HISTOGRAM_BINS {
+            seen += self.counts[bin];
+            if seen > target {
+                return Some(Self::bin_loudness(bin));
+            }
+        }
+
+        None
+    }
+}
+
+/// The two stage K-weighting filter: a high shelf for the head, then a high
+/// pass. Coefficients are worked out for the sample rate rather than using
+/// the 48 kHz ones from the standard.
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        // High shelf
+        let f0 = 1681.974450955533;
+        let gain_db = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain_db / 20f64);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1f64 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2f64 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2f64 * (k * k - 1f64) / a0, (1f64 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+
+        // High pass
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1f64 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1f64, -2f64, 1f64],
+            a: [2f64 * (k * k - 1f64) / a0, (1f64 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+
+        KWeighting {
+            stages: [shelf, high_pass],
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, sample: f64) -> f64 {
+        let shelved = self.stages[0].process(sample);
+        self.stages[1].process(shelved)
+    }
+
+    fn reset(&mut self) {
+        for stage in self.stages.iter_mut() {
+            stage.state = [0f64; 2];
+        }
+    }
+}
+
+/// Transposed direct form II, with `a0` normalised to 1.
+#[derive(Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    #[inline]
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+
+        y
+    }
+}
+
+#[cfg(test)]
+mod loudness_tests {
+    use crate::loudness::*;
+
+    /// `seconds` of a stereo sine at `dbfs` peak level in both channels
+    fn sine(frequency: f64, dbfs: f64, seconds: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(dbfs / 20f64);
+
+        (0..(seconds * 48000f64) as usize)
+            .flat_map(|i| {
+                let sample = amplitude * (2f64 * PI * frequency * i as f64 / 48000f64).sin();
+                [sample as f32, sample as f32]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sine_at_1k() {
+        // The reference from EBU Tech 3341: a stereo 1 kHz sine at -23 dBFS
+        // is -23 LUFS
+        let mut meter = LoudnessMeter::new(48000, 2);
+        meter.process(&sine(1000f64, -23f64, 20f64));
+
+        assert!((meter.integrated() + 23f64).abs() < 0.1);
+        assert!((meter.momentary() + 23f64).abs() < 0.1);
+        assert!((meter.short_term() + 23f64).abs() < 0.1);
+        assert!(meter.range() < 0.2);
+        assert!((meter.true_peak_db() + 23f64).abs() < 0.1);
+    }
+
+    #[test]
+    fn gating_ignores_silence() {
+        let mut meter = LoudnessMeter::new(48000, 2);
+        meter.process(&sine(1000f64, -20f64, 10f64));
+        meter.process(&vec![0f32; 48000 * 2 * 10]);
+
+        assert!((meter.integrated() + 20f64).abs() < 0.1);
+        assert_eq!(meter.momentary(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn range_of_two_levels() {
+        // EBU Tech 3342 case 1: 20 s at -20 then 20 s at -30 is 10 LU
+        let mut meter = LoudnessMeter::new(48000, 2);
+        meter.process(&sine(1000f64, -20f64, 20f64));
+        meter.process(&sine(1000f64, -30f64, 20f64));
+
+        assert!((meter.range() - 10f64).abs() < 0.2);
+    }
+
+    #[test]
+    fn handle_reads_and_resets() {
+        let mut meter = LoudnessMeter::new(48000, 2);
+        let handle = meter.handle();
+        assert_eq!(handle.integrated(), f64::NEG_INFINITY);
+
+        meter.process(&sine(1000f64, -23f64, 1f64));
+        assert!((handle.momentary() + 23f64).abs() < 0.1);
+
+        handle.reset();
+        meter.process(&[0f32; 2]);
+        assert_eq!(handle.momentary(), f64::NEG_INFINITY);
+        assert_eq!(meter.true_peak_db(), f64::NEG_INFINITY);
+    }
+}
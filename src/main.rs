@@ -2,15 +2,38 @@ pub use crate::builder::*;
 use engine::{Engine, EngineController};
 use tokio;
 
+pub mod automation;
+pub mod backend;
 pub mod builder;
 pub mod channer;
+pub mod convolution;
+pub mod delay;
+pub mod device;
+pub mod dither;
+pub mod dynamics;
 pub mod engine;
+pub mod eq;
+pub mod error;
+pub mod export;
+pub mod flac;
 pub mod frame;
+pub mod loudness;
+pub mod master;
+pub mod meter;
+pub mod mixer;
+pub mod param;
+pub mod playhead;
+pub mod render;
+pub mod reverb;
+pub mod ring_buffer;
+pub mod routing;
 pub mod sample_rate;
 pub mod source;
 pub mod source_reader;
+pub mod stats;
 pub mod symph;
 pub mod track;
+pub mod transport;
 
 // use tokio::sync::mpsc;
 
@@ -23,12 +46,19 @@ pub mod track;
 #[tokio::main]
 async fn main() {
     let controller = EngineController::new().unwrap();
+    println!(
+        "Output: {:?}, buffer size {}",
+        controller.output_config(),
+        controller.buffer_size()
+    );
 
     // controller.open_source_reader("sounds/sample-1.wav".to_string());
     // controller.open_source_reader("sounds/sample-2.wav".to_string());
     // controller.open_source_reader("sounds/sample-3.wav".to_string());
     // controller.open_source_reader("sounds/sample-3.wav".to_string());
-    controller.open_source_reader("sounds/sample-3.wav".to_string());
+    controller
+        .open_source_reader("sounds/sample-3.wav".to_string())
+        .unwrap();
     // controller.open_source_reader("sounds/sample-3.wav".to_string());
     // controller.open_source_reader("sounds/sample-3.wav".to_string());
     // controller.open_source_reader("sounds/sample-4.wav".to_string());
@@ -0,0 +1,363 @@
+use std::collections::VecDeque;
+
+use crate::mixer::db_to_gain;
+
+/// What the master bus does to keep the mix from clipping.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Protection {
+    /// Nothing, the mix goes out as it is
+    #[default]
+    Off,
+    /// Leaves anything below the knee alone and rounds everything above it
+    /// off so it never goes past full scale
+    SoftClip,
+    /// Turns the mix down just ahead of peaks so nothing goes past the
+    /// ceiling. Delays the output by the look-ahead, which offline renders
+    /// leave off the start.
+    Limiter(LimiterSettings),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LimiterSettings {
+    /// Highest level that comes out, in dBFS
+    pub ceiling_db: f32,
+    /// How far ahead peaks are seen, which is also how long the gain takes to
+    /// come down
+    pub lookahead_ms: f32,
+    /// How long the gain takes to recover after a peak
+    pub release_ms: f32,
+}
+
+impl Default for LimiterSettings {
+    fn default() -> Self {
+        LimiterSettings {
+            ceiling_db: -1f32,
+            lookahead_ms: 5f32,
+            release_ms: 50f32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MasterModel {
+    /// Master fader in dB, 0 is unity
+    pub volume_db: f32,
+    pub protection: Protection,
+}
+
+/// Where the soft clipper starts bending the signal
+const SOFT_CLIP_KNEE: f64 = 0.8;
+
+/// Passes anything up to the knee straight through and squashes the rest
+/// smoothly into what's left below full scale.
+#[inline]
+pub fn soft_clip(sample: f64) -> f64 {
+    let level = sample.abs();
+
+    if level <= SOFT_CLIP_KNEE {
+        return sample;
+    }
+
+    let room = 1f64 - SOFT_CLIP_KNEE;
+    let clipped = SOFT_CLIP_KNEE + room * ((level - SOFT_CLIP_KNEE) / room).tanh();
+
+    clipped.copysign(sample)
+}
+
+/// The end of the mix. Takes the channels summed at double precision, applies
+/// the master fader and protection and hands the result out as `f32`.
+pub struct MasterBus {
+    volume_db: f32,
+    gain: f64,
+    protection: Protection,
+    limiter: Option<Limiter>,
+    sample_rate: u32,
+    channel_count: usize,
+}
+
+impl MasterBus {
+    pub fn new(model: &MasterModel, sample_rate: u32, channel_count: usize) -> Self {
+        let mut master = MasterBus {
+            volume_db: 0f32,
+            gain: 1f64,
+            protection: Protection::Off,
+            limiter: None,
+            sample_rate,
+            channel_count,
+        };
+        master.set_volume(model.volume_db);
+        master.set_protection(model.protection);
+
+        master
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume_db
+    }
+
+    pub fn set_volume(&mut self, volume_db: f32) {
+        self.volume_db = volume_db;
+        self.gain = db_to_gain(volume_db) as f64;
+    }
+
+    pub fn protection(&self) -> Protection {
+        self.protection
+    }
+
+    /// Switching to a limiter with a different look-ahead changes `latency`.
+    pub fn set_protection(&mut self, protection: Protection) {
+        self.protection = protection;
+        self.limiter = match protection {
+            Protection::Limiter(settings) => Some(Limiter::new(
+                &settings,
+                self.sample_rate,
+                self.channel_count,
+            )),
+            _ => None,
+        };
+    }
+
+    /// Frames the output lags the mix by
+    pub fn latency(&self) -> usize {
+        self.limiter.as_ref().map_or(0, |l| l.latency())
+    }
+
+    /// Forgets any audio held back for the limiter, e.g. after a seek.
+    pub fn reset(&mut self) {
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.reset();
+        }
+    }
+
+    /// Turns `mix` into the output in `out`. Both are interleaved and the
+    /// same length.
+    pub fn process(&mut self, mix: &mut [f64], out: &mut [f32]) {
+        for sample in mix.iter_mut() {
+            *sample *= self.gain;
+        }
+
+        match self.protection {
+            Protection::Off => {}
+            Protection::SoftClip => {
+                for sample in mix.iter_mut() {
+                    *sample = soft_clip(*sample);
+                }
+            }
+            Protection::Limiter(_) => {
+                if let Some(limiter) = self.limiter.as_mut() {
+                    limiter.process(mix);
+                }
+            }
+        }
+
+        for (out, sample) in out.iter_mut().zip(mix.iter()) {
+            *out = *sample as f32;
+        }
+    }
+}
+
+/// A look-ahead brickwall limiter. The gain each frame needs to stay under the
+/// ceiling is held for the look-ahead, released exponentially and then
+/// smoothed with a moving average as long as the look-ahead, so it has fully
+/// come down by the time the delayed peak comes out.
+struct Limiter {
+    ceiling: f64,
+    release: f64,
+    channel_count: usize,
+    // Frames the gain is held and averaged over, one more than the delay
+    window: usize,
+    // (frame, gain) of the frames in the hold window that could still be the
+    // lowest, in increasing order of gain
+    hold: VecDeque<(u64, f64)>,
+    envelope: f64,
+    // The last `window` envelope values and their sum
+    average: VecDeque<f64>,
+    average_sum: f64,
+    // The last `window - 1` frames of input
+    delay: VecDeque<f64>,
+    frame: u64,
+}
+
+impl Limiter {
+    fn new(settings: &LimiterSettings, sample_rate: u32, channel_count: usize) -> Self {
+        let lookahead = (settings.lookahead_ms / 1000f32 * sample_rate as f32).round() as usize;
+        let release_frames = (settings.release_ms / 1000f32 * sample_rate as f32).max(1f32);
+        let window = lookahead + 1;
+
+        let mut limiter = Limiter {
+            ceiling: db_to_gain(settings.ceiling_db) as f64,
+            release: (-1f64 / release_frames as f64).exp(),
+            channel_count,
+            window,
+            hold: VecDeque::with_capacity(window + 1),
+            envelope: 1f64,
+            average: VecDeque::with_capacity(window + 1),
+            average_sum: 0f64,
+            delay: VecDeque::with_capacity((window + 1) * channel_count),
+            frame: 0,
+        };
+        limiter.reset();
+
+        limiter
+    }
+
+    fn latency(&self) -> usize {
+        self.window - 1
+    }
+
+    fn reset(&mut self) {
+        self.hold.clear();
+        self.envelope = 1f64;
+        self.average.clear();
+        self.average.resize(self.window, 1f64);
+        self.average_sum = self.window as f64;
+        self.delay.clear();
+        self.delay.resize(self.latency() * self.channel_count, 0f64);
+        self.frame = 0;
+    }
+
+    fn process(&mut self, samples: &mut [f64]) {
+        for frame in samples.chunks_exact_mut(self.channel_count) {
+            let peak = frame.iter().fold(0f64, |peak, s| peak.max(s.abs()));
+            let needed = if peak > self.ceiling {
+                self.ceiling / peak
+            } else {
+                1f64
+            };
+
+            // Lowest gain needed in the window
+            while self.hold.back().is_some_and(|(_, gain)| *gain >= needed) {
+                self.hold.pop_back();
+            }
+            self.hold.push_back((self.frame, needed));
+            while self
+                .hold
+                .front()
+                .is_some_and(|(at, _)| at + self.window as u64 <= self.frame)
+            {
+                self.hold.pop_front();
+            }
+            let held = self.hold.front().map_or(1f64, |(_, gain)| *gain);
+
+            // Straight down, slowly back up
+            self.envelope = if held < self.envelope {
+                held
+            } else {
+                held + (self.envelope - held) * self.release
+            };
+
+            self.average_sum += self.envelope - self.average.pop_front().unwrap_or(1f64);
+            self.average.push_back(self.envelope);
+            let gain = (self.average_sum / self.window as f64).min(1f64);
+
+            for sample in frame.iter_mut() {
+                self.delay.push_back(*sample);
+                let delayed = self.delay.pop_front().unwrap_or(0f64);
+                *sample = delayed * gain;
+            }
+
+            self.frame += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod master_tests {
+    use crate::master::*;
+
+    fn limiter() -> MasterBus {
+        MasterBus::new(
+            &MasterModel {
+                protection: Protection::Limiter(LimiterSettings::default()),
+                ..Default::default()
+            },
+            44100,
+            2,
+        )
+    }
+
+    fn process(master: &mut MasterBus, mix: &[f64]) -> Vec<f32> {
+        let mut mix = mix.to_vec();
+        let mut out = vec![0f32; mix.len()];
+        master.process(&mut mix, &mut out);
+
+        out
+    }
+
+    #[test]
+    fn off_only_applies_the_fader() {
+        let mut master = MasterBus::new(
+            &MasterModel {
+                volume_db: -6f32,
+                ..Default::default()
+            },
+            44100,
+            2,
+        );
+        let out = process(&mut master, &[0.5, 3.0]);
+
+        let gain = db_to_gain(-6f32) as f64;
+        assert_eq!(out, [(0.5 * gain) as f32, (3.0 * gain) as f32]);
+        assert_eq!(master.latency(), 0);
+    }
+
+    #[test]
+    fn soft_clip_stays_under_full_scale() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-0.8), -0.8);
+        assert!(soft_clip(0.9) < 0.9 && soft_clip(0.9) > 0.8);
+        assert!(soft_clip(10.0) <= 1.0);
+        assert!(soft_clip(-10.0) >= -1.0);
+
+        // Louder in is never quieter out
+        let mut last = 0f64;
+        for i in 0..300 {
+            let clipped = soft_clip(i as f64 / 100f64);
+            assert!(clipped >= last);
+            last = clipped;
+        }
+    }
+
+    #[test]
+    fn limiter_holds_the_ceiling() {
+        let mut master = limiter();
+        let latency = master.latency();
+        assert_eq!(latency, 221);
+
+        // Quiet, then a burst well over full scale, then quiet again
+        let mix: Vec<f64> = (0..20000)
+            .flat_map(|i| {
+                let level = if (2000..2400).contains(&i) { 4.0 } else { 0.25 };
+                let sample = level * (i as f64 * 0.05).sin();
+                [sample, -sample]
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        for block in mix.chunks(128) {
+            out.extend(process(&mut master, block));
+        }
+
+        let ceiling = db_to_gain(-1f32);
+        assert!(out.iter().all(|s| s.abs() <= ceiling + 1e-6));
+
+        // Quiet parts before the burst come out unchanged, just later
+        for i in 0..1500 {
+            assert!((out[(i + latency) * 2] as f64 - mix[i * 2]).abs() < 1e-6);
+        }
+
+        // And it recovers afterwards
+        let end = mix.len() / 2 - 1;
+        assert!((out[end * 2] as f64 - mix[(end - latency) * 2]).abs() < 1e-3);
+    }
+
+    #[test]
+    fn reset_drops_held_audio() {
+        let mut master = limiter();
+        process(&mut master, &[0.5; 64]);
+        master.reset();
+
+        let out = process(&mut master, &[0.5; 64]);
+        assert!(out.iter().all(|s| *s == 0f32));
+    }
+}
@@ -0,0 +1,399 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use crate::{loudness::LoudnessHandle, mixer::db_to_gain};
+
+/// How meters measure and fall back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeterSettings {
+    /// Length of the window RMS is measured over
+    pub rms_window_ms: f32,
+    /// How long the peak hold stays put before following the peak down
+    pub hold_ms: f32,
+    /// How fast the peak and true peak fall once the level drops
+    pub decay_db_per_second: f32,
+}
+
+impl Default for MeterSettings {
+    fn default() -> Self {
+        MeterSettings {
+            rms_window_ms: 300f32,
+            hold_ms: 1500f32,
+            decay_db_per_second: 20f32,
+        }
+    }
+}
+
+/// Levels of one audio channel, as gains (1 is full scale).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MeterLevels {
+    pub peak: f32,
+    pub peak_hold: f32,
+    pub rms: f32,
+    /// Peak of the signal oversampled 4 times, which catches peaks that fall
+    /// between samples
+    pub true_peak: f32,
+}
+
+#[derive(Default)]
+struct SharedLevels {
+    peak: AtomicU32,
+    peak_hold: AtomicU32,
+    rms: AtomicU32,
+    true_peak: AtomicU32,
+}
+
+/// Reads a meter from any thread. The audio thread only ever stores to
+/// atomics, so reading never holds it up.
+#[derive(Clone)]
+pub struct MeterHandle {
+    channels: Arc<[SharedLevels]>,
+}
+
+impl MeterHandle {
+    /// The latest levels, one per audio channel.
+    pub fn levels(&self) -> Vec<MeterLevels> {
+        let load = |value: &AtomicU32| f32::from_bits(value.load(Ordering::Relaxed));
+
+        self.channels
+            .iter()
+            .map(|levels| MeterLevels {
+                peak: load(&levels.peak),
+                peak_hold: load(&levels.peak_hold),
+                rms: load(&levels.rms),
+                true_peak: load(&levels.true_peak),
+            })
+            .collect()
+    }
+}
+
+/// Handles to every meter of a playback.
+#[derive(Clone)]
+pub struct Meters {
+    /// After each channel's fader and pan, in `Playback::channel_ids` order
+    pub channels: Vec<MeterHandle>,
+    /// After the master bus
+    pub master: MeterHandle,
+    /// Loudness of the master output
+    pub loudness: LoudnessHandle,
+    /// Per channel, per insert, for the inserts that report it
+    pub gain_reduction: Vec<Vec<Option<GainReductionHandle>>>,
+    /// Per bus, per insert, like `gain_reduction`, in `MixerModel::buses`
+    /// order
+    pub bus_gain_reduction: Vec<Vec<Option<GainReductionHandle>>>,
+}
+
+/// How far a dynamics processor turned the gain down over its last block, in
+/// dB. Written on the audio thread, read through `GainReductionHandle`s.
+#[derive(Default)]
+pub struct GainReduction {
+    shared: Arc<AtomicU32>,
+}
+
+impl GainReduction {
+    pub fn handle(&self) -> GainReductionHandle {
+        GainReductionHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    pub fn set(&self, db: f32) {
+        self.shared.store(db.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Reads a processor's gain reduction from any thread.
+#[derive(Clone)]
+pub struct GainReductionHandle {
+    shared: Arc<AtomicU32>,
+}
+
+impl GainReductionHandle {
+    /// How many dB down the gain went at most over the last block, 0 when it
+    /// left the audio alone.
+    pub fn db(&self) -> f32 {
+        f32::from_bits(self.shared.load(Ordering::Relaxed))
+    }
+}
+
+/// Measures interleaved audio a block at a time. Owned by whatever renders the
+/// audio, read through `MeterHandle`s.
+pub struct Meter {
+    shared: Arc<[SharedLevels]>,
+    channels: Vec<ChannelMeter>,
+    decay_per_frame: f32,
+    hold_frames: u64,
+}
+
+struct ChannelMeter {
+    peak: f32,
+    true_peak: f32,
+    hold: f32,
+    hold_left: u64,
+    // Squares of the last window of samples and their sum
+    squares: Vec<f64>,
+    next_square: usize,
+    square_sum: f64,
+    oversampler: TruePeak,
+}
+
+impl Meter {
+    pub fn new(settings: &MeterSettings, sample_rate: u32, channel_count: usize) -> Self {
+        let frames = |ms: f32| (ms / 1000f32 * sample_rate as f32).round() as usize;
+        let window = frames(settings.rms_window_ms).max(1);
+
+        Meter {
+            shared: (0..channel_count)
+                .map(|_| SharedLevels::default())
+                .collect(),
+            channels: (0..channel_count)
+                .map(|_| ChannelMeter {
+                    peak: 0f32,
+                    true_peak: 0f32,
+                    hold: 0f32,
+                    hold_left: 0,
+                    squares: vec![0f64; window],
+                    next_square: 0,
+                    square_sum: 0f64,
+                    oversampler: TruePeak::default(),
+                })
+                .collect(),
+            decay_per_frame: db_to_gain(-settings.decay_db_per_second / sample_rate as f32),
+            hold_frames: frames(settings.hold_ms) as u64,
+        }
+    }
+
+    pub fn handle(&self) -> MeterHandle {
+        MeterHandle {
+            channels: self.shared.clone(),
+        }
+    }
+
+    /// Measures the next block of `samples`.
+    pub fn process(&mut self, samples: &[f32]) {
+        let channel_count = self.channels.len();
+        let frames = samples.len() / channel_count;
+        let decay = self.decay_per_frame.powi(frames as i32);
+
+        for (index, (meter, shared)) in self.channels.iter_mut().zip(self.shared.iter()).enumerate()
+        {
+            let mut peak = 0f32;
+            let mut true_peak = 0f32;
+
+            for sample in samples.iter().skip(index).step_by(channel_count) {
+                peak = peak.max(sample.abs());
+                true_peak = true_peak.max(meter.oversampler.next(*sample));
+
+                let square = *sample as f64 * *sample as f64;
+                meter.square_sum += square - meter.squares[meter.next_square];
+                meter.squares[meter.next_square] = square;
+                meter.next_square = (meter.next_square + 1) % meter.squares.len();
+            }
+
+            meter.peak = peak.max(meter.peak * decay);
+            meter.true_peak = true_peak.max(meter.true_peak * decay);
+
+            if peak >= meter.hold {
+                meter.hold = peak;
+                meter.hold_left = self.hold_frames;
+            } else if meter.hold_left > frames as u64 {
+                meter.hold_left -= frames as u64;
+            } else {
+                meter.hold_left = 0;
+                meter.hold = meter.peak;
+            }
+
+            let rms = (meter.square_sum.max(0f64) / meter.squares.len() as f64).sqrt();
+
+            shared.peak.store(meter.peak.to_bits(), Ordering::Relaxed);
+            shared
+                .peak_hold
+                .store(meter.hold.to_bits(), Ordering::Relaxed);
+            shared.rms.store((rms as f32).to_bits(), Ordering::Relaxed);
+            shared
+                .true_peak
+                .store(meter.true_peak.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Drops the meter back to silence.
+    pub fn reset(&mut self) {
+        for meter in self.channels.iter_mut() {
+            meter.peak = 0f32;
+            meter.true_peak = 0f32;
+            meter.hold = 0f32;
+            meter.hold_left = 0;
+            meter.squares.fill(0f64);
+            meter.square_sum = 0f64;
+            meter.oversampler = TruePeak::default();
+        }
+
+        for shared in self.shared.iter() {
+            shared.peak.store(0, Ordering::Relaxed);
+            shared.peak_hold.store(0, Ordering::Relaxed);
+            shared.rms.store(0, Ordering::Relaxed);
+            shared.true_peak.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Taps of the 4 times oversampling filter from ITU-R BS.1770-4 Annex 2, one
+/// row per phase.
+const TRUE_PEAK_TAPS: [[f64; 12]; 4] = [
+    [
+        0.001708984375,
+        0.010986328125,
+        -0.0196533203125,
+        0.033203125,
+        -0.0594482421875,
+        0.1373291015625,
+        0.97216796875,
+        -0.102294921875,
+        0.047607421875,
+        -0.026611328125,
+        0.014892578125,
+        -0.00830078125,
+    ],
+    [
+        -0.0291748046875,
+        0.029296875,
+        -0.0517578125,
+        0.089111328125,
+        -0.16650390625,
+        0.465087890625,
+        0.77978515625,
+        -0.2003173828125,
+        0.1015625,
+        -0.0582275390625,
+        0.0330810546875,
+        -0.0189208984375,
+    ],
+    [
+        -0.0189208984375,
+        0.0330810546875,
+        -0.0582275390625,
+        0.1015625,
+        -0.2003173828125,
+        0.77978515625,
+        0.465087890625,
+        -0.16650390625,
+        0.089111328125,
+        -0.0517578125,
+        0.029296875,
+        -0.0291748046875,
+    ],
+    [
+        -0.00830078125,
+        0.014892578125,
+        -0.026611328125,
+        0.047607421875,
+        -0.102294921875,
+        0.97216796875,
+        0.1373291015625,
+        -0.0594482421875,
+        0.033203125,
+        -0.0196533203125,
+        0.010986328125,
+        0.001708984375,
+    ],
+];
+
+/// Finds the peak of one channel between its samples by oversampling it.
+#[derive(Clone, Default)]
+pub(crate) struct TruePeak {
+    // The last 12 samples, newest first, written twice over so they can always
+    // be read as one slice without shifting
+    history: [f32; 24],
+    newest: usize,
+}
+
+impl TruePeak {
+    /// Takes the next sample and returns the highest level of the 4 samples
+    /// it oversamples to.
+    #[inline]
+    pub fn next(&mut self, sample: f32) -> f32 {
+        self.newest = (self.newest + 11) % 12;
+        self.history[self.newest] = sample;
+        self.history[self.newest + 12] = sample;
+
+        let history = &self.history[self.newest..self.newest + 12];
+        let mut peak = 0f32;
+
+        for taps in TRUE_PEAK_TAPS.iter() {
+            let mut sum = 0f64;
+            for (tap, sample) in taps.iter().zip(history) {
+                sum += tap * *sample as f64;
+            }
+            peak = peak.max(sum.abs() as f32);
+        }
+
+        peak
+    }
+}
+
+#[cfg(test)]
+mod meter_tests {
+    use crate::meter::*;
+
+    fn meter() -> Meter {
+        Meter::new(&MeterSettings::default(), 48000, 2)
+    }
+
+    fn sine(frequency: f32, phase: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let t = i as f32 / 48000f32;
+                let sample = (2f32 * std::f32::consts::PI * frequency * t + phase).sin();
+                [sample, sample * 0.5]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn measures_peak_and_rms() {
+        let mut meter = meter();
+        meter.process(&sine(1000f32, 0f32, 48000));
+
+        let levels = meter.handle().levels();
+        assert!((levels[0].peak - 1f32).abs() < 1e-3);
+        assert!((levels[0].rms - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        assert!((levels[1].peak - 0.5).abs() < 1e-3);
+        assert!((levels[1].rms - std::f32::consts::FRAC_1_SQRT_2 / 2f32).abs() < 1e-3);
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        // A quarter sample rate sine with its peaks halfway between samples
+        let mut meter = meter();
+        let samples = sine(12000f32, std::f32::consts::FRAC_PI_4, 4800);
+        meter.process(&samples);
+
+        let levels = meter.handle().levels();
+        assert!((levels[0].peak - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        assert!(levels[0].true_peak > 0.95);
+    }
+
+    #[test]
+    fn peak_holds_then_decays() {
+        let mut meter = meter();
+        meter.process(&[1f32, 1f32]);
+
+        // A second of silence: the hold stays, the peak falls 20 dB
+        meter.process(&vec![0f32; 96000]);
+        let levels = meter.handle().levels();
+        assert_eq!(levels[0].peak_hold, 1f32);
+        assert!((levels[0].peak - 0.1).abs() < 1e-3);
+
+        // Once the hold runs out, half a second later, it follows the peak
+        meter.process(&vec![0f32; 48000]);
+        let levels = meter.handle().levels();
+        assert!((levels[0].peak_hold - 0.0316).abs() < 1e-3);
+        assert_eq!(levels[0].peak_hold, levels[0].peak);
+        assert!(levels[0].rms < 1e-6);
+
+        meter.reset();
+        assert_eq!(meter.handle().levels()[0], MeterLevels::default());
+    }
+}
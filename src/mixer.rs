@@ -1,49 +1,565 @@
-use crossbeam::channel::{Receiver, Sender};
-
-// Holds a list of Tracks
-// Mixes all channels together
-pub struct Mixer {}
-
-// Holds a list of clips
-// Holds a list of Processors
-// Is in charge of channeling source data through its Processors/
-// to the mixer
-pub struct Track {
-    clips: Vec<Source>,
-    processors: Vec<Box<dyn Processor>>,
-    rx: Receiver<f32>,
-    tx: Sender<f32>,
+use std::f32::consts::FRAC_PI_2;
+
+use crate::{
+    builder::MixerModel,
+    meter::GainReductionHandle,
+    param::{Param, ParamHandle, ParamInfo, Smoothing, Unit},
+};
+
+/// How a channel's level is split between left and right as it is panned.
+/// Named by how far each side drops at the centre compared to hard panned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanLaw {
+    /// Constant power, so a signal sounds as loud wherever it is panned
+    #[default]
+    Minus3Db,
+    /// Halfway between constant power and constant amplitude
+    Minus4_5Db,
+    /// Constant amplitude, so the sides sum to the same level in mono
+    Minus6Db,
+    /// The far side fades out in a straight line and the near side stays at
+    /// full level, like a balance control
+    Linear,
+}
+
+impl PanLaw {
+    /// Left and right gains for `pan`, from -1 (hard left) to 1 (hard right).
+    /// They are relative to the centre, so a centred channel plays at its
+    /// fader level and panning raises the near side by the law's amount.
+    pub fn gains(&self, pan: f32) -> (f32, f32) {
+        let (left, right) = self.raw_gains(pan);
+        let (centre, _) = self.raw_gains(0f32);
+
+        (left / centre, right / centre)
+    }
+
+    fn raw_gains(&self, pan: f32) -> (f32, f32) {
+        // 0 is hard left, 1 hard right
+        let position = (pan.clamp(-1f32, 1f32) + 1f32) / 2f32;
+        let power = ((1f32 - position) * FRAC_PI_2).sin();
+        let power_right = (position * FRAC_PI_2).sin();
+
+        match self {
+            PanLaw::Minus3Db => (power, power_right),
+            PanLaw::Minus4_5Db => (
+                ((1f32 - position) * power).sqrt(),
+                (position * power_right).sqrt(),
+            ),
+            PanLaw::Minus6Db => (1f32 - position, position),
+            PanLaw::Linear => (
+                (2f32 * (1f32 - position)).min(1f32),
+                (2f32 * position).min(1f32),
+            ),
+        }
+    }
+}
+
+/// Turns decibels into a gain to multiply samples by. Negative infinity is
+/// silence.
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20f32)
+}
+
+/// Where a channel's fader, pan, mute and solo are set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Strip {
+    pub volume_db: f32,
+    pub pan: f32,
+    pub mute: bool,
+    pub solo: bool,
+}
+
+/// Applies each channel's strip to its audio before it is summed. Channels are
+/// in the same order as the `MixerModel` it was made from.
+#[derive(Clone, Debug, Default)]
+pub struct Mixer {
+    pan_law: PanLaw,
+    strips: Vec<Strip>,
+}
+
+impl Mixer {
+    pub fn new(model: &MixerModel) -> Self {
+        Mixer {
+            pan_law: model.pan_law,
+            strips: model
+                .channels
+                .iter()
+                .map(|channel| Strip {
+                    volume_db: channel.volume_db,
+                    pan: channel.pan,
+                    mute: channel.mute,
+                    solo: channel.solo,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn pan_law(&self) -> PanLaw {
+        self.pan_law
+    }
+
+    pub fn set_pan_law(&mut self, pan_law: PanLaw) {
+        self.pan_law = pan_law;
+    }
+
+    pub fn strips(&self) -> &[Strip] {
+        &self.strips
+    }
+
+    pub fn strip_mut(&mut self, index: usize) -> &mut Strip {
+        &mut self.strips[index]
+    }
+
+    /// Whether channel `index` can be heard: it isn't muted, and either it is
+    /// soloed or nothing is.
+    pub fn is_audible(&self, index: usize) -> bool {
+        let strip = &self.strips[index];
+        let any_solo = self.strips.iter().any(|s| s.solo);
+
+        !strip.mute && (strip.solo || !any_solo)
+    }
+
+    /// Applies channel `index`'s strip to `samples`, interleaved with
+    /// `channel_count` channels. Pan only applies to stereo, any other layout
+    /// just gets the fader.
+    pub fn apply(&self, index: usize, samples: &mut [f32], channel_count: usize) {
+        if !self.is_audible(index) {
+            samples.fill(0f32);
+            return;
+        }
+
+        self.strips[index].apply(self.pan_law, samples, channel_count);
+    }
+
+    /// Same as `apply`, but with the fader and pan following `volume_db` and
+    /// `pan`, one value per frame, where they are given. The strip is left at
+    /// the last value, so it reads back where the automation has got to.
+    pub fn apply_automated(
+        &mut self,
+        index: usize,
+        samples: &mut [f32],
+        channel_count: usize,
+        volume_db: Option<&[f32]>,
+        pan: Option<&[f32]>,
+    ) {
+        if volume_db.is_none() && pan.is_none() {
+            return self.apply(index, samples, channel_count);
+        }
+
+        if self.is_audible(index) {
+            let strip = &self.strips[index];
+
+            for (i, frame) in samples.chunks_exact_mut(channel_count).enumerate() {
+                let frame_strip = Strip {
+                    volume_db: volume_db.map_or(strip.volume_db, |v| v[i]),
+                    pan: pan.map_or(strip.pan, |p| p[i]),
+                    mute: false,
+                    solo: false,
+                };
+                frame_strip.apply(self.pan_law, frame, channel_count);
+            }
+        } else {
+            samples.fill(0f32);
+        }
+
+        let strip = &mut self.strips[index];
+        if let Some(last) = volume_db.and_then(|v| v.last()) {
+            strip.volume_db = *last;
+        }
+        if let Some(last) = pan.and_then(|p| p.last()) {
+            strip.pan = *last;
+        }
+    }
 }
 
-impl Track {
-    pub fn next(&mut self) -> f32 {
-        let mut sample = self.clips.get_mut(0).unwrap().next().unwrap();
-        let mut proc_iter = self.processors.iter();
+impl Strip {
+    /// Applies the fader and pan (but not mute or solo) to `samples`.
+    pub fn apply(&self, pan_law: PanLaw, samples: &mut [f32], channel_count: usize) {
+        let gain = db_to_gain(self.volume_db);
+
+        if channel_count == 2 {
+            let (left, right) = pan_law.gains(self.pan);
+            let (left, right) = (left * gain, right * gain);
 
-        while let Some(processor) = proc_iter.next() {
-            sample = processor.process(sample);
+            for frame in samples.chunks_exact_mut(2) {
+                frame[0] *= left;
+                frame[1] *= right;
+            }
+        } else if gain != 1f32 {
+            for sample in samples.iter_mut() {
+                *sample *= gain;
+            }
         }
+    }
+}
+
+/// An effect in a channel's insert chain. Runs on the audio thread a block at
+/// a time and can keep whatever state it needs between blocks.
+pub trait Processor: Send {
+    /// Called once before the first block, with the sample rate, the number
+    /// of interleaved channels and the most frames any block will have.
+    /// Anything sized by those is allocated here, not in `process`.
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize, max_block_frames: usize);
+
+    /// Processes a block of interleaved `samples` in place.
+    fn process(&mut self, samples: &mut [f32]);
+
+    /// Same as `process`, for processors that follow the level of another
+    /// channel. `sidechain` is that channel's block, the same length as
+    /// `samples`. Processors that have no use for one ignore it.
+    fn process_with_sidechain(&mut self, samples: &mut [f32], _sidechain: &[f32]) {
+        self.process(samples);
+    }
+
+    /// Frames the output lags the input by
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Frames the processor can keep sounding after its input goes silent,
+    /// e.g. the decay of a reverb
+    fn tail(&self) -> usize {
+        0
+    }
+
+    /// Handles for changing the processor's parameters while it runs.
+    fn params(&self) -> Vec<ParamHandle> {
+        Vec::new()
+    }
 
-        sample
+    /// For metering how far a dynamics processor is turning the gain down.
+    fn gain_reduction(&self) -> Option<GainReductionHandle> {
+        None
     }
+
+    /// Forgets any state built up from past audio, e.g. after a seek.
+    fn reset(&mut self) {}
 }
 
-// Can be anything, like resampler, volume, pan, or other pugins
-pub trait Processor {
-    /// Process one sample at a time.
-    /// This might need some sort of state argument variable
-    fn process(&self, sample: f32) -> f32;
+/// Turns a signal up or down.
+pub struct Gain {
+    gain_db: Param,
+    gain: f32,
+    channel_count: usize,
+}
 
-    // fn process_buffer(&self, buffer: &mut [T]);
+impl Gain {
+    pub fn new(gain_db: f32) -> Self {
+        let param = Param::with_value(
+            ParamInfo {
+                name: "Gain".to_string(),
+                unit: Unit::Decibels,
+                min: -96f32,
+                max: 24f32,
+                default: 0f32,
+                smoothing: Smoothing::default(),
+            },
+            gain_db,
+        );
+
+        Gain {
+            gain: db_to_gain(param.value()),
+            gain_db: param,
+            channel_count: 1,
+        }
+    }
 }
 
-// For now this can be a clip
-pub struct Source {}
+impl Processor for Gain {
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize, _max_block_frames: usize) {
+        self.channel_count = channel_count;
+        self.gain_db.prepare(sample_rate);
+        self.gain = db_to_gain(self.gain_db.value());
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(self.channel_count) {
+            if self.gain_db.is_smoothing() {
+                self.gain = db_to_gain(self.gain_db.next_value());
+            }
+
+            for sample in frame.iter_mut() {
+                *sample *= self.gain;
+            }
+        }
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        vec![self.gain_db.handle()]
+    }
+
+    fn reset(&mut self) {
+        self.gain_db.reset();
+        self.gain = db_to_gain(self.gain_db.value());
+    }
+}
+
+/// Processors run one after the other, in order.
+#[derive(Default)]
+pub struct InsertChain {
+    processors: Vec<Box<dyn Processor>>,
+    // Index of the channel each processor takes a sidechain from, if any
+    sidechains: Vec<Option<usize>>,
+    channel_count: usize,
+    max_block_frames: usize,
+}
+
+impl InsertChain {
+    pub fn new(processors: Vec<Box<dyn Processor>>) -> Self {
+        InsertChain {
+            sidechains: vec![None; processors.len()],
+            processors,
+            channel_count: 1,
+            max_block_frames: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+
+    pub fn processor_mut(&mut self, index: usize) -> &mut dyn Processor {
+        self.processors[index].as_mut()
+    }
+
+    /// Has processor `index` follow channel `channel`, in
+    /// `process_with_sidechains`.
+    pub fn set_sidechain(&mut self, index: usize, channel: Option<usize>) {
+        self.sidechains[index] = channel;
+    }
+
+    pub fn prepare(&mut self, sample_rate: u32, channel_count: usize, max_block_frames: usize) {
+        self.channel_count = channel_count;
+        self.max_block_frames = max_block_frames;
+
+        for processor in self.processors.iter_mut() {
+            processor.prepare(sample_rate, channel_count, max_block_frames);
+        }
+    }
+
+    /// Runs `samples` through every processor. Blocks longer than the one
+    /// the chain was prepared for are split up.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        self.process_with_sidechains(samples, &[], 0);
+    }
+
+    /// Same as `process`, handing processors with a sidechain their channel's
+    /// audio from `channels`. `samples` lines up with `channels` from sample
+    /// `offset` on. Processors whose channel isn't there run without.
+    pub fn process_with_sidechains(
+        &mut self,
+        samples: &mut [f32],
+        channels: &[Vec<f32>],
+        offset: usize,
+    ) {
+        if self.processors.is_empty() {
+            return;
+        }
+
+        let block = self.max_block_frames.max(1) * self.channel_count;
+        let mut start = offset;
+
+        for block in samples.chunks_mut(block) {
+            let end = start + block.len();
+
+            for (processor, sidechain) in self.processors.iter_mut().zip(self.sidechains.iter()) {
+                let sidechain = sidechain
+                    .and_then(|channel| channels.get(channel))
+                    .and_then(|audio| audio.get(start..end));
+
+                match sidechain {
+                    Some(sidechain) => processor.process_with_sidechain(block, sidechain),
+                    None => processor.process(block),
+                }
+            }
+
+            start = end;
+        }
+    }
+
+    /// Frames the chain's output lags its input by, the sum of its processors'
+    pub fn latency(&self) -> usize {
+        self.processors.iter().map(|p| p.latency()).sum()
+    }
+
+    /// Frames the chain can keep sounding after its input goes silent. Each
+    /// processor's tail runs on through the ones after it, so they add up.
+    pub fn tail(&self) -> usize {
+        self.processors.iter().map(|p| p.tail()).sum()
+    }
+
+    /// Gain reduction of each processor, in order, for the ones that report
+    /// it.
+    pub fn gain_reduction(&self) -> Vec<Option<GainReductionHandle>> {
+        self.processors.iter().map(|p| p.gain_reduction()).collect()
+    }
+
+    /// Parameters of each processor, in order.
+    pub fn params(&self) -> Vec<Vec<ParamHandle>> {
+        self.processors.iter().map(|p| p.params()).collect()
+    }
+
+    pub fn reset(&mut self) {
+        for processor in self.processors.iter_mut() {
+            processor.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod mixer_tests {
+    use crate::builder::ChannelModel;
+    use crate::mixer::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    fn mixer(strips: &[(f32, f32, bool, bool)]) -> Mixer {
+        Mixer::new(&MixerModel {
+            channels: strips
+                .iter()
+                .map(|(volume_db, pan, mute, solo)| ChannelModel {
+                    volume_db: *volume_db,
+                    pan: *pan,
+                    mute: *mute,
+                    solo: *solo,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn centre_is_unity_for_every_law() {
+        for law in [
+            PanLaw::Minus3Db,
+            PanLaw::Minus4_5Db,
+            PanLaw::Minus6Db,
+            PanLaw::Linear,
+        ] {
+            let (left, right) = law.gains(0f32);
+            assert!(close(left, 1f32) && close(right, 1f32), "{:?}", law);
+
+            let (left, right) = law.gains(-1f32);
+            assert!(close(right, 0f32), "{:?}", law);
+            assert!(left >= 1f32, "{:?}", law);
+        }
+    }
+
+    #[test]
+    fn laws_drop_the_centre_by_their_amount() {
+        let drop = |law: PanLaw| {
+            let (hard, _) = law.gains(-1f32);
+            20f32 * hard.log10()
+        };
+
+        assert!((drop(PanLaw::Minus3Db) - 3.01).abs() < 0.01);
+        assert!((drop(PanLaw::Minus4_5Db) - 4.52).abs() < 0.01);
+        assert!((drop(PanLaw::Minus6Db) - 6.02).abs() < 0.01);
+        assert!(close(drop(PanLaw::Linear), 0f32));
+
+        // Constant power keeps the sum of squares the same
+        let (left, right) = PanLaw::Minus3Db.gains(0.4);
+        assert!(close(left * left + right * right, 2f32));
+    }
+
+    #[test]
+    fn applies_volume_and_pan() {
+        let mut mixer = mixer(&[(-6f32, 1f32, false, false)]);
+        mixer.set_pan_law(PanLaw::Minus6Db);
+
+        let mut samples = vec![0.5f32; 4];
+        mixer.apply(0, &mut samples, 2);
+
+        let gain = db_to_gain(-6f32);
+        assert!(close(samples[0], 0f32));
+        assert!(close(samples[1], 0.5 * gain * 2f32));
+
+        // Other layouts only get the fader
+        let mut samples = vec![0.5f32; 3];
+        mixer.apply(0, &mut samples, 1);
+        assert!(samples.iter().all(|s| close(*s, 0.5 * gain)));
+    }
+
+    #[test]
+    fn mute_and_solo() {
+        let mixer = mixer(&[
+            (0f32, 0f32, false, false),
+            (0f32, 0f32, false, true),
+            (0f32, 0f32, true, true),
+        ]);
+
+        assert!(!mixer.is_audible(0));
+        assert!(mixer.is_audible(1));
+        // Mute wins over solo
+        assert!(!mixer.is_audible(2));
+
+        let mut samples = vec![1f32; 4];
+        mixer.apply(0, &mut samples, 2);
+        assert!(samples.iter().all(|s| *s == 0f32));
+
+        assert_eq!(db_to_gain(f32::NEG_INFINITY), 0f32);
+    }
+
+    /// Fills each block it gets with how many blocks came before it
+    struct CountBlocks {
+        count: f32,
+        tail: usize,
+    }
+
+    impl Processor for CountBlocks {
+        fn prepare(&mut self, _: u32, _: usize, _: usize) {}
+
+        fn process(&mut self, samples: &mut [f32]) {
+            samples.fill(self.count);
+            self.count += 1f32;
+        }
+
+        fn latency(&self) -> usize {
+            3
+        }
+
+        fn tail(&self) -> usize {
+            self.tail
+        }
+
+        fn reset(&mut self) {
+            self.count = 0f32;
+        }
+    }
+
+    #[test]
+    fn insert_chain_runs_in_order_within_the_block_size() {
+        let mut chain = InsertChain::new(vec![
+            Box::new(CountBlocks {
+                count: 0f32,
+                tail: 10,
+            }),
+            Box::new(Gain::new(-6f32)),
+        ]);
+        chain.prepare(44100, 2, 4);
+        assert_eq!(chain.latency(), 3);
+        assert_eq!(chain.tail(), 10);
+
+        let mut samples = vec![0f32; 20];
+        chain.process(&mut samples);
 
-impl Iterator for Source {
-    type Item = f32;
+        let gain = db_to_gain(-6f32);
+        let expected: Vec<f32> = [0f32, 1f32, 2f32]
+            .iter()
+            .flat_map(|block| vec![block * gain; 8])
+            .take(20)
+            .collect();
+        assert_eq!(samples, expected);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        Some(1f32)
+        chain.reset();
+        chain.process(&mut samples[..2]);
+        assert_eq!(samples[0], 0f32);
     }
 }
@@ -0,0 +1,473 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+/// What a parameter's value is measured in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Unit {
+    #[default]
+    None,
+    Decibels,
+    Hertz,
+    Milliseconds,
+    Percent,
+    /// e.g. a compressor's 4:1
+    Ratio,
+}
+
+impl Unit {
+    /// What goes after the value when it is shown, e.g. "dB".
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Unit::None => "",
+            Unit::Decibels => "dB",
+            Unit::Hertz => "Hz",
+            Unit::Milliseconds => "ms",
+            Unit::Percent => "%",
+            Unit::Ratio => ":1",
+        }
+    }
+}
+
+/// How a parameter moves to a new value, so changing it doesn't click.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Smoothing {
+    /// Jumps straight there
+    None,
+    /// In a straight line over this many ms
+    Linear(f32),
+    /// Quickly at first then slowing down, getting 99% of the way there in
+    /// this many ms
+    Exponential(f32),
+}
+
+impl Default for Smoothing {
+    fn default() -> Self {
+        Smoothing::Linear(20f32)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParamInfo {
+    pub name: String,
+    pub unit: Unit,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub smoothing: Smoothing,
+}
+
+struct SharedParam {
+    info: ParamInfo,
+    value: AtomicU32,
+    // Frames to ramp in a straight line to the value over instead of
+    // smoothing, 0 to jump straight there, or NO_RAMP
+    ramp: AtomicU32,
+}
+
+const NO_RAMP: u32 = u32::MAX;
+
+/// Changes a parameter from any thread. Setting only stores to an atomic, so
+/// it never waits on the audio thread and the audio thread never waits on it.
+#[derive(Clone)]
+pub struct ParamHandle {
+    shared: Arc<SharedParam>,
+}
+
+impl ParamHandle {
+    pub fn info(&self) -> &ParamInfo {
+        &self.shared.info
+    }
+
+    /// The value last set, which the audio thread may still be smoothing
+    /// towards.
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.shared.value.load(Ordering::Relaxed))
+    }
+
+    /// Sets the value, clamped to the parameter's range.
+    pub fn set(&self, value: f32) {
+        let info = &self.shared.info;
+        let value = value.clamp(info.min, info.max);
+
+        self.shared.value.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Sets the value like `set`, but the audio thread jumps straight to it
+    /// instead of smoothing, e.g. for automation that has to land on a frame.
+    pub fn set_immediate(&self, value: f32) {
+        self.ramp_to(value, 0);
+    }
+
+    /// Sets the value like `set`, but the audio thread moves to it in a
+    /// straight line over exactly `frames` frames instead of smoothing, e.g.
+    /// for automation following a lane between breakpoints.
+    pub fn ramp_to(&self, value: f32, frames: u32) {
+        self.set(value);
+        self.shared
+            .ramp
+            .store(frames.min(NO_RAMP - 1), Ordering::Release);
+    }
+
+    pub fn set_to_default(&self) {
+        self.set(self.shared.info.default);
+    }
+
+    /// The value as 0 at the bottom of its range to 1 at the top, e.g. for a
+    /// slider.
+    pub fn normalized(&self) -> f32 {
+        let info = &self.shared.info;
+        (self.get() - info.min) / (info.max - info.min)
+    }
+
+    pub fn set_normalized(&self, normalized: f32) {
+        let info = &self.shared.info;
+        self.set(info.min + normalized * (info.max - info.min));
+    }
+
+    /// The value with its unit, e.g. "-6.0 dB".
+    pub fn display(&self) -> String {
+        let suffix = self.shared.info.unit.suffix();
+
+        match self.shared.info.unit {
+            Unit::None => format!("{:.2}", self.get()),
+            Unit::Percent | Unit::Ratio => format!("{:.1}{}", self.get(), suffix),
+            _ => format!("{:.1} {}", self.get(), suffix),
+        }
+    }
+}
+
+/// A parameter as a processor sees it on the audio thread: the value set
+/// through its `ParamHandle`s, smoothed. Read it once per sample with
+/// `next_value` or once per block with `advance`.
+pub struct Param {
+    shared: Arc<SharedParam>,
+    target: f32,
+    current: f32,
+    // Frames a linear ramp takes and how far through the current one it is
+    ramp_frames: u32,
+    ramp_left: u32,
+    step: f32,
+    // How much of the distance to the target is left after each frame
+    coefficient: f32,
+}
+
+impl Param {
+    /// Starts at `info.default`.
+    pub fn new(info: ParamInfo) -> Self {
+        let default = info.default;
+        Self::with_value(info, default)
+    }
+
+    /// Starts at `value`, clamped to the range, e.g. from a processor's
+    /// settings.
+    pub fn with_value(info: ParamInfo, value: f32) -> Self {
+        let value = value.clamp(info.min, info.max);
+
+        Param {
+            shared: Arc::new(SharedParam {
+                info,
+                value: AtomicU32::new(value.to_bits()),
+                ramp: AtomicU32::new(NO_RAMP),
+            }),
+            target: value,
+            current: value,
+            ramp_frames: 0,
+            ramp_left: 0,
+            step: 0f32,
+            coefficient: 0f32,
+        }
+    }
+
+    pub fn handle(&self) -> ParamHandle {
+        ParamHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    pub fn info(&self) -> &ParamInfo {
+        &self.shared.info
+    }
+
+    /// Works out how long smoothing takes at `sample_rate`.
+    pub fn prepare(&mut self, sample_rate: u32) {
+        let frames = |ms: f32| (ms / 1000f32 * sample_rate as f32).round().max(0f32);
+
+        match self.shared.info.smoothing {
+            Smoothing::None => {
+                self.ramp_frames = 0;
+                self.coefficient = 0f32;
+            }
+            Smoothing::Linear(ms) => {
+                self.ramp_frames = frames(ms) as u32;
+                self.coefficient = 0f32;
+            }
+            Smoothing::Exponential(ms) => {
+                self.ramp_frames = 0;
+                self.coefficient = match frames(ms) {
+                    f if f > 0f32 => (0.01f32.ln() / f).exp(),
+                    _ => 0f32,
+                };
+            }
+        }
+
+        self.reset();
+    }
+
+    /// The value the parameter is at now.
+    pub fn value(&self) -> f32 {
+        self.current
+    }
+
+    /// Whether it is still on its way to the value last set.
+    pub fn is_smoothing(&self) -> bool {
+        self.current != self.target || self.load() != self.target
+    }
+
+    /// Moves one frame on and returns the value for that frame.
+    #[inline]
+    pub fn next_value(&mut self) -> f32 {
+        self.advance(1)
+    }
+
+    /// Moves `frames` on and returns the value at the end, for processors
+    /// that only update once a block.
+    pub fn advance(&mut self, frames: usize) -> f32 {
+        let ramp = self.shared.ramp.load(Ordering::Acquire);
+        if ramp == 0 {
+            self.reset();
+            return self.current;
+        }
+
+        let target = self.load();
+        if ramp != NO_RAMP {
+            self.shared.ramp.store(NO_RAMP, Ordering::Relaxed);
+            self.target = target;
+            self.ramp_left = ramp;
+            self.step = (target - self.current) / ramp as f32;
+        } else if target != self.target {
+            self.target = target;
+            self.ramp_left = self.ramp_frames;
+            if self.ramp_frames > 0 {
+                self.step = (target - self.current) / self.ramp_frames as f32;
+            }
+        }
+
+        if self.current == self.target {
+            return self.current;
+        }
+
+        match self.shared.info.smoothing {
+            // Linear smoothing or a ramp asked for through the handle
+            _ if self.ramp_left as usize > frames => {
+                self.ramp_left -= frames as u32;
+                self.current += self.step * frames as f32;
+            }
+            Smoothing::Exponential(_) if self.ramp_left == 0 => {
+                let left = (self.current - self.target) * self.coefficient.powi(frames as i32);
+                let range = self.shared.info.max - self.shared.info.min;
+
+                self.current = if left.abs() > range * 1e-5 {
+                    self.target + left
+                } else {
+                    self.target
+                };
+            }
+            _ => {
+                self.ramp_left = 0;
+                self.current = self.target;
+            }
+        }
+
+        self.current
+    }
+
+    /// Jumps straight to the value last set.
+    pub fn reset(&mut self) {
+        self.shared.ramp.store(NO_RAMP, Ordering::Relaxed);
+        self.target = self.load();
+        self.current = self.target;
+        self.ramp_left = 0;
+    }
+
+    fn load(&self) -> f32 {
+        f32::from_bits(self.shared.value.load(Ordering::Relaxed))
+    }
+}
+
+/// Handles to the parameters of every insert of a playback.
+#[derive(Clone, Default)]
+pub struct InsertParams {
+    // Per channel or bus, per insert
+    channels: Vec<Vec<Vec<ParamHandle>>>,
+    buses: Vec<Vec<Vec<ParamHandle>>>,
+}
+
+impl InsertParams {
+    pub fn new(channels: Vec<Vec<Vec<ParamHandle>>>, buses: Vec<Vec<Vec<ParamHandle>>>) -> Self {
+        InsertParams { channels, buses }
+    }
+
+    /// Parameters of insert `insert` on channel `channel`, in the order the
+    /// processor lists them.
+    pub fn get(&self, channel: usize, insert: usize) -> &[ParamHandle] {
+        &self.channels[channel][insert]
+    }
+
+    /// The parameter called `name` of insert `insert` on channel `channel`.
+    pub fn find(&self, channel: usize, insert: usize, name: &str) -> Option<&ParamHandle> {
+        self.channels
+            .get(channel)?
+            .get(insert)?
+            .iter()
+            .find(|param| param.info().name == name)
+    }
+
+    /// Parameters of insert `insert` on bus `bus`, an index into
+    /// `MixerModel::buses`.
+    pub fn get_bus(&self, bus: usize, insert: usize) -> &[ParamHandle] {
+        &self.buses[bus][insert]
+    }
+
+    /// The parameter called `name` of insert `insert` on bus `bus`.
+    pub fn find_bus(&self, bus: usize, insert: usize, name: &str) -> Option<&ParamHandle> {
+        self.buses
+            .get(bus)?
+            .get(insert)?
+            .iter()
+            .find(|param| param.info().name == name)
+    }
+}
+
+#[cfg(test)]
+mod param_tests {
+    use crate::param::*;
+
+    fn param(smoothing: Smoothing) -> Param {
+        let mut param = Param::new(ParamInfo {
+            name: "Level".to_string(),
+            unit: Unit::Decibels,
+            min: -60f32,
+            max: 12f32,
+            default: 0f32,
+            smoothing,
+        });
+        param.prepare(1000);
+
+        param
+    }
+
+    #[test]
+    fn starts_at_the_value_given() {
+        let info = ParamInfo {
+            name: "Level".to_string(),
+            unit: Unit::Decibels,
+            min: -60f32,
+            max: 12f32,
+            default: 0f32,
+            smoothing: Smoothing::default(),
+        };
+
+        let param = Param::with_value(info.clone(), -6f32);
+        assert_eq!(param.value(), -6f32);
+        assert_eq!(param.handle().get(), -6f32);
+        assert!(!param.is_smoothing());
+
+        assert_eq!(Param::with_value(info, 20f32).value(), 12f32);
+    }
+
+    #[test]
+    fn linear_ramps_to_the_new_value() {
+        // 10 ms is 10 frames at 1 kHz
+        let mut param = param(Smoothing::Linear(10f32));
+        param.handle().set(-10f32);
+
+        let values: Vec<f32> = (0..12).map(|_| param.next_value()).collect();
+        assert!((values[0] - -1f32).abs() < 1e-5);
+        assert!((values[4] - -5f32).abs() < 1e-5);
+        assert_eq!(values[9], -10f32);
+        assert_eq!(values[11], -10f32);
+        assert!(!param.is_smoothing());
+
+        // A block at a time lands in the same place
+        param.handle().set(0f32);
+        assert!((param.advance(5) - -5f32).abs() < 1e-5);
+        assert_eq!(param.advance(5), 0f32);
+    }
+
+    #[test]
+    fn set_immediate_skips_smoothing() {
+        let mut param = param(Smoothing::Linear(10f32));
+        param.handle().set_immediate(-10f32);
+
+        assert!(param.is_smoothing());
+        assert_eq!(param.next_value(), -10f32);
+        assert!(!param.is_smoothing());
+
+        // Setting normally smooths again
+        param.handle().set(0f32);
+        assert!((param.next_value() - -9f32).abs() < 1e-5);
+    }
+
+    #[test]
+    fn ramp_to_takes_exactly_the_frames_given() {
+        // Even over exponential smoothing
+        let mut param = param(Smoothing::Exponential(10f32));
+        param.handle().ramp_to(-4f32, 4);
+
+        let values: Vec<f32> = (0..5).map(|_| param.next_value()).collect();
+        assert_eq!(values, vec![-1f32, -2f32, -3f32, -4f32, -4f32]);
+        assert!(!param.is_smoothing());
+
+        // The next ramp starts from wherever the last one got to
+        param.handle().ramp_to(0f32, 8);
+        assert!((param.advance(2) - -3f32).abs() < 1e-5);
+        param.handle().ramp_to(-3f32, 2);
+        assert_eq!(param.advance(2), -3f32);
+    }
+
+    #[test]
+    fn exponential_gets_most_of_the_way_in_time() {
+        let mut param = param(Smoothing::Exponential(10f32));
+        param.handle().set(-10f32);
+
+        let first = param.next_value();
+        assert!(first < 0f32 && first > -10f32);
+        assert!((param.advance(9) - -9.9f32).abs() < 1e-3);
+
+        // And eventually arrives
+        param.advance(1000);
+        assert_eq!(param.value(), -10f32);
+    }
+
+    #[test]
+    fn handle_clamps_and_maps() {
+        let mut param = param(Smoothing::None);
+        let handle = param.handle();
+
+        handle.set(100f32);
+        assert_eq!(handle.get(), 12f32);
+        assert_eq!(param.next_value(), 12f32);
+
+        handle.set_normalized(0f32);
+        assert_eq!(handle.get(), -60f32);
+        assert_eq!(handle.normalized(), 0f32);
+        assert_eq!(handle.display(), "-60.0 dB");
+
+        handle.set_to_default();
+        assert_eq!(param.next_value(), 0f32);
+    }
+
+    #[test]
+    fn set_from_another_thread() {
+        let mut param = param(Smoothing::None);
+        let handle = param.handle();
+
+        std::thread::spawn(move || handle.set(-3f32))
+            .join()
+            .unwrap();
+
+        assert_eq!(param.next_value(), -3f32);
+    }
+}
@@ -0,0 +1,189 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crossbeam::channel::Receiver;
+
+/// Where the transport's output lines up with the timeline. The transport
+/// sends one whenever that changes, i.e. on load, play, pause, stop and seek.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PlayheadMarker {
+    /// Frame of the transport's output, counted from when it started, that
+    /// the marker applies from
+    pub output_frame: u64,
+    /// Timeline frame at that point
+    pub timeline_frame: u64,
+    /// Whether the timeline moves on from there or holds still
+    pub playing: bool,
+}
+
+/// The timeline position that can currently be heard. Written by the audio
+/// callback and safe to read from any thread without locking.
+pub struct Playhead {
+    frames: AtomicU64,
+    latency: AtomicU64,
+    sample_rate: u32,
+}
+
+impl Playhead {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        Playhead {
+            frames: AtomicU64::new(0),
+            latency: AtomicU64::new(0),
+            sample_rate,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Timeline frame coming out of the device
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Acquire)
+    }
+
+    pub fn ms(&self) -> u64 {
+        self.frames() * 1000 / self.sample_rate as u64
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.frames() as f64 / self.sample_rate as f64
+    }
+
+    /// Frames between the callback and the device, as last reported by the
+    /// output stream
+    pub fn latency_frames(&self) -> u64 {
+        self.latency.load(Ordering::Relaxed)
+    }
+}
+
+/// The audio callback's half of the playhead. Counts the frames taken from the
+/// transport and maps them onto the timeline with the transport's markers.
+pub(crate) struct PlayheadTracker {
+    playhead: Arc<Playhead>,
+    markers: Receiver<PlayheadMarker>,
+    // The marker in effect and the next one, if it hasn't been reached yet
+    current: PlayheadMarker,
+    next: Option<PlayheadMarker>,
+    // Frames of the transport's output delivered so far
+    delivered: u64,
+}
+
+impl PlayheadTracker {
+    pub fn new(playhead: Arc<Playhead>, markers: Receiver<PlayheadMarker>) -> Self {
+        PlayheadTracker {
+            playhead,
+            markers,
+            current: PlayheadMarker {
+                output_frame: 0,
+                timeline_frame: 0,
+                playing: false,
+            },
+            next: None,
+            delivered: 0,
+        }
+    }
+
+    /// Updates the playhead for the start of a buffer of `frames` that will
+    /// be heard `latency_frames` from now.
+    pub fn advance(&mut self, frames: u64, latency_frames: u64) {
+        // The frames still in the device were delivered earlier
+        let audible = self.delivered.saturating_sub(latency_frames);
+
+        while let Some(next) = self.next.take().or_else(|| self.markers.try_recv().ok()) {
+            if next.output_frame > audible {
+                self.next = Some(next);
+                break;
+            }
+
+            self.current = next;
+        }
+
+        let marker = &self.current;
+        let frames_on = match marker.playing {
+            true => audible.saturating_sub(marker.output_frame),
+            false => 0,
+        };
+
+        self.playhead
+            .frames
+            .store(marker.timeline_frame + frames_on, Ordering::Release);
+        self.playhead
+            .latency
+            .store(latency_frames, Ordering::Relaxed);
+
+        self.delivered += frames;
+    }
+}
+
+#[cfg(test)]
+mod playhead_tests {
+    use crate::playhead::*;
+    use crossbeam::channel::unbounded;
+
+    fn marker(output_frame: u64, timeline_frame: u64, playing: bool) -> PlayheadMarker {
+        PlayheadMarker {
+            output_frame,
+            timeline_frame,
+            playing,
+        }
+    }
+
+    #[test]
+    fn follows_delivered_frames() {
+        let playhead = Arc::new(Playhead::new(1000));
+        let (tx, rx) = unbounded();
+        let mut tracker = PlayheadTracker::new(playhead.clone(), rx);
+
+        tracker.advance(64, 0);
+        assert_eq!(playhead.frames(), 0);
+
+        tx.send(marker(64, 0, true)).unwrap();
+        tracker.advance(64, 0);
+        assert_eq!(playhead.frames(), 0);
+        tracker.advance(64, 0);
+        assert_eq!(playhead.frames(), 64);
+        tracker.advance(64, 0);
+        assert_eq!(playhead.frames(), 128);
+        assert_eq!(playhead.ms(), 128);
+        assert_eq!(playhead.seconds(), 0.128);
+    }
+
+    #[test]
+    fn latency_holds_the_playhead_back() {
+        let playhead = Arc::new(Playhead::new(1000));
+        let (tx, rx) = unbounded();
+        let mut tracker = PlayheadTracker::new(playhead.clone(), rx);
+        tx.send(marker(0, 500, true)).unwrap();
+
+        tracker.advance(100, 30);
+        assert_eq!(playhead.frames(), 500);
+        tracker.advance(100, 30);
+        assert_eq!(playhead.frames(), 570);
+        assert_eq!(playhead.latency_frames(), 30);
+    }
+
+    #[test]
+    fn markers_apply_once_reached() {
+        let playhead = Arc::new(Playhead::new(1000));
+        let (tx, rx) = unbounded();
+        let mut tracker = PlayheadTracker::new(playhead.clone(), rx);
+
+        // Play from the start, pause at 100, then seek to 1000 and play
+        tx.send(marker(0, 0, true)).unwrap();
+        tx.send(marker(100, 100, false)).unwrap();
+        tx.send(marker(150, 1000, true)).unwrap();
+
+        tracker.advance(50, 0);
+        tracker.advance(50, 0);
+        assert_eq!(playhead.frames(), 50);
+        tracker.advance(50, 0);
+        assert_eq!(playhead.frames(), 100);
+        tracker.advance(50, 0);
+        assert_eq!(playhead.frames(), 1000);
+        tracker.advance(50, 0);
+        assert_eq!(playhead.frames(), 1050);
+    }
+}
@@ -0,0 +1,293 @@
+use std::convert::Infallible;
+
+use crate::builder::{block_size, Playback};
+
+/// Somewhere for rendered audio to go. Samples are always interleaved and
+/// handed over a block at a time.
+pub trait RenderSink {
+    type Error;
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), Self::Error>;
+}
+
+impl RenderSink for Vec<f32> {
+    type Error = Infallible;
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), Self::Error> {
+        self.extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+/// Renders a `Playback` as fast as it can be mixed, without an output device.
+///
+/// This pulls blocks through `Playback::render`, the same mixing path the
+/// realtime engine uses, so an offline render is sample for sample what would
+/// have been sent to the device.
+pub struct OfflineRenderer {
+    playback: Playback,
+    block: Vec<f32>,
+    frames_rendered: u64,
+}
+
+impl OfflineRenderer {
+    pub fn new(playback: Playback) -> Self {
+        let block = vec![0f32; block_size(playback.config()) * playback.channel_count()];
+
+        OfflineRenderer {
+            playback,
+            block,
+            frames_rendered: 0,
+        }
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.playback.channel_count()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.playback.config().sample_rate.0
+    }
+
+    /// Total number of frames rendered so far.
+    pub fn frames_rendered(&self) -> u64 {
+        self.frames_rendered
+    }
+
+    /// Renders the next frames into a caller supplied interleaved buffer.
+    /// Returns the number of frames that had audio in them, 0 once the
+    /// playback has finished. Unlike `render_to` this is the master bus's
+    /// output as it is, starting with its latency.
+    pub fn render(&mut self, out: &mut [f32]) -> usize {
+        let frames = self.playback.render(out);
+        self.frames_rendered += frames as u64;
+
+        frames
+    }
+
+    /// Renders the whole playback into `sink` and returns the number of frames
+    /// written. The final block is trimmed so no trailing silence is written,
+    /// and whatever the master bus delays the mix by is left off the start so
+    /// the file starts at the start of the timeline.
+    pub fn render_to<S: RenderSink>(&mut self, sink: &mut S) -> Result<u64, S::Error> {
+        let channel_count = self.channel_count();
+        let mut skip = self.playback.master().latency();
+        let mut written = 0u64;
+
+        loop {
+            let frames = self.playback.render(&mut self.block);
+
+            if frames == 0 {
+                break;
+            }
+
+            let skipped = skip.min(frames);
+            skip -= skipped;
+
+            sink.write(&self.block[skipped * channel_count..frames * channel_count])?;
+            self.frames_rendered += (frames - skipped) as u64;
+            written += (frames - skipped) as u64;
+        }
+
+        Ok(written)
+    }
+
+    /// Renders the whole playback in a single pass, writing the mix to
+    /// `master` and each channel to the sink at the same index in `stems`
+    /// (see `Playback::channel_ids`). Every sink gets exactly the same number
+    /// of frames, lined up with each other like `render_to` lines the mix up
+    /// with the timeline.
+    pub fn render_stems_to<S: RenderSink>(
+        &mut self,
+        master: &mut S,
+        stems: &mut [S],
+    ) -> Result<u64, S::Error> {
+        let channel_count = self.channel_count();
+        let mut stem_blocks = vec![Vec::with_capacity(self.block.len()); stems.len()];
+        // The stems are taken before the buses and master bus. The master's
+        // latency is left off the mix, which leaves the stems ahead of it by
+        // the buses' latency, so they start that much later.
+        let held = self.playback.bus_latency() * channel_count;
+        let mut pending = vec![vec![0f32; held]; stems.len()];
+        let mut skip = self.playback.master().latency();
+        let mut written = 0u64;
+
+        loop {
+            let frames = self
+                .playback
+                .render_with_stems(&mut self.block, &mut stem_blocks);
+
+            if frames == 0 {
+                break;
+            }
+
+            let skipped = skip.min(frames);
+            skip -= skipped;
+
+            let samples = frames * channel_count;
+            master.write(&self.block[skipped * channel_count..samples])?;
+
+            let ready = (frames - skipped) * channel_count;
+            for ((sink, block), pending) in stems
+                .iter_mut()
+                .zip(stem_blocks.iter())
+                .zip(pending.iter_mut())
+            {
+                pending.extend_from_slice(&block[..samples]);
+                sink.write(&pending[..ready])?;
+                pending.drain(..ready);
+            }
+
+            self.frames_rendered += (frames - skipped) as u64;
+            written += (frames - skipped) as u64;
+        }
+
+        Ok(written)
+    }
+
+    pub fn into_playback(self) -> Playback {
+        self.playback
+    }
+}
+
+#[cfg(test)]
+mod render_tests {
+    use crate::builder::{
+        BusModel, ChannelModel, ClipModel, InsertModel, MixerModel, PlaybackBuilder,
+    };
+    use crate::dynamics::CompressorSettings;
+    use crate::render::*;
+    use cpal::{BufferSize, SampleRate, StreamConfig};
+
+    fn config() -> StreamConfig {
+        StreamConfig {
+            channels: 2,
+            sample_rate: SampleRate(44100),
+            buffer_size: BufferSize::Fixed(512),
+        }
+    }
+
+    fn mixer(paths: &[&str]) -> MixerModel {
+        MixerModel {
+            channels: paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| ChannelModel {
+                    id: format!("chan-{}", i),
+                    clips: vec![ClipModel {
+                        path: path.to_string(),
+                        start_time_ms: 0,
+                        duration_ms: 2000,
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn render(mixer: &MixerModel) -> Vec<f32> {
+        let playback = PlaybackBuilder::new(mixer, config()).unwrap();
+        let mut renderer = OfflineRenderer::new(playback);
+        let mut out = Vec::new();
+
+        let frames = renderer.render_to(&mut out).unwrap();
+        assert_eq!(out.len() as u64, frames * 2);
+        assert_eq!(renderer.frames_rendered(), frames);
+
+        out
+    }
+
+    #[test]
+    fn renders_until_the_clip_ends() {
+        let out = render(&mixer(&["sounds/sample-2.wav"]));
+
+        assert_eq!(out.len(), 88200 * 2);
+    }
+
+    #[test]
+    fn mono_source_is_copied_to_both_channels() {
+        let out = render(&mixer(&["sounds/sample-2.wav"]));
+
+        for frame in out.chunks(2) {
+            assert_eq!(frame[0], frame[1]);
+        }
+    }
+
+    #[test]
+    fn render_is_deterministic() {
+        let mixer = mixer(&["sounds/sample-1.wav", "sounds/sample-2.wav"]);
+
+        assert_eq!(render(&mixer), render(&mixer));
+    }
+
+    #[test]
+    fn render_sums_channels() {
+        let one = render(&mixer(&["sounds/sample-2.wav"]));
+        let two = render(&mixer(&["sounds/sample-2.wav", "sounds/sample-2.wav"]));
+
+        assert_eq!(one.len(), two.len());
+        for (a, b) in one.iter().zip(two.iter()) {
+            assert_eq!(a * 2f32, *b);
+        }
+    }
+
+    #[test]
+    fn stems_sum_to_master() {
+        let mixer = mixer(&["sounds/sample-1.wav", "sounds/sample-2.wav"]);
+        let playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+        let mut renderer = OfflineRenderer::new(playback);
+        let mut master = Vec::new();
+        let mut stems = vec![Vec::new(), Vec::new()];
+
+        let frames = renderer.render_stems_to(&mut master, &mut stems).unwrap();
+
+        assert_eq!(master, render(&mixer));
+        for stem in stems.iter() {
+            assert_eq!(stem.len() as u64, frames * 2);
+        }
+        for (i, sample) in master.iter().enumerate() {
+            assert_eq!(*sample, stems[0][i] + stems[1][i]);
+        }
+    }
+
+    #[test]
+    fn stems_line_up_with_master_through_a_latent_bus() {
+        let mut mixer = mixer(&["sounds/sample-1.wav", "sounds/sample-2.wav"]);
+        mixer.channels[1].output = Some("look-ahead".to_string());
+        // Compresses nothing, only looks ahead
+        mixer.buses.push(BusModel {
+            id: "look-ahead".to_string(),
+            inserts: vec![InsertModel::Compressor(CompressorSettings {
+                ratio: 1f32,
+                lookahead_ms: 5f32,
+                ..Default::default()
+            })],
+            ..Default::default()
+        });
+        let playback = PlaybackBuilder::new(&mixer, config()).unwrap();
+        assert!(playback.bus_latency() > 0);
+        let mut renderer = OfflineRenderer::new(playback);
+        let mut master = Vec::new();
+        let mut stems = vec![Vec::new(), Vec::new()];
+
+        renderer.render_stems_to(&mut master, &mut stems).unwrap();
+
+        for (i, sample) in master.iter().enumerate() {
+            assert!((*sample - (stems[0][i] + stems[1][i])).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn render_into_buffer_finishes_with_silence() {
+        let playback = PlaybackBuilder::new(&mixer(&["sounds/sample-2.wav"]), config()).unwrap();
+        let mut renderer = OfflineRenderer::new(playback);
+        let mut block = vec![1f32; 4096];
+
+        while renderer.render(&mut block) > 0 {}
+
+        assert!(block.iter().all(|s| *s == 0f32));
+        assert_eq!(renderer.render(&mut block), 0);
+    }
+}
@@ -0,0 +1,428 @@
+use crate::{
+    mixer::Processor,
+    param::{Param, ParamHandle, ParamInfo, Smoothing, Unit},
+};
+
+/// Comb and all-pass lengths at 44.1 kHz and a size of 1 (Freeverb's)
+const COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALL_PASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+/// How much longer the right side's delays are, so it doesn't match the left
+const STEREO_SPREAD: usize = 23;
+
+const MAX_PRE_DELAY_MS: f32 = 500f32;
+const MAX_SIZE: f32 = 2f32;
+
+/// Keeps the eight combs summed from clipping
+const INPUT_GAIN: f32 = 0.015;
+const WET_GAIN: f32 = 3f32;
+const ALL_PASS_FEEDBACK: f32 = 0.5;
+/// Damping of 1 is as dull as the combs get
+const MAX_DAMPING: f32 = 0.4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReverbSettings {
+    /// Gap before the reverb starts
+    pub pre_delay_ms: f32,
+    /// How long the reverb takes to die away by 60 dB
+    pub decay_ms: f32,
+    /// How much quicker the highs die away, from 0 to 1
+    pub damping: f32,
+    /// How big the room sounds, from 0.5 to 2
+    pub size: f32,
+    /// How much of the output is reverb rather than the input, from 0 to 1.
+    /// Fully wet by default, for an aux bus fed by sends.
+    pub mix: f32,
+}
+
+impl Default for ReverbSettings {
+    fn default() -> Self {
+        ReverbSettings {
+            pre_delay_ms: 20f32,
+            decay_ms: 2000f32,
+            damping: 0.5,
+            size: 1f32,
+            mix: 1f32,
+        }
+    }
+}
+
+/// A delay line with a fixed buffer that can be read back any length up to
+/// the buffer's.
+struct Line {
+    buffer: Vec<f32>,
+    write: usize,
+}
+
+impl Line {
+    fn new(length: usize) -> Self {
+        Line {
+            buffer: vec![0f32; length.max(1)],
+            write: 0,
+        }
+    }
+
+    #[inline]
+    fn read(&self, delay: usize) -> f32 {
+        let length = self.buffer.len();
+        self.buffer[(self.write + length - delay.min(length)) % length]
+    }
+
+    #[inline]
+    fn write(&mut self, sample: f32) {
+        self.buffer[self.write] = sample;
+        self.write = (self.write + 1) % self.buffer.len();
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0f32);
+        self.write = 0;
+    }
+}
+
+/// A feedback comb with a low-pass in its loop.
+struct Comb {
+    line: Line,
+    base_length: usize,
+    length: usize,
+    feedback: f32,
+    filter: f32,
+}
+
+impl Comb {
+    #[inline]
+    fn process(&mut self, input: f32, damping: f32) -> f32 {
+        let output = self.line.read(self.length);
+        self.filter = output * (1f32 - damping) + self.filter * damping;
+        self.line.write(input + self.filter * self.feedback);
+        output
+    }
+}
+
+struct AllPass {
+    line: Line,
+    base_length: usize,
+    length: usize,
+}
+
+impl AllPass {
+    #[inline]
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.line.read(self.length);
+        self.line.write(input + delayed * ALL_PASS_FEEDBACK);
+        delayed - input
+    }
+}
+
+/// One side of the reverb: parallel combs into all-passes in series.
+struct Tank {
+    combs: Vec<Comb>,
+    all_passes: Vec<AllPass>,
+}
+
+impl Tank {
+    fn new(sample_rate: u32, spread: usize) -> Self {
+        let scale = sample_rate as f32 / 44100f32;
+        let length = |base: usize| ((base + spread) as f32 * scale).round() as usize;
+
+        Tank {
+            combs: COMB_LENGTHS
+                .iter()
+                .map(|base| Comb {
+                    line: Line::new((length(*base) as f32 * MAX_SIZE) as usize + 1),
+                    base_length: length(*base),
+                    length: length(*base),
+                    feedback: 0f32,
+                    filter: 0f32,
+                })
+                .collect(),
+            all_passes: ALL_PASS_LENGTHS
+                .iter()
+                .map(|base| AllPass {
+                    line: Line::new((length(*base) as f32 * MAX_SIZE) as usize + 1),
+                    base_length: length(*base),
+                    length: length(*base),
+                })
+                .collect(),
+        }
+    }
+
+    /// Sets the delay lengths for `size` and each comb's feedback so it dies
+    /// away by 60 dB in `decay` frames.
+    fn tune(&mut self, size: f32, decay: f32) {
+        for comb in self.combs.iter_mut() {
+            comb.length = ((comb.base_length as f32 * size) as usize).max(1);
+            comb.feedback = 10f32.powf(-3f32 * comb.length as f32 / decay.max(1f32));
+        }
+        for all_pass in self.all_passes.iter_mut() {
+            all_pass.length = ((all_pass.base_length as f32 * size) as usize).max(1);
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, input: f32, damping: f32) -> f32 {
+        let mut output = 0f32;
+        for comb in self.combs.iter_mut() {
+            output += comb.process(input, damping);
+        }
+        for all_pass in self.all_passes.iter_mut() {
+            output = all_pass.process(output);
+        }
+        output
+    }
+
+    fn clear(&mut self) {
+        for comb in self.combs.iter_mut() {
+            comb.line.clear();
+            comb.filter = 0f32;
+        }
+        for all_pass in self.all_passes.iter_mut() {
+            all_pass.line.clear();
+        }
+    }
+}
+
+/// An algorithmic reverb after Freeverb, with its decay set as a time. Every
+/// channel is mixed into it and it comes out in stereo, the left side on even
+/// channels and the right on odd ones.
+pub struct Reverb {
+    pre_delay_ms: Param,
+    decay_ms: Param,
+    damping: Param,
+    size: Param,
+    mix: Param,
+    sample_rate: u32,
+    channel_count: usize,
+    pre_delay: Line,
+    tanks: [Tank; 2],
+}
+
+impl Reverb {
+    pub fn new(settings: &ReverbSettings) -> Self {
+        let param = |name: &str, unit, min, max, value, smoothing| {
+            let info = ParamInfo {
+                name: name.to_string(),
+                unit,
+                min,
+                max,
+                default: value,
+                smoothing,
+            };
+            Param::with_value(info, value)
+        };
+
+        Reverb {
+            pre_delay_ms: param(
+                "Pre-delay",
+                Unit::Milliseconds,
+                0f32,
+                MAX_PRE_DELAY_MS,
+                settings.pre_delay_ms,
+                Smoothing::None,
+            ),
+            decay_ms: param(
+                "Decay",
+                Unit::Milliseconds,
+                100f32,
+                30000f32,
+                settings.decay_ms,
+                Smoothing::None,
+            ),
+            damping: param(
+                "Damping",
+                Unit::None,
+                0f32,
+                1f32,
+                settings.damping,
+                Smoothing::default(),
+            ),
+            size: param(
+                "Size",
+                Unit::None,
+                0.5,
+                MAX_SIZE,
+                settings.size,
+                Smoothing::None,
+            ),
+            mix: param(
+                "Mix",
+                Unit::None,
+                0f32,
+                1f32,
+                settings.mix,
+                Smoothing::default(),
+            ),
+            sample_rate: 44100,
+            channel_count: 1,
+            pre_delay: Line::new(1),
+            tanks: [Tank::new(44100, 0), Tank::new(44100, STEREO_SPREAD)],
+        }
+    }
+
+    fn params_mut(&mut self) -> [&mut Param; 5] {
+        [
+            &mut self.pre_delay_ms,
+            &mut self.decay_ms,
+            &mut self.damping,
+            &mut self.size,
+            &mut self.mix,
+        ]
+    }
+}
+
+impl Processor for Reverb {
+    fn prepare(&mut self, sample_rate: u32, channel_count: usize, _max_block_frames: usize) {
+        self.sample_rate = sample_rate;
+        self.channel_count = channel_count;
+
+        for param in self.params_mut() {
+            param.prepare(sample_rate);
+        }
+
+        let pre_delay = (MAX_PRE_DELAY_MS / 1000f32 * sample_rate as f32) as usize + 1;
+        self.pre_delay = Line::new(pre_delay);
+        self.tanks = [
+            Tank::new(sample_rate, 0),
+            Tank::new(sample_rate, STEREO_SPREAD),
+        ];
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        let channel_count = self.channel_count;
+        let frames = samples.len() / channel_count;
+        let sample_rate = self.sample_rate as f32;
+
+        // Changing these means retuning, so they only move once a block
+        let pre_delay = (self.pre_delay_ms.advance(frames) / 1000f32 * sample_rate) as usize;
+        let decay = self.decay_ms.advance(frames) / 1000f32 * sample_rate;
+        let size = self.size.advance(frames);
+        for tank in self.tanks.iter_mut() {
+            tank.tune(size, decay);
+        }
+
+        for frame in samples.chunks_exact_mut(channel_count) {
+            let damping = self.damping.next_value() * MAX_DAMPING;
+            let mix = self.mix.next_value();
+
+            let input = frame.iter().sum::<f32>() / channel_count as f32;
+            let delayed = if pre_delay > 0 {
+                self.pre_delay.read(pre_delay)
+            } else {
+                input
+            };
+            self.pre_delay.write(input);
+
+            let left = self.tanks[0].process(delayed * INPUT_GAIN, damping) * WET_GAIN;
+            let right = self.tanks[1].process(delayed * INPUT_GAIN, damping) * WET_GAIN;
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let wet = if channel % 2 == 0 { left } else { right };
+                *sample += (wet - *sample) * mix;
+            }
+        }
+    }
+
+    fn tail(&self) -> usize {
+        let ms = self.pre_delay_ms.value() + self.decay_ms.value();
+        (ms / 1000f32 * self.sample_rate as f32).ceil() as usize
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        [
+            &self.pre_delay_ms,
+            &self.decay_ms,
+            &self.damping,
+            &self.size,
+            &self.mix,
+        ]
+        .iter()
+        .map(|param| param.handle())
+        .collect()
+    }
+
+    fn reset(&mut self) {
+        for param in self.params_mut() {
+            param.reset();
+        }
+
+        self.pre_delay.clear();
+        for tank in self.tanks.iter_mut() {
+            tank.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod reverb_tests {
+    use crate::reverb::*;
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    fn impulse_response(settings: &ReverbSettings, frames: usize) -> Vec<f32> {
+        let mut reverb = Reverb::new(settings);
+        reverb.prepare(44100, 2, 512);
+
+        let mut samples = vec![0f32; frames * 2];
+        samples[0] = 1f32;
+        samples[1] = 1f32;
+        for block in samples.chunks_mut(1024) {
+            reverb.process(block);
+        }
+        samples
+    }
+
+    #[test]
+    fn waits_for_the_pre_delay() {
+        let samples = impulse_response(
+            &ReverbSettings {
+                pre_delay_ms: 100f32,
+                ..Default::default()
+            },
+            44100,
+        );
+
+        // Nothing until the pre-delay and the shortest comb have gone by
+        let silent = 4410 + 1116;
+        assert!(samples[..silent * 2].iter().all(|s| *s == 0f32));
+        assert!(samples[silent * 2..].iter().any(|s| s.abs() > 1e-4));
+    }
+
+    #[test]
+    fn decays_at_the_rate_set() {
+        let samples = impulse_response(
+            &ReverbSettings {
+                pre_delay_ms: 0f32,
+                decay_ms: 1000f32,
+                damping: 0f32,
+                ..Default::default()
+            },
+            44100 * 2,
+        );
+
+        // 60 dB a second is 30 dB over half a second
+        let window = |ms: usize| {
+            let start = ms * 441 / 10 * 2;
+            rms(&samples[start..start + 4410 * 2])
+        };
+        let fall = 20f32 * (window(200) / window(700)).log10();
+        assert!((fall - 30f32).abs() < 5f32, "fell {} dB", fall);
+
+        // Left and right differ
+        assert!(samples[8820..]
+            .chunks_exact(2)
+            .any(|frame| (frame[0] - frame[1]).abs() > 1e-4));
+    }
+
+    #[test]
+    fn tail_covers_pre_delay_and_decay() {
+        let mut reverb = Reverb::new(&ReverbSettings {
+            pre_delay_ms: 50f32,
+            decay_ms: 1500f32,
+            ..Default::default()
+        });
+        reverb.prepare(48000, 2, 512);
+
+        assert_eq!(reverb.tail(), 74400);
+    }
+}
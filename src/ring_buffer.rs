@@ -0,0 +1,264 @@
+use std::{
+    cell::UnsafeCell,
+    ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Creates a lock-free single producer, single consumer queue of samples that
+/// holds at least `capacity` samples. Neither side ever blocks, which makes it
+/// safe to use from the audio callback.
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let buffer = (0..capacity).map(|_| UnsafeCell::new(0f32)).collect();
+
+    let shared = Arc::new(Shared {
+        buffer,
+        mask: capacity - 1,
+        read: AtomicUsize::new(0),
+        write: AtomicUsize::new(0),
+    });
+
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+struct Shared {
+    buffer: Box<[UnsafeCell<f32>]>,
+    mask: usize,
+    // Both only ever go up (wrapping), so `write - read` is how many samples
+    // are queued
+    read: AtomicUsize,
+    write: AtomicUsize,
+}
+
+// The producer only writes to the free part of the buffer and the consumer only
+// reads from the filled part, and they hand over with the `read` and `write`
+// atomics.
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn len(&self) -> usize {
+        let write = self.write.load(Ordering::Acquire);
+        let read = self.read.load(Ordering::Acquire);
+
+        write.wrapping_sub(read)
+    }
+
+    fn ptr(&self) -> *mut f32 {
+        UnsafeCell::raw_get(self.buffer.as_ptr())
+    }
+}
+
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+impl Producer {
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    /// Samples waiting to be popped
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Room left for samples
+    pub fn free_len(&self) -> usize {
+        self.capacity() - self.len()
+    }
+
+    /// Whether the consumer has been dropped
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+
+    /// Queues as many of `samples` as fit and returns how many that was.
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let shared = &self.shared;
+        let write = shared.write.load(Ordering::Relaxed);
+        let read = shared.read.load(Ordering::Acquire);
+        let count = samples
+            .len()
+            .min(shared.capacity() - write.wrapping_sub(read));
+
+        let start = write & shared.mask;
+        let first = count.min(shared.capacity() - start);
+
+        // Safe because `start..start + first` and `0..count - first` are in
+        // the free part of the buffer, which the consumer doesn't touch.
+        unsafe {
+            ptr::copy_nonoverlapping(samples.as_ptr(), shared.ptr().add(start), first);
+            ptr::copy_nonoverlapping(samples.as_ptr().add(first), shared.ptr(), count - first);
+        }
+
+        shared
+            .write
+            .store(write.wrapping_add(count), Ordering::Release);
+
+        count
+    }
+}
+
+pub struct Consumer {
+    shared: Arc<Shared>,
+}
+
+impl Consumer {
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    /// Samples ready to be popped
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fills as much of `out` as there are samples for and returns how many
+    /// that was.
+    pub fn pop(&mut self, out: &mut [f32]) -> usize {
+        let shared = &self.shared;
+        let read = shared.read.load(Ordering::Relaxed);
+        let write = shared.write.load(Ordering::Acquire);
+        let count = out.len().min(write.wrapping_sub(read));
+
+        let start = read & shared.mask;
+        let first = count.min(shared.capacity() - start);
+
+        // Safe because `start..start + first` and `0..count - first` are in
+        // the filled part of the buffer, which the producer doesn't touch.
+        unsafe {
+            ptr::copy_nonoverlapping(shared.ptr().add(start), out.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(shared.ptr(), out.as_mut_ptr().add(first), count - first);
+        }
+
+        shared
+            .read
+            .store(read.wrapping_add(count), Ordering::Release);
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod ring_buffer_tests {
+    use crate::ring_buffer::*;
+    use std::thread;
+
+    #[test]
+    fn capacity_is_rounded_up() {
+        let (producer, consumer) = ring_buffer(1000);
+
+        assert_eq!(producer.capacity(), 1024);
+        assert_eq!(consumer.capacity(), 1024);
+        assert_eq!(producer.free_len(), 1024);
+    }
+
+    #[test]
+    fn pops_what_was_pushed() {
+        let (mut producer, mut consumer) = ring_buffer(8);
+        let mut out = [0f32; 8];
+
+        assert_eq!(producer.push(&[1f32, 2f32, 3f32]), 3);
+        assert_eq!(consumer.len(), 3);
+
+        assert_eq!(consumer.pop(&mut out[..2]), 2);
+        assert_eq!(out[..2], [1f32, 2f32]);
+
+        // Only one left
+        assert_eq!(consumer.pop(&mut out), 1);
+        assert_eq!(out[0], 3f32);
+        assert!(consumer.is_empty());
+        assert_eq!(consumer.pop(&mut out), 0);
+    }
+
+    #[test]
+    fn push_stops_when_full() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        let mut out = [0f32; 4];
+
+        assert_eq!(producer.push(&[1f32, 2f32, 3f32, 4f32, 5f32]), 4);
+        assert_eq!(producer.free_len(), 0);
+        assert_eq!(producer.push(&[6f32]), 0);
+
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(out, [1f32, 2f32, 3f32, 4f32]);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        let mut out = [0f32; 4];
+
+        producer.push(&[1f32, 2f32, 3f32]);
+        consumer.pop(&mut out[..3]);
+
+        assert_eq!(producer.push(&[4f32, 5f32, 6f32, 7f32]), 4);
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(out, [4f32, 5f32, 6f32, 7f32]);
+    }
+
+    #[test]
+    fn knows_when_the_consumer_is_gone() {
+        let (producer, consumer) = ring_buffer(4);
+
+        assert!(!producer.is_abandoned());
+        drop(consumer);
+        assert!(producer.is_abandoned());
+    }
+
+    #[test]
+    fn keeps_order_across_threads() {
+        let (mut producer, mut consumer) = ring_buffer(64);
+        let total = 100_000;
+
+        let writer = thread::spawn(move || {
+            let mut next = 0;
+            while next < total {
+                let block: Vec<f32> = (next..(next + 37).min(total)).map(|i| i as f32).collect();
+                let mut written = 0;
+                while written < block.len() {
+                    match producer.push(&block[written..]) {
+                        0 => thread::yield_now(),
+                        pushed => written += pushed,
+                    }
+                }
+                next += block.len();
+            }
+        });
+
+        let mut expected = 0;
+        let mut out = [0f32; 50];
+        while expected < total {
+            let read = consumer.pop(&mut out);
+            if read == 0 {
+                thread::yield_now();
+            }
+            for sample in out[..read].iter() {
+                assert_eq!(*sample, expected as f32);
+                expected += 1;
+            }
+        }
+
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    builder::{MixerModel, SendModel},
+    error::{Error, Result},
+    mixer::{db_to_gain, InsertChain, PanLaw, Strip},
+};
+
+/// Where a channel or bus plays into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Master,
+    /// Index into `MixerModel::buses`
+    Bus(usize),
+}
+
+/// Holds audio back on its way into a bus or the master, so it gets there as
+/// late as audio that came a way with more latency.
+#[derive(Default)]
+struct Compensation {
+    frames: usize,
+    line: VecDeque<f32>,
+}
+
+impl Compensation {
+    fn new(frames: usize, channel_count: usize) -> Self {
+        Compensation {
+            frames,
+            line: vec![0f32; frames * channel_count].into(),
+        }
+    }
+
+    #[inline]
+    fn delay(&mut self, sample: f32) -> f32 {
+        if self.line.is_empty() {
+            return sample;
+        }
+
+        self.line.push_back(sample);
+        self.line.pop_front().unwrap_or(0f32)
+    }
+
+    fn reset(&mut self) {
+        self.line.iter_mut().for_each(|s| *s = 0f32);
+    }
+}
+
+struct Send {
+    bus: usize,
+    gain: f32,
+    pre_fader: bool,
+    delay: Compensation,
+}
+
+struct Bus {
+    id: String,
+    inserts: InsertChain,
+    strip: Strip,
+    output: Target,
+    output_delay: Compensation,
+    sends: Vec<Send>,
+    // What has been sent or output to the bus this block
+    buffer: Vec<f32>,
+}
+
+impl Bus {
+    /// Where the bus plays into, and how long it is held back on the way.
+    fn targets(&self) -> impl Iterator<Item = (Target, &Compensation)> {
+        std::iter::once((self.output, &self.output_delay)).chain(
+            self.sends
+                .iter()
+                .map(|send| (Target::Bus(send.bus), &send.delay)),
+        )
+    }
+}
+
+/// How audio gets from the channels to the master through the buses. Channels
+/// only ever feed buses, so they are all rendered first and the buses are
+/// then run in an order where every bus comes after everything that feeds it.
+pub struct Routing {
+    channel_outputs: Vec<Target>,
+    channel_output_delays: Vec<Compensation>,
+    channel_sends: Vec<Vec<Send>>,
+    buses: Vec<Bus>,
+    // Bus indices in the order they are run
+    order: Vec<usize>,
+    // Frames the channels are held back by so every way to the master takes
+    // as long as the one through the buses with the most latency
+    latency: usize,
+}
+
+impl Routing {
+    /// Works out the routing of `mixer`, failing if anything points at a bus
+    /// that isn't there or the buses feed back into each other.
+    pub fn new(mixer: &MixerModel) -> Result<Self> {
+        let mut bus_indices = HashMap::with_capacity(mixer.buses.len());
+        for (index, bus) in mixer.buses.iter().enumerate() {
+            if bus_indices.insert(bus.id.as_str(), index).is_some() {
+                return Err(Error::DuplicateBus(bus.id.clone()));
+            }
+        }
+
+        let target = |output: &Option<String>| match output {
+            None => Ok(Target::Master),
+            Some(id) => bus_indices
+                .get(id.as_str())
+                .map(|index| Target::Bus(*index))
+                .ok_or_else(|| Error::UnknownBus(id.clone())),
+        };
+
+        let sends = |sends: &[SendModel]| {
+            sends
+                .iter()
+                .map(|send| {
+                    let bus = *bus_indices
+                        .get(send.bus.as_str())
+                        .ok_or_else(|| Error::UnknownBus(send.bus.clone()))?;
+
+                    Ok(Send {
+                        bus,
+                        gain: db_to_gain(send.level_db),
+                        pre_fader: send.pre_fader,
+                        delay: Compensation::default(),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+
+        let buses = mixer
+            .buses
+            .iter()
+            .map(|bus| {
+                Ok(Bus {
+                    id: bus.id.clone(),
+                    inserts: InsertChain::default(),
+                    strip: Strip {
+                        volume_db: bus.volume_db,
+                        pan: bus.pan,
+                        mute: bus.mute,
+                        solo: false,
+                    },
+                    output: target(&bus.output)?,
+                    output_delay: Compensation::default(),
+                    sends: sends(&bus.sends)?,
+                    buffer: Vec::new(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let order = bus_order(&buses)?;
+
+        Ok(Routing {
+            channel_outputs: mixer
+                .channels
+                .iter()
+                .map(|channel| target(&channel.output))
+                .collect::<Result<_>>()?,
+            channel_output_delays: mixer
+                .channels
+                .iter()
+                .map(|_| Compensation::default())
+                .collect(),
+            channel_sends: mixer
+                .channels
+                .iter()
+                .map(|channel| sends(&channel.sends))
+                .collect::<Result<_>>()?,
+            buses,
+            order,
+            latency: 0,
+        })
+    }
+
+    /// Ids of the buses in the order they are run.
+    pub fn bus_order(&self) -> Vec<&str> {
+        self.order
+            .iter()
+            .map(|index| self.buses[*index].id.as_str())
+            .collect()
+    }
+
+    pub fn channel_output(&self, channel: usize) -> Target {
+        self.channel_outputs[channel]
+    }
+
+    /// Gives bus `bus` (an index into `MixerModel::buses`) the inserts it
+    /// runs through before its sends and fader.
+    pub(crate) fn set_bus_inserts(&mut self, bus: usize, inserts: InsertChain) {
+        self.buses[bus].inserts = inserts;
+    }
+
+    /// Each bus's inserts, in `MixerModel::buses` order.
+    pub(crate) fn bus_inserts(&self) -> impl Iterator<Item = &InsertChain> {
+        self.buses.iter().map(|bus| &bus.inserts)
+    }
+
+    /// Works out how long to hold back each channel and bus on the way into
+    /// each bus or the master, so everything reaches the master as late as
+    /// the way through the buses with the most latency. Call once the buses
+    /// have their inserts.
+    pub(crate) fn compensate(&mut self, channel_count: usize) {
+        let depths: Vec<usize> = (0..self.buses.len()).map(|bus| self.depth(bus)).collect();
+        let depth = |target: Target| match target {
+            Target::Master => 0,
+            Target::Bus(bus) => depths[bus],
+        };
+
+        // Channels all come out of their inserts together, so they wait for
+        // the slowest way any of them takes
+        self.latency = self
+            .channel_outputs
+            .iter()
+            .copied()
+            .chain(
+                self.channel_sends
+                    .iter()
+                    .flatten()
+                    .map(|send| Target::Bus(send.bus)),
+            )
+            .map(depth)
+            .max()
+            .unwrap_or(0);
+
+        let latency = self.latency;
+        for (output, delay) in self
+            .channel_outputs
+            .iter()
+            .zip(self.channel_output_delays.iter_mut())
+        {
+            *delay = Compensation::new(latency - depth(*output), channel_count);
+        }
+        for send in self.channel_sends.iter_mut().flatten() {
+            send.delay = Compensation::new(latency - depth(Target::Bus(send.bus)), channel_count);
+        }
+
+        for (bus, own) in self.buses.iter_mut().zip(depths.iter()) {
+            let latency = own - bus.inserts.latency();
+
+            bus.output_delay = Compensation::new(latency - depth(bus.output), channel_count);
+            for send in bus.sends.iter_mut() {
+                send.delay =
+                    Compensation::new(latency - depth(Target::Bus(send.bus)), channel_count);
+            }
+        }
+    }
+
+    /// Frames the buses hold the channels back by so they line up at the
+    /// master, on top of the channels' own latency.
+    pub(crate) fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// Frames from bus `index`'s input to the master the slowest way there.
+    fn depth(&self, index: usize) -> usize {
+        let bus = &self.buses[index];
+        let fed = bus
+            .targets()
+            .map(|(target, _)| match target {
+                Target::Master => 0,
+                Target::Bus(bus) => self.depth(bus),
+            })
+            .max()
+            .unwrap_or(0);
+
+        bus.inserts.latency() + fed
+    }
+
+    /// Frames channel `channel`'s audio can keep sounding for once it has
+    /// left the channel, held back and ringing out through the buses it
+    /// reaches.
+    pub(crate) fn channel_tail(&self, channel: usize) -> usize {
+        std::iter::once((
+            self.channel_outputs[channel],
+            &self.channel_output_delays[channel],
+        ))
+        .chain(
+            self.channel_sends[channel]
+                .iter()
+                .map(|send| (Target::Bus(send.bus), &send.delay)),
+        )
+        .map(|(target, delay)| delay.frames + self.target_tail(target))
+        .max()
+        .unwrap_or(0)
+    }
+
+    /// Frames `target` can keep sounding for after its input stops,
+    /// including the buses it feeds.
+    fn target_tail(&self, target: Target) -> usize {
+        let bus = match target {
+            Target::Master => return 0,
+            Target::Bus(bus) => &self.buses[bus],
+        };
+
+        let fed = bus
+            .targets()
+            .map(|(target, delay)| delay.frames + self.target_tail(target))
+            .max()
+            .unwrap_or(0);
+
+        bus.inserts.latency() + bus.inserts.tail() + fed
+    }
+
+    /// Clears the buses' inserts and everything held back, e.g. after a seek.
+    pub(crate) fn reset(&mut self) {
+        self.channel_output_delays
+            .iter_mut()
+            .for_each(Compensation::reset);
+        for send in self.channel_sends.iter_mut().flatten() {
+            send.delay.reset();
+        }
+
+        for bus in self.buses.iter_mut() {
+            bus.inserts.reset();
+            bus.output_delay.reset();
+            for send in bus.sends.iter_mut() {
+                send.delay.reset();
+            }
+        }
+    }
+
+    /// Empties every bus ready for a block of `samples` samples.
+    pub(crate) fn start_block(&mut self, samples: usize) {
+        for bus in self.buses.iter_mut() {
+            bus.buffer.clear();
+            bus.buffer.resize(samples, 0f32);
+        }
+    }
+
+    /// Adds channel `channel`'s pre or post fader sends of `samples` to their
+    /// buses.
+    pub(crate) fn send(&mut self, channel: usize, samples: &[f32], pre_fader: bool) {
+        for send in self.channel_sends[channel].iter_mut() {
+            if send.pre_fader == pre_fader {
+                let bus = &mut self.buses[send.bus].buffer;
+                mix_into(bus, samples, send.gain, &mut send.delay);
+            }
+        }
+    }
+
+    /// Plays a channel's faded `samples` into its output, which is either a
+    /// bus or `master`.
+    pub(crate) fn output(&mut self, channel: usize, samples: &[f32], master: &mut [f64]) {
+        let delay = &mut self.channel_output_delays[channel];
+
+        match self.channel_outputs[channel] {
+            Target::Master => mix_into_master(master, samples, delay),
+            Target::Bus(bus) => mix_into(&mut self.buses[bus].buffer, samples, 1f32, delay),
+        }
+    }
+
+    /// Runs every bus in order and plays the ones that output to the master
+    /// into `master`. `channels` is every channel's audio before its inserts,
+    /// for bus inserts with a sidechain.
+    pub(crate) fn run_buses(
+        &mut self,
+        pan_law: PanLaw,
+        channel_count: usize,
+        channels: &[Vec<f32>],
+        master: &mut [f64],
+    ) {
+        for i in 0..self.order.len() {
+            let index = self.order[i];
+            // Taken out so it can be mixed into the buses it feeds. A bus never
+            // feeds itself, so it is back before anything reads it.
+            let mut buffer = std::mem::take(&mut self.buses[index].buffer);
+
+            self.buses[index]
+                .inserts
+                .process_with_sidechains(&mut buffer, channels, 0);
+
+            if self.buses[index].strip.mute {
+                buffer.fill(0f32);
+            }
+
+            self.send_from_bus(index, &buffer, true);
+            self.buses[index]
+                .strip
+                .apply(pan_law, &mut buffer, channel_count);
+            self.send_from_bus(index, &buffer, false);
+
+            let mut delay = std::mem::take(&mut self.buses[index].output_delay);
+            match self.buses[index].output {
+                Target::Master => mix_into_master(master, &buffer, &mut delay),
+                Target::Bus(bus) => {
+                    mix_into(&mut self.buses[bus].buffer, &buffer, 1f32, &mut delay)
+                }
+            }
+
+            self.buses[index].output_delay = delay;
+            self.buses[index].buffer = buffer;
+        }
+    }
+
+    fn send_from_bus(&mut self, index: usize, samples: &[f32], pre_fader: bool) {
+        // Taken out like the buffer, as a bus never sends to itself
+        let mut sends = std::mem::take(&mut self.buses[index].sends);
+
+        for send in sends.iter_mut() {
+            if send.pre_fader == pre_fader {
+                let bus = &mut self.buses[send.bus].buffer;
+                mix_into(bus, samples, send.gain, &mut send.delay);
+            }
+        }
+
+        self.buses[index].sends = sends;
+    }
+}
+
+fn mix_into(bus: &mut [f32], samples: &[f32], gain: f32, delay: &mut Compensation) {
+    for (mixed, sample) in bus.iter_mut().zip(samples.iter()) {
+        *mixed += delay.delay(*sample) * gain;
+    }
+}
+
+fn mix_into_master(master: &mut [f64], samples: &[f32], delay: &mut Compensation) {
+    for (mixed, sample) in master.iter_mut().zip(samples.iter()) {
+        *mixed += delay.delay(*sample) as f64;
+    }
+}
+
+/// Orders the buses so each comes after every bus that outputs or sends to it
+/// (Kahn's algorithm). Whatever can't be ordered is part of a loop.
+fn bus_order(buses: &[Bus]) -> Result<Vec<usize>> {
+    let feeds = |bus: &Bus| {
+        let output = match bus.output {
+            Target::Bus(index) => Some(index),
+            Target::Master => None,
+        };
+
+        output
+            .into_iter()
+            .chain(bus.sends.iter().map(|send| send.bus))
+            .collect::<Vec<_>>()
+    };
+
+    let mut inputs = vec![0usize; buses.len()];
+    for bus in buses.iter() {
+        for index in feeds(bus) {
+            inputs[index] += 1;
+        }
+    }
+
+    let mut ready: Vec<usize> = (0..buses.len()).filter(|i| inputs[*i] == 0).collect();
+    let mut order = Vec::with_capacity(buses.len());
+
+    while let Some(index) = ready.pop() {
+        order.push(index);
+
+        for fed in feeds(&buses[index]) {
+            inputs[fed] -= 1;
+            if inputs[fed] == 0 {
+                ready.push(fed);
+            }
+        }
+    }
+
+    if order.len() < buses.len() {
+        let looped = (0..buses.len())
+            .filter(|i| inputs[*i] > 0)
+            .map(|i| buses[i].id.clone())
+            .collect();
+
+        return Err(Error::RoutingCycle(looped));
+    }
+
+    Ok(order)
+}
+
+#[cfg(test)]
+mod routing_tests {
+    use crate::builder::{BusModel, ChannelModel};
+    use crate::routing::*;
+
+    fn bus(id: &str, output: Option<&str>, sends: &[&str]) -> BusModel {
+        BusModel {
+            id: id.to_string(),
+            output: output.map(|o| o.to_string()),
+            sends: sends
+                .iter()
+                .map(|bus| SendModel {
+                    bus: bus.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn mixer(channels: Vec<ChannelModel>, buses: Vec<BusModel>) -> MixerModel {
+        MixerModel {
+            channels,
+            buses,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn buses_run_after_what_feeds_them() {
+        // drums -> group -> master, group sends to reverb, reverb -> master
+        let routing = Routing::new(&mixer(
+            vec![],
+            vec![
+                bus("reverb", None, &[]),
+                bus("group", None, &["reverb"]),
+                bus("drums", Some("group"), &[]),
+            ],
+        ))
+        .unwrap();
+
+        assert_eq!(routing.bus_order(), ["drums", "group", "reverb"]);
+    }
+
+    #[test]
+    fn rejects_feedback() {
+        let result = Routing::new(&mixer(
+            vec![],
+            vec![
+                bus("a", Some("b"), &[]),
+                bus("b", None, &["a"]),
+                bus("c", None, &[]),
+            ],
+        ));
+        assert!(matches!(result, Err(Error::RoutingCycle(ids)) if ids == ["a", "b"]));
+
+        let result = Routing::new(&mixer(vec![], vec![bus("a", None, &["a"])]));
+        assert!(matches!(result, Err(Error::RoutingCycle(_))));
+    }
+
+    #[test]
+    fn rejects_unknown_and_duplicate_buses() {
+        let channel = ChannelModel {
+            output: Some("nowhere".to_string()),
+            ..Default::default()
+        };
+        let result = Routing::new(&mixer(vec![channel], vec![]));
+        assert!(matches!(result, Err(Error::UnknownBus(id)) if id == "nowhere"));
+
+        let result = Routing::new(&mixer(
+            vec![],
+            vec![bus("a", None, &[]), bus("a", None, &[])],
+        ));
+        assert!(matches!(result, Err(Error::DuplicateBus(_))));
+    }
+
+    #[test]
+    fn mixes_through_buses() {
+        let channel = ChannelModel {
+            output: Some("group".to_string()),
+            sends: vec![SendModel {
+                bus: "aux".to_string(),
+                level_db: -6f32,
+                pre_fader: true,
+            }],
+            ..Default::default()
+        };
+        let mut group = bus("group", None, &[]);
+        group.volume_db = -6f32;
+        let mut routing =
+            Routing::new(&mixer(vec![channel], vec![group, bus("aux", None, &[])])).unwrap();
+
+        let samples = [0.5f32; 4];
+        let mut master = [0f64; 4];
+        routing.start_block(4);
+        routing.send(0, &samples, true);
+        routing.output(0, &samples, &mut master);
+        routing.run_buses(PanLaw::default(), 1, &[], &mut master);
+
+        // Half through the group and half through the aux
+        let half = db_to_gain(-6f32);
+        for sample in master {
+            assert!((sample - (0.5 * half * 2f32) as f64).abs() < 1e-6);
+        }
+    }
+}
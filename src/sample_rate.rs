@@ -1,17 +1,19 @@
-struct SampleRate(pub u32);
+pub struct SampleRate(pub u32);
 
 impl SampleRate {
-    // For now these conversions are happening to keep this as precise as possible
-    fn ms_to_sample(&self, ms: u32) -> u32 {
-        let samples_per_ms = self.0 as f32 / 1000 as f32;
-
-        (samples_per_ms * ms as f32) as u32
+    // Integer math so that long timelines don't lose precision
+    pub fn ms_to_sample(&self, ms: u32) -> u32 {
+        (ms as u64 * self.0 as u64 / 1000) as u32
     }
 
-    fn sample_to_ms(&self, sample: u32) -> u32 {
-        let ms_per_sample = sample as f32 / self.0 as f32;
+    pub fn sample_to_ms(&self, sample: u32) -> u32 {
+        (sample as u64 * 1000 / self.0 as u64) as u32
+    }
 
-        (ms_per_sample * 1000f32) as u32
+    /// Converts a timeline position in milliseconds, which can be before the
+    /// start of the timeline, to a frame. Rounds down.
+    pub fn ms_to_frame(&self, ms: i64) -> i64 {
+        (ms * self.0 as i64).div_euclid(1000)
     }
 }
 
@@ -29,6 +31,18 @@ mod sample_rate_test {
         assert_eq!(sample_rate.ms_to_sample(1), 44);
     }
 
+    #[test]
+    fn ms_to_frame() {
+        let sample_rate = SampleRate(44100);
+
+        assert_eq!(sample_rate.ms_to_frame(1000), 44100);
+        assert_eq!(sample_rate.ms_to_frame(0), 0);
+        assert_eq!(sample_rate.ms_to_frame(-1000), -44100);
+        assert_eq!(sample_rate.ms_to_frame(-1), -45);
+        // Past where an f32 would start dropping samples
+        assert_eq!(sample_rate.ms_to_frame(3_600_000), 158_760_000);
+    }
+
     #[test]
     fn sample_to_ms() {
         let sample_rate = SampleRate(44100);
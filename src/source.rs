@@ -1,7 +1,10 @@
 use std::io::{Read, Seek};
+use std::time::Duration;
 
 use hound::{SampleFormat, WavReader, WavSpec};
 
+use crate::error::{Error, Result};
+
 pub trait Source: Iterator
 where
     Self::Item: cpal::Sample,
@@ -9,8 +12,16 @@ where
     fn channels(&self) -> usize;
     fn sample_rate(&self) -> cpal::SampleRate;
 
-    fn seek(&self) -> Result<(), ()> {
-        todo!()
+    /// Moves to `frame` (one sample per channel) so that the next sample read
+    /// is the first channel of that frame. Returns the frame seeked to.
+    fn seek(&mut self, frame: u64) -> Result<u64>;
+
+    /// Moves to the frame `time` into the source.
+    fn seek_time(&mut self, time: Duration) -> Result<u64> {
+        let rate = self.sample_rate().0 as u64;
+        let frame = time.as_secs() * rate + time.subsec_nanos() as u64 * rate / 1_000_000_000;
+
+        self.seek(frame)
     }
 }
 
@@ -26,8 +37,8 @@ impl<R> HoundWav<R>
 where
     R: Read + Seek,
 {
-    pub fn open(path: R) -> Result<HoundWav<R>, ()> {
-        let reader = WavReader::new(path).unwrap();
+    pub fn open(path: R) -> Result<HoundWav<R>> {
+        let reader = WavReader::new(path)?;
         let spec = reader.spec();
 
         Ok(HoundWav { reader, spec })
@@ -45,6 +56,17 @@ where
     fn sample_rate(&self) -> cpal::SampleRate {
         cpal::SampleRate(self.spec.sample_rate)
     }
+
+    // PCM has no pre-roll, so hound can jump straight to the frame
+    fn seek(&mut self, frame: u64) -> Result<u64> {
+        if frame > self.reader.duration() as u64 {
+            return Err(Error::SeekOutOfRange(frame));
+        }
+
+        self.reader.seek(frame as u32)?;
+
+        Ok(frame)
+    }
 }
 
 impl<R> Iterator for HoundWav<R>
@@ -114,3 +136,44 @@ fn i24_to_i16(i: i32) -> i16 {
 fn i32_to_i16(i: i32) -> i16 {
     (i >> 16) as i16
 }
+
+#[cfg(test)]
+mod source_tests {
+    use crate::source::*;
+    use std::fs::File;
+
+    // sample-1.wav is 140928 stereo 16 bit frames
+    fn open() -> HoundWav<File> {
+        HoundWav::open(File::open("sounds/sample-1.wav").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn hound_seeks_to_frame() {
+        let source: Vec<i16> = open().collect();
+
+        for frame in [0u64, 1, 12345, 140927, 140928] {
+            let mut wav = open();
+            assert_eq!(wav.seek(frame).unwrap(), frame);
+
+            let read: Vec<i16> = wav.collect();
+            assert_eq!(read[..], source[frame as usize * 2..]);
+        }
+    }
+
+    #[test]
+    fn hound_seeks_to_time() {
+        let source: Vec<i16> = open().collect();
+        let mut wav = open();
+
+        assert_eq!(wav.seek_time(Duration::from_millis(1500)).unwrap(), 66150);
+        assert_eq!(wav.next(), Some(source[66150 * 2]));
+    }
+
+    #[test]
+    fn hound_rejects_seeking_past_the_end() {
+        assert!(matches!(
+            open().seek(140929),
+            Err(Error::SeekOutOfRange(140929))
+        ));
+    }
+}
@@ -1,25 +1,46 @@
 use crate::{
+    error::Result,
     frame::{new_frame, Frame},
     source::Source,
     symph::Symphonia,
 };
-use cpal::{StreamConfig, SupportedStreamConfig};
-use rubato::{FftFixedInOut, FftFixedOut, Resampler};
+use cpal::StreamConfig;
+use rubato::{FftFixedOut, Resampler};
+
+/// Number of frames produced by each refill.
+const CHUNK_SIZE: usize = 2048;
+
+/// How many pieces the resampler splits each chunk into.
+const SUB_CHUNKS: usize = 2;
 
 pub struct SourceReader {
     source: Symphonia,
     resample_input_buf: Vec<Vec<f32>>,
     resample_output_buf: Vec<Vec<f32>>,
     // resampler: FftFixedInOut<f32>,
-    resampler: FftFixedOut<f32>,
-    target_channel_count: usize,
+    // None when the source is already at the target sample rate, in which case
+    // samples are passed straight through.
+    resampler: Option<FftFixedOut<f32>>,
+    // Resampled frames still to be dropped from the start of the output to
+    // make up for the resampler's delay
+    delay_remaining: usize,
+    // Whether the resampler has been flushed after the source ran out
+    flushed: bool,
+    // Set when a seek went past the end of the source
+    finished: bool,
+    // Next frame to be read from the source, at the source's sample rate
+    source_position: u64,
+    // Source frames to loop between once `source_position` reaches the end
+    loop_region: Option<(u64, u64)>,
+    source_sample_rate: u32,
+    target_sample_rate: u32,
     source_channel_count: usize,
     frame: Box<dyn Frame + Send + Sync>,
 }
 
 // Currently working to convert from source channel count to target channel count
 impl SourceReader {
-    pub fn new(source: Symphonia, config: StreamConfig) -> Self {
+    pub fn new(source: Symphonia, config: StreamConfig) -> Result<Self> {
         let target_sample_rate = config.sample_rate.0;
         let source_sample_rate = source.sample_rate().0;
         let target_channel_count = config.channels as usize;
@@ -32,32 +53,93 @@ impl SourceReader {
         // println!(" Source: {}", source_channel_count);
         // println!(" Target: {}", target_channel_count);
 
-        // let resampler = FftFixedInOut::new(
-        let resampler = FftFixedOut::new(
-            source_sample_rate as usize,
-            target_sample_rate as usize,
-            2048,
-            2,
-            source_channel_count,
-        )
-        .unwrap();
+        let resampler =
+            new_resampler(source_sample_rate, target_sample_rate, source_channel_count)?;
 
-        let input_buf = resampler.input_buffer_allocate();
-        let output_buf = resampler.output_buffer_allocate();
+        let (input_buf, output_buf) = match &resampler {
+            Some(resampler) => (
+                resampler.input_buffer_allocate(),
+                resampler.output_buffer_allocate(),
+            ),
+            None => {
+                let buf = vec![Vec::with_capacity(CHUNK_SIZE); source_channel_count];
+                (buf.clone(), buf)
+            }
+        };
 
         let mut reader = Self {
             frame: new_frame(source_channel_count, target_channel_count),
             source,
+            delay_remaining: match resampler {
+                Some(_) => resampler_delay(source_sample_rate, target_sample_rate),
+                None => 0,
+            },
+            flushed: false,
+            finished: false,
+            source_position: 0,
+            loop_region: None,
+            source_sample_rate,
+            target_sample_rate,
             resampler,
             resample_input_buf: input_buf,
             resample_output_buf: output_buf,
-            target_channel_count,
             source_channel_count,
         };
 
         reader.refil();
 
-        reader
+        Ok(reader)
+    }
+
+    pub fn source_sample_rate(&self) -> u32 {
+        self.source_sample_rate
+    }
+
+    pub fn target_sample_rate(&self) -> u32 {
+        self.target_sample_rate
+    }
+
+    /// Moves to `frame` of the source, counted at the source's sample rate.
+    /// Anything already read ahead is dropped and the resampler starts over.
+    /// Seeking past the end leaves the reader finished.
+    pub fn seek(&mut self, frame: u64) -> Result<()> {
+        for c in self.resample_output_buf.iter_mut() {
+            c.clear();
+        }
+        self.frame.reset();
+
+        if let Err(err) = self.source.seek(frame) {
+            self.finished = true;
+            return Err(err);
+        }
+
+        self.finished = false;
+        self.source_position = frame;
+
+        if self.resampler.is_some() {
+            self.resampler = new_resampler(
+                self.source_sample_rate,
+                self.target_sample_rate,
+                self.source_channel_count,
+            )?;
+            self.delay_remaining =
+                resampler_delay(self.source_sample_rate, self.target_sample_rate);
+            self.flushed = false;
+        }
+
+        self.refil();
+
+        Ok(())
+    }
+
+    /// Loops the source between the `start` and `end` frames (at the source's
+    /// sample rate) once reading reaches `end`.
+    pub fn set_loop(&mut self, start: u64, end: u64) {
+        self.loop_region = if start < end {
+            Some((start, end))
+        } else {
+            None
+        };
     }
 
     // TODO: Put this in an iterator impl
@@ -65,15 +147,15 @@ impl SourceReader {
     pub fn next(&mut self) -> Option<f32> {
         // return self.source.next();
 
-        // This should work for both 1:1 and 1:(1+n) channel conversion
-        // In an up channel setup (1 -> 2 channels) we need to make sure that the
-        // samples advanced % 2 == 1 (odd) and the current sample index + 1 is the
-        // same as the length of the last source channel buffer
-        let could_be_last_sample = self.frame.samples_advanced() % self.target_channel_count != 0;
-        let is_last_sample = self.resample_output_buf[self.frame.current_channel_index()].len()
-            == self.frame.current_sample_index() + 1;
+        // This should work for both 1:1 and 1:(1+n) channel conversion.
+        // Once the frame has advanced past the last sample of the resampled
+        // output we need a new buffer. Checking for this after the frame has
+        // moved on (rather than on the last sample itself) makes sure every
+        // target channel of the last frame gets read before refilling.
+        let is_past_last_sample =
+            self.frame.current_sample_index() >= self.resample_output_buf[0].len();
 
-        if is_last_sample && could_be_last_sample {
+        if is_past_last_sample {
             let read_samples = self.refil();
 
             if read_samples == 0 {
@@ -94,14 +176,32 @@ impl SourceReader {
     // IDEA: Each time we read a frame from the output buffer, could we also read one from the source and fill the input buffer?
     // Then when we have read all the frames from the output buffer, we could run a qucker resample.
     // I suppose this would keep a larger memory footprint
+    /// Reads the next chunk from the source into the output buffer and returns
+    /// the number of frames now in it, 0 once the source is finished.
     #[inline(always)]
     pub fn refil(&mut self) -> usize {
         // How many frames do we need to get (samples * channels)
-        let get_frame_count = self.resampler.input_frames_next();
+        let get_frame_count = match &self.resampler {
+            Some(resampler) => resampler.input_frames_next(),
+            None => CHUNK_SIZE,
+        };
         let mut n = 0;
 
+        if self.finished {
+            return 0;
+        }
+
         // Fill input buffer with samples
         'outer: while n < get_frame_count {
+            if let Some((loop_start, loop_end)) = self.loop_region {
+                if self.source_position == loop_end {
+                    if self.source.seek(loop_start).is_err() {
+                        break 'outer;
+                    }
+                    self.source_position = loop_start;
+                }
+            }
+
             // Get a sample for each channel
             for c in 0..self.source_channel_count {
                 let sample = match self.source.next() {
@@ -115,11 +215,31 @@ impl SourceReader {
             }
 
             n += 1;
+            self.source_position += 1;
         }
 
-        // If we didn't read any, return 0
+        let resampler = match self.resampler.as_mut() {
+            Some(resampler) => resampler,
+            None => {
+                // Same sample rate, so the input is the output
+                std::mem::swap(&mut self.resample_input_buf, &mut self.resample_output_buf);
+                for c in self.resample_input_buf.iter_mut() {
+                    c.clear();
+                }
+
+                return n;
+            }
+        };
+
+        // If we didn't read any, we're done. The resampler still holds its
+        // last `delay` frames though, so push one more chunk of silence
+        // through first to get them out.
         if n == 0 {
-            return 0;
+            if self.flushed {
+                return 0;
+            }
+
+            self.flushed = true;
         }
 
         // If we read less than the resampler expects, fill each buffer with 0's
@@ -134,7 +254,7 @@ impl SourceReader {
             }
         }
 
-        if let Err(resample_err) = self.resampler.process_into_buffer(
+        if let Err(resample_err) = resampler.process_into_buffer(
             &self.resample_input_buf,
             &mut self.resample_output_buf,
             None,
@@ -147,6 +267,118 @@ impl SourceReader {
             c.clear();
         }
 
-        return n;
+        if self.delay_remaining > 0 {
+            let skip = self.delay_remaining.min(self.resample_output_buf[0].len());
+            for c in self.resample_output_buf.iter_mut() {
+                c.drain(..skip);
+            }
+            self.delay_remaining -= skip;
+        }
+
+        self.resample_output_buf[0].len()
+    }
+}
+
+fn new_resampler(
+    source_sample_rate: u32,
+    target_sample_rate: u32,
+    channel_count: usize,
+) -> Result<Option<FftFixedOut<f32>>> {
+    if source_sample_rate == target_sample_rate {
+        return Ok(None);
+    }
+
+    // let resampler = FftFixedInOut::new(
+    let resampler = FftFixedOut::new(
+        source_sample_rate as usize,
+        target_sample_rate as usize,
+        CHUNK_SIZE,
+        SUB_CHUNKS,
+        channel_count,
+    )?;
+
+    Ok(Some(resampler))
+}
+
+/// The FFT resampler's filter is linear phase and as long as an output FFT, so
+/// its output is delayed by half of that. This works out the FFT size the same
+/// way `FftFixedOut::new` does.
+fn resampler_delay(source_sample_rate: u32, target_sample_rate: u32) -> usize {
+    let gcd = gcd(source_sample_rate as usize, target_sample_rate as usize);
+    let min_chunk_out = target_sample_rate as usize / gcd;
+    let wanted_subsize = CHUNK_SIZE / SUB_CHUNKS;
+    let fft_chunks = wanted_subsize.div_ceil(min_chunk_out);
+
+    (fft_chunks * min_chunk_out - 1) / 2
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod source_reader_tests {
+    use crate::source_reader::*;
+    use cpal::{BufferSize, SampleRate};
+
+    const PATH: &str = "sounds/sample-2.wav";
+
+    fn read(sample_rate: u32) -> Vec<f32> {
+        let config = StreamConfig {
+            channels: 1,
+            sample_rate: SampleRate(sample_rate),
+            buffer_size: BufferSize::Default,
+        };
+        let mut reader =
+            SourceReader::new(Symphonia::new(PATH.to_string()).unwrap(), config).unwrap();
+        let mut out = Vec::new();
+
+        while let Some(sample) = reader.next() {
+            out.push(sample);
+        }
+
+        out
+    }
+
+    #[test]
+    fn same_sample_rate_passes_samples_through() {
+        let source: Vec<f32> = Symphonia::new(PATH.to_string()).unwrap().collect();
+
+        assert_eq!(read(44100), source);
+    }
+
+    #[test]
+    fn resampling_keeps_source_timing() {
+        let source: Vec<f32> = Symphonia::new(PATH.to_string()).unwrap().collect();
+        let out = read(48000);
+        let ratio = 44100f64 / 48000f64;
+
+        // Nothing from the end of the source is lost in the resampler
+        assert!(out.len() as f64 * ratio >= source.len() as f64);
+
+        // Linearly interpolate the source at the output's sample times and
+        // check the output lines up best with no lag.
+        let at = |i: i64| -> f32 {
+            let t = i as f64 * ratio;
+            let i = t as usize;
+            let frac = (t - i as f64) as f32;
+            source[i] * (1f32 - frac) + source[i + 1] * frac
+        };
+
+        let error = |lag: i64| -> f32 {
+            (40_000..44_000)
+                .map(|i| (out[i as usize] - at(i + lag)).powi(2))
+                .sum()
+        };
+
+        let best = (-20..=20)
+            .min_by(|a, b| error(*a).partial_cmp(&error(*b)).unwrap())
+            .unwrap();
+
+        assert_eq!(best, 0);
     }
 }
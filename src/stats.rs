@@ -0,0 +1,223 @@
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use parking_lot::RwLock;
+
+/// A snapshot of how the engine has been keeping up, from
+/// `EngineController::stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EngineStats {
+    /// Callbacks that had to play silence because the transport hadn't
+    /// rendered in time
+    pub underruns: u64,
+    /// Callbacks that took longer than the audio they delivered lasts
+    pub late_callbacks: u64,
+    /// Longest a single output callback has taken
+    pub max_callback: Duration,
+    /// Samples waiting in the ring buffer at the start of the last callback
+    pub ring_buffer_fill: usize,
+    pub ring_buffer_capacity: usize,
+    /// Render times of the loaded playback's channels, in mixing order
+    pub channels: Vec<ChannelStats>,
+}
+
+impl EngineStats {
+    /// How full the ring buffer was, from 0 to 1
+    pub fn ring_buffer_level(&self) -> f32 {
+        match self.ring_buffer_capacity {
+            0 => 0f32,
+            capacity => self.ring_buffer_fill as f32 / capacity as f32,
+        }
+    }
+}
+
+/// Time spent reading, decoding and resampling a channel's clips per block.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    pub id: String,
+    pub last_decode: Duration,
+    pub max_decode: Duration,
+}
+
+/// A channel's times, kept in atomics so recording them doesn't lock.
+struct ChannelSlot {
+    id: String,
+    last_decode_nanos: AtomicU64,
+    max_decode_nanos: AtomicU64,
+}
+
+/// Where the stats are collected. The output callback only touches the
+/// atomics. The transport thread sets up the channels when a playback is
+/// loaded and records into them every block without allocating or waiting.
+#[derive(Default)]
+pub(crate) struct StatsCollector {
+    underruns: AtomicU64,
+    late_callbacks: AtomicU64,
+    max_callback_nanos: AtomicU64,
+    ring_buffer_fill: AtomicUsize,
+    ring_buffer_capacity: AtomicUsize,
+    channels: RwLock<Vec<ChannelSlot>>,
+}
+
+impl StatsCollector {
+    pub fn new(ring_buffer_capacity: usize) -> Self {
+        StatsCollector {
+            ring_buffer_capacity: AtomicUsize::new(ring_buffer_capacity),
+            ..Default::default()
+        }
+    }
+
+    /// Records one output callback that took `elapsed` to deliver `budget`
+    /// worth of audio, and whether it ran out of samples.
+    #[inline]
+    pub fn record_callback(
+        &self,
+        ring_buffer_fill: usize,
+        underrun: bool,
+        elapsed: Duration,
+        budget: Duration,
+    ) {
+        self.ring_buffer_fill
+            .store(ring_buffer_fill, Ordering::Relaxed);
+
+        if underrun {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if elapsed > budget {
+            self.late_callbacks.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.max_callback_nanos
+            .fetch_max(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Starts the channels over with `ids`, e.g. when a new playback is
+    /// loaded.
+    pub fn set_channels(&self, ids: &[&str]) {
+        *self.channels.write() = ids
+            .iter()
+            .map(|id| ChannelSlot {
+                id: id.to_string(),
+                last_decode_nanos: AtomicU64::new(0),
+                max_decode_nanos: AtomicU64::new(0),
+            })
+            .collect();
+    }
+
+    /// Records how long each channel, in the order given to `set_channels`,
+    /// took to render a block.
+    #[inline]
+    pub fn record_channels(&self, decode_times: &[Duration]) {
+        // Only `set_channels` writes, and that runs on the same thread
+        if let Some(channels) = self.channels.try_read() {
+            for (channel, time) in channels.iter().zip(decode_times.iter()) {
+                let nanos = time.as_nanos() as u64;
+                channel.last_decode_nanos.store(nanos, Ordering::Relaxed);
+                channel.max_decode_nanos.fetch_max(nanos, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> EngineStats {
+        EngineStats {
+            underruns: self.underruns.load(Ordering::Relaxed),
+            late_callbacks: self.late_callbacks.load(Ordering::Relaxed),
+            max_callback: Duration::from_nanos(self.max_callback_nanos.load(Ordering::Relaxed)),
+            ring_buffer_fill: self.ring_buffer_fill.load(Ordering::Relaxed),
+            ring_buffer_capacity: self.ring_buffer_capacity.load(Ordering::Relaxed),
+            channels: self
+                .channels
+                .read()
+                .iter()
+                .map(|channel| ChannelStats {
+                    id: channel.id.clone(),
+                    last_decode: Duration::from_nanos(
+                        channel.last_decode_nanos.load(Ordering::Relaxed),
+                    ),
+                    max_decode: Duration::from_nanos(
+                        channel.max_decode_nanos.load(Ordering::Relaxed),
+                    ),
+                })
+                .collect(),
+        }
+    }
+
+    /// Zeroes the counters and maximums, e.g. to start a new logging period.
+    pub fn reset(&self) {
+        self.underruns.store(0, Ordering::Relaxed);
+        self.late_callbacks.store(0, Ordering::Relaxed);
+        self.max_callback_nanos.store(0, Ordering::Relaxed);
+
+        for channel in self.channels.read().iter() {
+            channel.max_decode_nanos.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use crate::stats::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn counts_callbacks() {
+        let stats = StatsCollector::new(1024);
+
+        stats.record_callback(512, false, MS, 2 * MS);
+        stats.record_callback(0, true, 3 * MS, 2 * MS);
+        stats.record_callback(256, false, 2 * MS, 2 * MS);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.underruns, 1);
+        assert_eq!(snapshot.late_callbacks, 1);
+        assert_eq!(snapshot.max_callback, 3 * MS);
+        assert_eq!(snapshot.ring_buffer_fill, 256);
+        assert_eq!(snapshot.ring_buffer_level(), 0.25);
+    }
+
+    #[test]
+    fn tracks_channel_decode_times() {
+        let stats = StatsCollector::new(1024);
+
+        stats.set_channels(&["a", "b"]);
+        stats.record_channels(&[2 * MS, MS]);
+        stats.record_channels(&[MS, 3 * MS]);
+
+        let channels = stats.snapshot().channels;
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0].id, "a");
+        assert_eq!(channels[0].last_decode, MS);
+        assert_eq!(channels[0].max_decode, 2 * MS);
+        assert_eq!(channels[1].max_decode, 3 * MS);
+
+        // A different playback starts over
+        stats.set_channels(&["c"]);
+        stats.record_channels(&[MS]);
+        let channels = stats.snapshot().channels;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].id, "c");
+        assert_eq!(channels[0].max_decode, MS);
+    }
+
+    #[test]
+    fn reset_clears_counters() {
+        let stats = StatsCollector::new(1024);
+
+        stats.record_callback(512, true, 3 * MS, 2 * MS);
+        stats.set_channels(&["a"]);
+        stats.record_channels(&[2 * MS]);
+        stats.reset();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.underruns, 0);
+        assert_eq!(snapshot.late_callbacks, 0);
+        assert_eq!(snapshot.max_callback, Duration::ZERO);
+        assert_eq!(snapshot.ring_buffer_fill, 512);
+        assert_eq!(snapshot.channels[0].last_decode, 2 * MS);
+        assert_eq!(snapshot.channels[0].max_decode, Duration::ZERO);
+    }
+}
@@ -2,13 +2,14 @@ use std::fs::File;
 // use std::path::Path;
 use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
 use symphonia::core::codecs::{Decoder, DecoderOptions};
-use symphonia::core::errors::Error;
-use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::errors::{Error as SymphoniaError, SeekErrorKind};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
 use symphonia::core::io::MediaSourceStream;
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
-use symphonia::core::units;
+use symphonia::core::units::{self, TimeBase, TimeStamp};
 
+use crate::error::{Error, Result};
 use crate::source::Source;
 
 pub struct Symphonia {
@@ -17,11 +18,13 @@ pub struct Symphonia {
     format: Box<dyn FormatReader>,
     decoder: Box<dyn Decoder>,
     spec: SignalSpec,
+    track_id: u32,
+    time_base: Option<TimeBase>,
 }
 
 impl Symphonia {
-    pub fn new(path: String) -> Result<Symphonia, ()> {
-        let file = Box::new(File::open(path).unwrap());
+    pub fn new(path: String) -> Result<Symphonia> {
+        let file = Box::new(File::open(&path).map_err(|err| Error::open(&path, err))?);
         let mss = MediaSourceStream::new(file, Default::default());
         let hint = Hint::new();
 
@@ -30,47 +33,105 @@ impl Symphonia {
         let metadata_opts: MetadataOptions = Default::default();
         let decoder_opts: DecoderOptions = Default::default();
 
-        let probed = symphonia::default::get_probe()
-            .format(&hint, mss, &format_opts, &metadata_opts)
-            .expect("Cannot get probe");
+        let probed =
+            symphonia::default::get_probe().format(&hint, mss, &format_opts, &metadata_opts)?;
 
         let mut format = probed.format;
         let track = match format.default_track() {
             Some(track) => track,
-            None => return Err(()),
+            None => return Err(Error::UnsupportedFormat(format!("{} has no tracks", path))),
         };
-        let mut decoder = symphonia::default::get_codecs()
-            .make(&track.codec_params, &decoder_opts)
-            .unwrap();
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        let mut decoder =
+            symphonia::default::get_codecs().make(&track.codec_params, &decoder_opts)?;
 
         let decoded = loop {
-            let packet = format.next_packet().unwrap();
-            match decoder.decode(&packet) {
-                Ok(decoded) => break decoded,
-                Err(_) => return Err(()),
-            }
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Err(Error::Decode(format!("{} has no audio", path)));
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            // The first packet has to decode to know the signal spec
+            break decoder.decode(&packet)?;
         };
 
         let spec = decoded.spec().to_owned();
-        let buffer = Self::get_new_buffer(decoded, &spec);
+        let mut buffer = Self::get_new_buffer(&decoded, &spec);
+        // Keep the samples of the packet we just decoded so they aren't lost
+        buffer.copy_interleaved_ref(decoded);
 
         let symp = Symphonia {
             buffer,
             decoder,
             format,
             spec,
+            track_id,
+            time_base,
             current_frame: 0,
         };
 
         Ok(symp)
     }
 
-    fn get_new_buffer(decoded: AudioBufferRef, spec: &SignalSpec) -> SampleBuffer<f32> {
+    fn get_new_buffer(decoded: &AudioBufferRef, spec: &SignalSpec) -> SampleBuffer<f32> {
         let duration = units::Duration::from(decoded.capacity() as u64);
         let buffer = SampleBuffer::<f32>::new(duration, spec.clone());
         buffer
     }
 
+    fn frame_to_ts(&self, frame: u64) -> TimeStamp {
+        match self.time_base {
+            Some(tb) => frame * tb.denom as u64 / (tb.numer as u64 * self.spec.rate as u64),
+            None => frame,
+        }
+    }
+
+    fn ts_to_frame(&self, ts: TimeStamp) -> u64 {
+        match self.time_base {
+            Some(tb) => ts * tb.numer as u64 * self.spec.rate as u64 / tb.denom as u64,
+            None => ts,
+        }
+    }
+
+    /// Decodes the next packet into the sample buffer. Returns false once
+    /// there is nothing left to decode.
+    fn decode_next_packet(&mut self) -> bool {
+        let decoded = loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err)) => {
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof {
+                        return false;
+                    }
+                    println!("IOError: {}", err);
+                    return false;
+                }
+                Err(err) => {
+                    println!("Err: {}", err);
+                    return false;
+                }
+            };
+
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => break decoded,
+                // A corrupt packet can be skipped
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(_) => return false,
+            }
+        };
+
+        self.buffer.copy_interleaved_ref(decoded);
+        self.current_frame = 0;
+
+        true
+    }
+
     // fn read_bytes(&mut self, byte_count: usize) {
     //     let read_sample_count = 0;
     //     self.buffer.samples()[read_sample_count..byte_count]
@@ -85,6 +146,51 @@ impl Source for Symphonia {
     fn sample_rate(&self) -> cpal::SampleRate {
         cpal::SampleRate(self.spec.rate)
     }
+
+    // The format reader can only land on the start of a packet, which may be
+    // before the frame we want (and for lossy codecs is the pre-roll the
+    // decoder needs to settle). The decoder is reset and everything decoded
+    // before the frame is dropped to make this sample accurate.
+    fn seek(&mut self, frame: u64) -> Result<u64> {
+        let seeked = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts: self.frame_to_ts(frame),
+                    track_id: self.track_id,
+                },
+            )
+            .map_err(|err| match err {
+                SymphoniaError::SeekError(SeekErrorKind::OutOfRange) => {
+                    Error::SeekOutOfRange(frame)
+                }
+                err => err.into(),
+            })?;
+
+        self.decoder.reset();
+
+        let mut discard =
+            self.ts_to_frame(seeked.required_ts.saturating_sub(seeked.actual_ts)) as usize;
+
+        loop {
+            if !self.decode_next_packet() {
+                // Seeked to the very end
+                self.current_frame = self.buffer.len();
+                break;
+            }
+
+            let frames = self.buffer.len() / self.channels();
+            if discard < frames {
+                self.current_frame = discard * self.channels();
+                break;
+            }
+
+            discard -= frames;
+        }
+
+        Ok(frame)
+    }
 }
 
 impl Iterator for Symphonia {
@@ -93,31 +199,11 @@ impl Iterator for Symphonia {
     #[inline]
     fn next(&mut self) -> Option<f32> {
         // println!("Channel: {}", self.current_frame % self.channels());
-        if self.current_frame == self.buffer.len() {
-            let decoded = loop {
-                let packet = match self.format.next_packet() {
-                    Ok(packet) => packet,
-                    Err(Error::IoError(err)) => {
-                        if err.kind() == std::io::ErrorKind::UnexpectedEof {
-                            return None;
-                        }
-                        println!("IOError: {}", err.to_string());
-                        return None;
-                    }
-                    Err(err) => {
-                        println!("Err: {}", err.to_string());
-                        return None;
-                    }
-                };
-
-                match self.decoder.decode(&packet) {
-                    Ok(decoded) => break decoded,
-                    Err(_) => return None,
-                }
-            };
-
-            self.buffer.copy_interleaved_ref(decoded);
-            self.current_frame = 0;
+        // Loop in case a packet decodes to no samples
+        while self.current_frame == self.buffer.len() {
+            if !self.decode_next_packet() {
+                return None;
+            }
         }
 
         let sample = self.buffer.samples()[self.current_frame];
@@ -126,3 +212,86 @@ impl Iterator for Symphonia {
         Some(sample)
     }
 }
+
+#[cfg(test)]
+mod symph_tests {
+    use crate::flac::FlacWriter;
+    use crate::symph::*;
+    use std::io::BufWriter;
+    use std::time::Duration;
+
+    // sample-1.wav is 140928 stereo frames
+    const PATH: &str = "sounds/sample-1.wav";
+
+    fn open() -> Symphonia {
+        Symphonia::new(PATH.to_string()).unwrap()
+    }
+
+    #[test]
+    fn seek_frame_is_sample_accurate() {
+        let source: Vec<f32> = open().collect();
+
+        for frame in [0u64, 1, 1000, 12345, 140927] {
+            let mut symph = open();
+            symph.seek(frame).unwrap();
+
+            let read: Vec<f32> = symph.collect();
+            assert_eq!(read[..], source[frame as usize * 2..]);
+        }
+    }
+
+    #[test]
+    fn seek_to_time() {
+        let source: Vec<f32> = open().collect();
+        let mut symph = open();
+
+        assert_eq!(symph.seek_time(Duration::from_millis(1500)).unwrap(), 66150);
+        assert_eq!(symph.next(), Some(source[66150 * 2]));
+    }
+
+    #[test]
+    fn seek_in_flac() {
+        let path = std::env::temp_dir().join("symph_seek.flac");
+        let file = BufWriter::new(File::create(&path).unwrap());
+        let mut writer = FlacWriter::new(file, 1, 44100, 16).unwrap();
+        for i in 0..100_000 {
+            writer.write_sample(i % 30_000).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        // Symphonia's FLAC reader can't seek into the last frame of a stream,
+        // so everything here is before it
+        let mut symph = Symphonia::new(path.to_str().unwrap().to_string()).unwrap();
+        for frame in [50_000u64, 4095, 4096, 98_000, 10] {
+            symph.seek(frame).unwrap();
+            let expected = (frame % 30_000) as f32 / 32768f32;
+            assert_eq!(symph.next(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn seek_frame_backwards() {
+        let source: Vec<f32> = open().collect();
+        let mut symph = open();
+
+        for _ in 0..100_000 {
+            symph.next();
+        }
+        symph.seek(10).unwrap();
+
+        assert_eq!(symph.next(), Some(source[20]));
+        assert_eq!(symph.next(), Some(source[21]));
+    }
+
+    #[test]
+    fn opening_fails_with_a_reason() {
+        assert!(matches!(
+            Symphonia::new("sounds/missing.wav".to_string()),
+            Err(Error::FileNotFound(_))
+        ));
+        assert!(matches!(
+            Symphonia::new("Cargo.toml".to_string()),
+            Err(Error::UnsupportedFormat(_))
+        ));
+    }
+}
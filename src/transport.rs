@@ -0,0 +1,372 @@
+use std::{sync::Arc, thread, time::Duration};
+
+use cpal::StreamConfig;
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use parking_lot::RwLock;
+
+use crate::{
+    builder::{block_size, Playback},
+    engine::{EngineCommand, EngineState, EngineStateMachine},
+    playhead::PlayheadMarker,
+    ring_buffer::Producer,
+    stats::StatsCollector,
+};
+
+/// How long to wait for the output stream to make room in the ring buffer
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Runs on its own thread between the controller and the output stream. It
+/// owns the loaded `Playback`, applies transport commands between blocks and
+/// keeps the stream fed, with silence whenever it isn't playing, so the stream
+/// never has to be torn down.
+pub(crate) struct Transport {
+    playback: Option<Playback>,
+    state: Arc<RwLock<EngineStateMachine>>,
+    commands: Receiver<EngineCommand>,
+    playbacks: Receiver<Playback>,
+    output: Producer,
+    markers: Sender<PlayheadMarker>,
+    // A marker that didn't fit in `markers` yet
+    pending_marker: Option<PlayheadMarker>,
+    stats: Arc<StatsCollector>,
+    channel_count: usize,
+    block: Vec<f32>,
+    // Frames sent to `output` so far
+    output_frames: u64,
+}
+
+impl Transport {
+    pub fn new(
+        state: Arc<RwLock<EngineStateMachine>>,
+        commands: Receiver<EngineCommand>,
+        playbacks: Receiver<Playback>,
+        output: Producer,
+        markers: Sender<PlayheadMarker>,
+        stats: Arc<StatsCollector>,
+        config: &StreamConfig,
+    ) -> Self {
+        let channel_count = config.channels as usize;
+
+        Transport {
+            playback: None,
+            state,
+            commands,
+            playbacks,
+            output,
+            markers,
+            pending_marker: None,
+            stats,
+            channel_count,
+            block: vec![0f32; block_size(config) * channel_count],
+            output_frames: 0,
+        }
+    }
+
+    /// Feeds blocks to the output stream until it goes away.
+    pub fn run(mut self) {
+        loop {
+            self.next_block();
+
+            let mut written = 0;
+
+            while written < self.block.len() {
+                if self.output.is_abandoned() {
+                    return;
+                }
+
+                // Only whole frames go in, so the output stream never ends up
+                // with half a frame and the channels swapped
+                let free = self.output.free_len() / self.channel_count * self.channel_count;
+                let to = self.block.len().min(written + free);
+                written += self.output.push(&self.block[written..to]);
+
+                if written < self.block.len() {
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Picks up any new playback and commands, then renders the next block
+    /// into `self.block`.
+    fn next_block(&mut self) -> &[f32] {
+        let mut moved = false;
+
+        if let Some(playback) = self.playbacks.try_iter().last() {
+            let block_len = block_size(playback.config()) * self.channel_count;
+            self.block.resize(block_len, 0f32);
+            self.stats.set_channels(&playback.channel_ids());
+            self.playback = Some(playback);
+            moved = true;
+        }
+
+        while let Ok(command) = self.commands.try_recv() {
+            if let Some(playback) = self.playback.as_mut() {
+                match command {
+                    EngineCommand::Stop => playback.seek(0),
+                    EngineCommand::Seek(frame) => playback.seek(frame),
+                    _ => (),
+                }
+            }
+
+            self.state.write().next(command);
+            moved = true;
+        }
+
+        let playing = self.state.read().state() == EngineState::Playing;
+
+        if moved {
+            // A newer marker supersedes one still waiting to be sent
+            self.pending_marker = Some(PlayheadMarker {
+                output_frame: self.output_frames,
+                timeline_frame: self.playback.as_ref().map_or(0, |p| p.position()),
+                playing: playing && self.playback.is_some(),
+            });
+        }
+
+        // Rather than block the mixer while the channel is full, the marker
+        // waits for the next block
+        if let Some(marker) = self.pending_marker.take() {
+            if let Err(TrySendError::Full(marker)) = self.markers.try_send(marker) {
+                self.pending_marker = Some(marker);
+            }
+        }
+
+        match (self.playback.as_mut(), playing) {
+            // Past the end of the timeline this keeps rendering silence
+            (Some(playback), true) => {
+                playback.render(&mut self.block);
+                self.stats.record_channels(playback.channel_render_times());
+            }
+            _ => self.block.fill(0f32),
+        }
+
+        self.output_frames += (self.block.len() / self.channel_count) as u64;
+
+        &self.block
+    }
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use crate::builder::{ChannelModel, ClipModel, MixerModel, PlaybackBuilder};
+    use crate::ring_buffer::ring_buffer;
+    use crate::symph::Symphonia;
+    use crate::transport::*;
+    use cpal::{BufferSize, SampleRate, StreamConfig};
+    use crossbeam::channel::{bounded, unbounded};
+
+    const PATH: &str = "sounds/sample-2.wav";
+
+    struct Harness {
+        transport: Transport,
+        commands: Sender<EngineCommand>,
+        state: Arc<RwLock<EngineStateMachine>>,
+        markers: Receiver<PlayheadMarker>,
+        stats: Arc<StatsCollector>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let mixer = MixerModel {
+                channels: vec![ChannelModel {
+                    id: "chan-1".to_string(),
+                    clips: vec![ClipModel {
+                        path: PATH.to_string(),
+                        duration_ms: 1000,
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            let config = StreamConfig {
+                channels: 1,
+                sample_rate: SampleRate(44100),
+                buffer_size: BufferSize::Fixed(100),
+            };
+            let playback = PlaybackBuilder::new(&mixer, config.clone()).unwrap();
+            let stats = Arc::new(StatsCollector::new(0));
+
+            let state = Arc::new(RwLock::new(EngineStateMachine::new()));
+            let (command_tx, command_rx) = unbounded();
+            let (playback_tx, playback_rx) = unbounded();
+            let (producer, _) = ring_buffer(0);
+            let (marker_tx, marker_rx) = unbounded();
+            playback_tx.send(playback).unwrap();
+
+            Harness {
+                transport: Transport::new(
+                    state.clone(),
+                    command_rx,
+                    playback_rx,
+                    producer,
+                    marker_tx,
+                    stats.clone(),
+                    &config,
+                ),
+                commands: command_tx,
+                state,
+                markers: marker_rx,
+                stats,
+            }
+        }
+
+        fn send(&self, command: EngineCommand) {
+            self.commands.send(command).unwrap();
+        }
+
+        fn next_block(&mut self) -> Vec<f32> {
+            self.transport.next_block().to_vec()
+        }
+
+        fn state(&self) -> EngineState {
+            self.state.read().state()
+        }
+    }
+
+    fn source() -> Vec<f32> {
+        Symphonia::new(PATH.to_string()).unwrap().collect()
+    }
+
+    #[test]
+    fn silent_until_played() {
+        let mut harness = Harness::new();
+
+        assert_eq!(harness.state(), EngineState::Idle);
+        assert!(harness.next_block().iter().all(|s| *s == 0f32));
+
+        harness.send(EngineCommand::Play);
+        assert_eq!(harness.next_block(), source()[..100]);
+        assert_eq!(harness.state(), EngineState::Playing);
+    }
+
+    #[test]
+    fn pause_holds_the_playhead() {
+        let source = source();
+        let mut harness = Harness::new();
+
+        harness.send(EngineCommand::Play);
+        harness.next_block();
+
+        harness.send(EngineCommand::Pause);
+        assert!(harness.next_block().iter().all(|s| *s == 0f32));
+        assert!(harness.next_block().iter().all(|s| *s == 0f32));
+        assert_eq!(harness.state(), EngineState::Paused);
+
+        harness.send(EngineCommand::Play);
+        assert_eq!(harness.next_block(), source[100..200]);
+    }
+
+    #[test]
+    fn stop_rewinds() {
+        let source = source();
+        let mut harness = Harness::new();
+
+        harness.send(EngineCommand::Play);
+        harness.next_block();
+        harness.next_block();
+
+        harness.send(EngineCommand::Stop);
+        assert!(harness.next_block().iter().all(|s| *s == 0f32));
+        assert_eq!(harness.state(), EngineState::Idle);
+
+        harness.send(EngineCommand::Play);
+        assert_eq!(harness.next_block(), source[..100]);
+    }
+
+    #[test]
+    fn seek_keeps_playing() {
+        let source = source();
+        let mut harness = Harness::new();
+
+        harness.send(EngineCommand::Play);
+        harness.next_block();
+
+        harness.send(EngineCommand::Seek(22050));
+        assert_eq!(harness.next_block(), source[22050..22150]);
+        assert_eq!(harness.state(), EngineState::Playing);
+
+        // Seeking while paused stays paused
+        harness.send(EngineCommand::Pause);
+        harness.send(EngineCommand::Seek(1000));
+        harness.next_block();
+        harness.send(EngineCommand::Play);
+        assert_eq!(harness.next_block(), source[1000..1100]);
+    }
+
+    #[test]
+    fn moving_the_playhead_sends_markers() {
+        let mut harness = Harness::new();
+        let marker = |output_frame, timeline_frame, playing| PlayheadMarker {
+            output_frame,
+            timeline_frame,
+            playing,
+        };
+
+        // Loading the playback
+        harness.next_block();
+        assert_eq!(harness.markers.try_recv(), Ok(marker(0, 0, false)));
+
+        harness.send(EngineCommand::Play);
+        harness.next_block();
+        harness.next_block();
+        assert_eq!(harness.markers.try_recv(), Ok(marker(100, 0, true)));
+        assert!(harness.markers.try_recv().is_err());
+
+        harness.send(EngineCommand::Seek(5000));
+        harness.next_block();
+        assert_eq!(harness.markers.try_recv(), Ok(marker(300, 5000, true)));
+
+        harness.send(EngineCommand::Pause);
+        harness.next_block();
+        assert_eq!(harness.markers.try_recv(), Ok(marker(400, 5100, false)));
+    }
+
+    #[test]
+    fn markers_wait_while_the_channel_is_full() {
+        let mut harness = Harness::new();
+        let (marker_tx, marker_rx) = bounded(1);
+        harness.transport.markers = marker_tx;
+        harness.markers = marker_rx;
+
+        // Loading the playback fills the channel
+        harness.next_block();
+        harness.send(EngineCommand::Play);
+        harness.next_block();
+        harness.send(EngineCommand::Seek(5000));
+        harness.next_block();
+
+        // The first marker went through, and the seek replaced the play
+        assert_eq!(harness.markers.try_recv().map(|m| m.output_frame), Ok(0));
+        harness.next_block();
+        assert_eq!(
+            harness.markers.try_recv(),
+            Ok(PlayheadMarker {
+                output_frame: 200,
+                timeline_frame: 5000,
+                playing: true,
+            })
+        );
+        harness.next_block();
+        assert!(harness.markers.try_recv().is_err());
+    }
+
+    #[test]
+    fn records_channel_decode_times() {
+        let mut harness = Harness::new();
+
+        assert!(harness.stats.snapshot().channels.is_empty());
+
+        // Loading the playback sets up its channels
+        harness.next_block();
+        let channels = harness.stats.snapshot().channels;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].id, "chan-1");
+        assert_eq!(channels[0].max_decode, Duration::ZERO);
+
+        harness.send(EngineCommand::Play);
+        harness.next_block();
+        assert!(harness.stats.snapshot().channels[0].max_decode > Duration::ZERO);
+    }
+}